@@ -0,0 +1,69 @@
+// Per-request cost of picking upstream headers for a segment URL.
+//
+// `per_request_compile` reproduces the old lookup, which compiled every
+// pattern of every group on each call; `regex_set` is the current lookup
+// against the precompiled `Templates`.
+//
+// Run with: cargo bench --bench templates
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use regex::Regex;
+use url::Url;
+
+#[allow(dead_code)]
+#[path = "../src/templates.rs"]
+mod templates;
+
+use templates::{builtin_groups, generate_headers_for_url, Templates};
+
+const SEGMENT_URLS: [&str; 3] = [
+    // Matches one of the last built-in groups
+    "https://cdn1.usbigcdn.cc/hls/seg-00042.ts",
+    // Matches an early group
+    "https://a1.padorupado.ru/stream/uwu/seg-7.ts",
+    // No template, falls back to the URL's own origin
+    "https://media.example.org/live/seg-1234.ts",
+];
+
+fn per_request_compile(patterns: &[Vec<String>], hostname: &str) -> Option<usize> {
+    patterns.iter().position(|group| {
+        group
+            .iter()
+            .any(|p| Regex::new(p).map(|re| re.is_match(hostname)).unwrap_or(false))
+    })
+}
+
+fn bench_lookup(c: &mut Criterion) {
+    let groups = builtin_groups();
+    let patterns: Vec<Vec<String>> = groups.iter().map(|g| g.patterns.clone()).collect();
+    let templates = Templates::compile(&groups).unwrap();
+    let urls: Vec<Url> = SEGMENT_URLS.iter().map(|u| Url::parse(u).unwrap()).collect();
+
+    let mut group = c.benchmark_group("domain_group_lookup");
+    group.bench_function("per_request_compile", |b| {
+        b.iter(|| {
+            for url in &urls {
+                black_box(per_request_compile(&patterns, url.host_str().unwrap()));
+            }
+        })
+    });
+    group.bench_function("regex_set", |b| {
+        b.iter(|| {
+            for url in &urls {
+                black_box(templates.find_group(url.host_str().unwrap()));
+            }
+        })
+    });
+    group.finish();
+
+    c.bench_function("generate_headers_for_url", |b| {
+        b.iter(|| {
+            for url in &urls {
+                black_box(generate_headers_for_url(&templates, url, None));
+            }
+        })
+    });
+}
+
+criterion_group!(benches, bench_lookup);
+criterion_main!(benches);
@@ -0,0 +1,537 @@
+// On-disk tier below the memory LRU, for responses fresh long enough to be
+// worth keeping across restarts (VOD segments). Bodies live under objects/,
+// named by the sha256 of their content, so identical bodies are kept once.
+// entries/ maps each cache key to its object, status and headers. Files are
+// written under tmp/ and renamed into place, so a crash never leaves a
+// partial file where a reader could find it.
+
+use bytes::Bytes;
+use futures_util::{stream, Stream};
+use once_cell::sync::OnceCell;
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use super::CachedResponse;
+use crate::config;
+
+// What entries/<key> holds
+#[derive(Clone, Serialize, Deserialize)]
+struct Meta {
+    status: u16,
+    headers: Vec<(String, String)>,
+    // sha256 of the body, naming its file under objects/
+    object: String,
+    size: u64,
+    // Unix seconds
+    stored: u64,
+    expires: u64,
+}
+
+struct Entry {
+    meta: Meta,
+    // Position in Index::order
+    used: u64,
+}
+
+struct Object {
+    size: u64,
+    // Entries pointing at this object; it is deleted when the last one goes
+    refs: usize,
+}
+
+struct Index {
+    root: PathBuf,
+    entries: HashMap<String, Entry>,
+    objects: HashMap<String, Object>,
+    // Last use tick -> key, least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    // Bytes of all objects
+    bytes: u64,
+}
+
+// Set once at startup when [cache] disk_dir is configured
+static INDEX: OnceCell<Mutex<Index>> = OnceCell::new();
+
+// Most read from an object file at a time when streaming a body
+const CHUNK: u64 = 64 * 1024;
+
+// A fresh entry found on disk. The body stays in its object file until sent.
+pub struct Hit {
+    pub key: String,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub stored: SystemTime,
+    pub ttl: Duration,
+    pub size: u64,
+    file: tokio::fs::File,
+}
+
+impl Hit {
+    // Body bytes in `range`, read from the object file as the stream is polled
+    pub fn body(self, range: Range<u64>) -> impl Stream<Item = io::Result<Bytes>> + Send {
+        let remaining = range.end.saturating_sub(range.start);
+        stream::try_unfold(
+            (self.file, Some(range.start), remaining),
+            |(mut file, seek, remaining)| async move {
+                if remaining == 0 {
+                    return Ok(None);
+                }
+                if let Some(offset) = seek {
+                    file.seek(SeekFrom::Start(offset)).await?;
+                }
+                let mut chunk = vec![0; remaining.min(CHUNK) as usize];
+                let read = file.read(&mut chunk).await?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                chunk.truncate(read);
+                Ok(Some((Bytes::from(chunk), (file, None, remaining - read as u64))))
+            },
+        )
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl Index {
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.root.join("entries").join(key)
+    }
+
+    fn object_path(&self, object: &str) -> PathBuf {
+        self.root.join("objects").join(&object[..2]).join(object)
+    }
+
+    fn touch(&mut self, key: &str) {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return;
+        };
+        self.order.remove(&entry.used);
+        self.tick += 1;
+        entry.used = self.tick;
+        self.order.insert(self.tick, key.to_string());
+    }
+
+    // Add an entry. Its object is held from here on, even before its files are in place.
+    fn add(&mut self, key: String, meta: Meta) {
+        let object = self.objects.entry(meta.object.clone()).or_insert(Object {
+            size: meta.size,
+            refs: 0,
+        });
+        if object.refs == 0 {
+            self.bytes += object.size;
+        }
+        object.refs += 1;
+        self.entries.insert(key.clone(), Entry { meta, used: 0 });
+        self.touch(&key);
+    }
+
+    // Forget an entry and delete its files
+    fn remove(&mut self, key: &str) {
+        let Some(entry) = self.entries.remove(key) else {
+            return;
+        };
+        self.order.remove(&entry.used);
+        let _ = fs::remove_file(self.entry_path(key));
+        let object = &entry.meta.object;
+        if let Some(o) = self.objects.get_mut(object) {
+            o.refs -= 1;
+            if o.refs == 0 {
+                self.bytes -= o.size;
+                self.objects.remove(object);
+                let _ = fs::remove_file(self.object_path(object));
+            }
+        }
+    }
+
+    // Drop least recently used entries until `incoming` more bytes fit in `budget`
+    fn make_room(&mut self, incoming: u64, budget: u64) {
+        while self.bytes + incoming > budget {
+            let Some((_, key)) = self.order.first_key_value().map(|(t, k)| (*t, k.clone())) else {
+                break;
+            };
+            self.remove(&key);
+        }
+    }
+
+    // Load the index from what is on disk, dropping expired or broken entries,
+    // unreferenced objects and leftover temp files, then trim it to `budget`
+    fn load(root: &Path, budget: u64) -> io::Result<Index> {
+        for dir in ["entries", "objects", "tmp"] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+        for file in fs::read_dir(root.join("tmp"))? {
+            let _ = fs::remove_file(file?.path());
+        }
+
+        let mut index = Index {
+            root: root.to_path_buf(),
+            entries: HashMap::new(),
+            objects: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            bytes: 0,
+        };
+        let now = unix_secs(SystemTime::now());
+        let mut found = Vec::new();
+        for file in fs::read_dir(root.join("entries"))? {
+            let path = file?.path();
+            let key = path.file_name().and_then(|n| n.to_str()).unwrap_or("").to_string();
+            let meta = fs::read(&path)
+                .ok()
+                .and_then(|json| serde_json::from_slice::<Meta>(&json).ok())
+                .filter(|m| m.expires > now && m.object.len() > 2);
+            let intact = meta.as_ref().is_some_and(|m| {
+                fs::metadata(index.object_path(&m.object)).is_ok_and(|f| f.len() == m.size)
+            });
+            match meta {
+                Some(meta) if intact => found.push((key, meta)),
+                _ => {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+        // Oldest first, so they are the first to go
+        found.sort_by_key(|(_, meta)| meta.stored);
+        for (key, meta) in found {
+            index.add(key, meta);
+        }
+
+        for shard in fs::read_dir(root.join("objects"))? {
+            let shard = shard?.path();
+            if !shard.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(&shard)? {
+                let path = file?.path();
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                if !index.objects.contains_key(name) {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+
+        index.make_room(0, budget);
+        Ok(index)
+    }
+}
+
+// Write `contents` under tmp/ and rename it to `path`
+fn write_atomic(root: &Path, path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = root.join("tmp").join(format!("{:016x}", rand::random::<u64>()));
+    fs::write(&tmp, contents)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+// Open the disk tier at `root`. Returns entries and bytes kept from an earlier run.
+pub fn open(root: &Path) -> io::Result<(usize, u64)> {
+    let index = Index::load(root, config::current().cache.disk_max_bytes)?;
+    let kept = (index.entries.len(), index.bytes);
+    let _ = INDEX.set(Mutex::new(index));
+    Ok(kept)
+}
+
+// Fresh response for a key, with its object file opened for reading
+pub async fn get(key: &str) -> Option<Hit> {
+    read(INDEX.get()?, key).await
+}
+
+async fn read(index: &Mutex<Index>, key: &str) -> Option<Hit> {
+    let (meta, path) = {
+        let mut index = index.lock().unwrap();
+        let meta = index.entries.get(key)?.meta.clone();
+        if meta.expires <= unix_secs(SystemTime::now()) {
+            return None;
+        }
+        index.touch(key);
+        let path = index.object_path(&meta.object);
+        (meta, path)
+    };
+    // Evicted in the meantime, not in place yet, or damaged behind our back.
+    // Once open, the file stays readable even if it is evicted while being sent.
+    let file = tokio::fs::File::open(&path).await.ok()?;
+    if file.metadata().await.ok()?.len() != meta.size {
+        return None;
+    }
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in &meta.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+            headers.append(name, value);
+        }
+    }
+    let ttl = (UNIX_EPOCH + Duration::from_secs(meta.expires))
+        .duration_since(SystemTime::now())
+        .unwrap_or(Duration::ZERO);
+    Some(Hit {
+        key: key.to_string(),
+        status: StatusCode::from_u16(meta.status).ok()?,
+        headers,
+        stored: UNIX_EPOCH + Duration::from_secs(meta.stored),
+        ttl,
+        size: meta.size,
+        file,
+    })
+}
+
+// Whether a fresh response is held, without counting as a use
+pub fn contains(key: &str) -> bool {
+    let Some(index) = INDEX.get() else {
+        return false;
+    };
+    let now = unix_secs(SystemTime::now());
+    index.lock().unwrap().entries.get(key).is_some_and(|e| e.meta.expires > now)
+}
+
+// Keep a response on disk. Runs off the request path, as a blocking task.
+pub fn insert(key: String, response: CachedResponse, ttl: Duration) {
+    let Some(index) = INDEX.get() else {
+        return;
+    };
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = write(index, &key, &response, ttl, config::current().cache.disk_max_bytes) {
+            eprintln!("Disk cache write for {} failed: {}", key, e);
+        }
+    });
+}
+
+// Only the index updates happen under the lock; the files are written around them
+fn write(index: &Mutex<Index>, key: &str, response: &CachedResponse, ttl: Duration, budget: u64) -> io::Result<()> {
+    let size = response.body.len() as u64;
+    if size > budget {
+        return Ok(());
+    }
+
+    let object: String = Sha256::digest(&response.body).iter().map(|b| format!("{:02x}", b)).collect();
+    let stored = unix_secs(response.stored);
+    let meta = Meta {
+        status: response.status.as_u16(),
+        headers: response
+            .headers
+            .iter()
+            .filter_map(|(k, v)| Some((k.as_str().to_string(), v.to_str().ok()?.to_string())))
+            .collect(),
+        object: object.clone(),
+        size,
+        stored,
+        expires: stored + ttl.as_secs(),
+    };
+    let json = serde_json::to_vec(&meta).map_err(io::Error::other)?;
+
+    let root = index.lock().unwrap().root.clone();
+    let tmp = root.join("tmp").join(format!("{:016x}", rand::random::<u64>()));
+    fs::write(&tmp, &response.body)?;
+
+    // Taking the entry into the index first holds its object, so an eviction
+    // running meanwhile can't delete the file once it is in place
+    let (new_object, object_path, entry_path) = {
+        let mut index = index.lock().unwrap();
+        index.remove(key);
+        index.make_room(size, budget);
+        let new_object = !index.objects.contains_key(&object);
+        index.add(key.to_string(), meta);
+        (new_object, index.object_path(&object), index.entry_path(key))
+    };
+
+    // Only needed if nobody holds the same body yet
+    let placed = if new_object {
+        fs::create_dir_all(object_path.parent().expect("objects are sharded"))
+            .and_then(|_| fs::rename(&tmp, &object_path))
+    } else {
+        fs::remove_file(&tmp)
+    };
+    let placed = placed.and_then(|_| write_atomic(&root, &entry_path, &json));
+
+    let mut index = index.lock().unwrap();
+    if let Err(e) = placed {
+        let _ = fs::remove_file(&tmp);
+        index.remove(key);
+        return Err(e);
+    }
+    // Evicted before its files were in place; nothing else refers to them
+    if !index.entries.contains_key(key) {
+        let _ = fs::remove_file(&entry_path);
+        if new_object && !index.objects.contains_key(&object) {
+            let _ = fs::remove_file(&object_path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::TryStreamExt;
+    use once_cell::sync::Lazy;
+
+    const TTL: Duration = Duration::from_secs(3600);
+
+    // Empty cache directory of its own for each test
+    fn root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("rustproxy-disk-{}-{:016x}", name, rand::random::<u64>()));
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn response(body: &'static [u8]) -> CachedResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("video/mp2t"));
+        CachedResponse {
+            status: StatusCode::OK,
+            headers,
+            body: Bytes::from_static(body),
+            stored: SystemTime::now(),
+        }
+    }
+
+    async fn body(index: &Mutex<Index>, key: &str, range: Option<Range<u64>>) -> Option<Vec<u8>> {
+        let hit = read(index, key).await?;
+        let range = range.unwrap_or(0..hit.size);
+        let chunks = hit.body(range).try_collect::<Vec<_>>().await.unwrap();
+        Some(chunks.concat())
+    }
+
+    fn files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|f| f.unwrap().path())
+            .map(|p| if p.is_dir() { files(&p) } else { 1 })
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn rebuilds_the_index_from_disk() {
+        let root = root("rebuild");
+        {
+            let index = Mutex::new(Index::load(&root, 1000).unwrap());
+            write(&index, "a", &response(b"first body"), TTL, 1000).unwrap();
+            write(&index, "b", &response(b"second body"), TTL, 1000).unwrap();
+            write(&index, "expired", &response(b"stale body"), Duration::ZERO, 1000).unwrap();
+            write(&index, "broken", &response(b"broken body"), TTL, 1000).unwrap();
+        }
+        // Left behind by a crash, or damaged since
+        fs::write(root.join("tmp").join("leftover"), b"partial").unwrap();
+        fs::write(root.join("entries").join("broken"), b"{not json").unwrap();
+        fs::create_dir_all(root.join("objects").join("00")).unwrap();
+        fs::write(root.join("objects").join("00").join("00orphan"), b"orphan").unwrap();
+
+        let index = Mutex::new(Index::load(&root, 1000).unwrap());
+        {
+            let index = index.lock().unwrap();
+            let mut keys: Vec<_> = index.entries.keys().cloned().collect();
+            keys.sort();
+            assert_eq!(keys, ["a", "b"]);
+            assert_eq!(index.bytes, 21);
+        }
+        assert_eq!(body(&index, "a", None).await.as_deref(), Some(&b"first body"[..]));
+        let hit = read(&index, "b").await.unwrap();
+        assert_eq!(hit.status, StatusCode::OK);
+        assert_eq!(hit.headers.get("content-type").unwrap(), "video/mp2t");
+        assert!(hit.ttl > TTL - Duration::from_secs(5));
+        assert!(read(&index, "expired").await.is_none());
+        assert_eq!(files(&root.join("tmp")), 0);
+        assert_eq!(files(&root.join("entries")), 2);
+        assert_eq!(files(&root.join("objects")), 2);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn identical_bodies_are_stored_once() {
+        let root = root("dedup");
+        let index = Mutex::new(Index::load(&root, 1000).unwrap());
+        write(&index, "a", &response(b"same body"), TTL, 1000).unwrap();
+        write(&index, "b", &response(b"same body"), TTL, 1000).unwrap();
+        assert_eq!(index.lock().unwrap().bytes, 9);
+        assert_eq!(files(&root.join("objects")), 1);
+
+        index.lock().unwrap().remove("a");
+        assert_eq!(body(&index, "b", None).await.as_deref(), Some(&b"same body"[..]));
+        assert!(read(&index, "a").await.is_none());
+
+        index.lock().unwrap().remove("b");
+        assert_eq!(index.lock().unwrap().bytes, 0);
+        assert_eq!(files(&root.join("objects")), 0);
+        assert_eq!(files(&root.join("entries")), 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_entries() {
+        let root = root("evict");
+        let index = Mutex::new(Index::load(&root, 30).unwrap());
+        write(&index, "a", &response(b"aaaaaaaaaa"), TTL, 30).unwrap();
+        write(&index, "b", &response(b"bbbbbbbbbb"), TTL, 30).unwrap();
+        write(&index, "c", &response(b"cccccccccc"), TTL, 30).unwrap();
+        assert!(read(&index, "a").await.is_some());
+
+        write(&index, "d", &response(b"dddddddddd"), TTL, 30).unwrap();
+        assert!(read(&index, "b").await.is_none());
+        for key in ["a", "c", "d"] {
+            assert!(read(&index, key).await.is_some(), "{}", key);
+        }
+        assert_eq!(index.lock().unwrap().bytes, 30);
+        assert_eq!(files(&root.join("objects")), 3);
+
+        // Too big for the budget at all: kept out rather than emptying the cache
+        write(&index, "e", &response(&[b'e'; 31]), TTL, 30).unwrap();
+        assert!(read(&index, "e").await.is_none());
+        assert_eq!(index.lock().unwrap().entries.len(), 3);
+
+        // A smaller budget at startup trims the oldest entries
+        drop(index);
+        let index = Index::load(&root, 20).unwrap();
+        assert_eq!((index.entries.len(), index.bytes), (2, 20));
+        assert_eq!(files(&root.join("objects")), 2);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_ranges_from_the_object_file() {
+        static BODY: Lazy<Vec<u8>> = Lazy::new(|| (0..200_000u32).map(|i| (i % 251) as u8).collect());
+        let root = root("range");
+        let index = Mutex::new(Index::load(&root, 1_000_000).unwrap());
+        let mut big = response(b"");
+        big.body = Bytes::from_static(&BODY);
+        write(&index, "big", &big, TTL, 1_000_000).unwrap();
+
+        assert_eq!(body(&index, "big", None).await.unwrap(), *BODY);
+        assert_eq!(body(&index, "big", Some(5..10)).await.unwrap(), BODY[5..10]);
+        let across = 60_000..140_000;
+        assert_eq!(
+            body(&index, "big", Some(across.clone())).await.unwrap(),
+            BODY[across.start as usize..across.end as usize]
+        );
+        assert_eq!(body(&index, "big", Some(199_999..200_000)).await.unwrap(), [BODY[199_999]]);
+
+        // Cut short behind our back
+        let hit = read(&index, "big").await.unwrap();
+        let object = index.lock().unwrap().entries["big"].meta.object.clone();
+        let path = index.lock().unwrap().object_path(&object);
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(100).unwrap();
+        assert!(hit.body(0..200_000).try_collect::<Vec<_>>().await.is_err());
+        assert!(read(&index, "big").await.is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+// In-memory tier: byte-budgeted LRU over fresh responses
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::CachedResponse;
+use crate::config;
+
+struct Entry {
+    response: CachedResponse,
+    size: u64,
+    expires: Instant,
+    // Position in Lru::order
+    used: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    // Last use tick -> key, least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return;
+        };
+        self.order.remove(&entry.used);
+        self.tick += 1;
+        entry.used = self.tick;
+        self.order.insert(self.tick, key.to_string());
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.bytes -= entry.size;
+        }
+    }
+
+    // Drop least recently used entries until `incoming` more bytes fit in `budget`
+    fn make_room(&mut self, incoming: u64, budget: u64) {
+        while self.bytes + incoming > budget {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.bytes -= entry.size;
+            }
+        }
+    }
+
+    fn insert(&mut self, key: String, response: CachedResponse, size: u64, ttl: Duration, budget: u64) {
+        self.remove(&key);
+        self.make_room(size, budget);
+        self.bytes += size;
+        self.entries.insert(
+            key.clone(),
+            Entry {
+                response,
+                size,
+                expires: Instant::now() + ttl,
+                used: 0,
+            },
+        );
+        self.touch(&key);
+    }
+}
+
+static LRU: Lazy<Mutex<Lru>> = Lazy::new(|| Mutex::new(Lru::default()));
+
+pub fn get(key: &str) -> Option<CachedResponse> {
+    let mut lru = LRU.lock().unwrap();
+    let expired = lru.entries.get(key)?.expires <= Instant::now();
+    if expired {
+        lru.remove(key);
+        return None;
+    }
+    lru.touch(key);
+    lru.entries.get(key).map(|e| e.response.clone())
+}
+
+// Whether a fresh response is held, without counting as a use
+pub fn contains(key: &str) -> bool {
+    let lru = LRU.lock().unwrap();
+    lru.entries.get(key).is_some_and(|e| e.expires > Instant::now())
+}
+
+pub fn insert(key: String, response: CachedResponse, ttl: Duration) {
+    let config = config::current();
+    let size = response.size() + key.len() as u64;
+    if size > config.cache.max_entry_bytes {
+        return;
+    }
+    LRU.lock().unwrap().insert(key, response, size, ttl, config.cache.max_bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::{header::HeaderMap, StatusCode};
+    use std::time::SystemTime;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn response(body: &'static str) -> CachedResponse {
+        CachedResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: bytes::Bytes::from_static(body.as_bytes()),
+            stored: SystemTime::now(),
+        }
+    }
+
+    fn keys(lru: &Lru) -> Vec<&str> {
+        lru.order.values().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_used_beyond_the_budget() {
+        let mut lru = Lru::default();
+        for key in ["a", "b", "c"] {
+            lru.insert(key.to_string(), response("x"), 10, TTL, 30);
+        }
+        assert_eq!(lru.bytes, 30);
+        assert_eq!(keys(&lru), vec!["a", "b", "c"]);
+
+        // Using "a" makes "b" the oldest
+        lru.touch("a");
+        lru.insert("d".to_string(), response("x"), 10, TTL, 30);
+        assert_eq!(keys(&lru), vec!["c", "a", "d"]);
+        assert!(!lru.entries.contains_key("b"));
+        assert_eq!(lru.bytes, 30);
+
+        // A big entry pushes out as many as it needs
+        lru.insert("e".to_string(), response("x"), 25, TTL, 30);
+        assert_eq!(keys(&lru), vec!["e"]);
+        assert_eq!(lru.bytes, 25);
+    }
+
+    #[test]
+    fn replacing_an_entry_frees_its_old_size() {
+        let mut lru = Lru::default();
+        lru.insert("a".to_string(), response("old"), 20, TTL, 30);
+        lru.insert("b".to_string(), response("x"), 10, TTL, 30);
+        lru.insert("a".to_string(), response("new"), 15, TTL, 30);
+        assert_eq!(keys(&lru), vec!["b", "a"]);
+        assert_eq!(lru.bytes, 25);
+        assert_eq!(lru.entries["a"].response.body, "new");
+
+        lru.remove("a");
+        lru.remove("missing");
+        assert_eq!(keys(&lru), vec!["b"]);
+        assert_eq!(lru.bytes, 10);
+    }
+
+    #[test]
+    fn expired_entries_are_not_served() {
+        let key = "test://memory/expired".to_string();
+        LRU.lock().unwrap().insert(key.clone(), response("x"), 10, Duration::ZERO, u64::MAX);
+        assert!(!contains(&key));
+        assert!(get(&key).is_none());
+        assert!(!LRU.lock().unwrap().entries.contains_key(&key));
+    }
+
+    #[test]
+    fn inserts_within_the_configured_limits() {
+        let key = "test://memory/configured".to_string();
+        insert(key.clone(), response("body"), TTL);
+        assert!(contains(&key));
+        assert_eq!(get(&key).unwrap().body, "body");
+    }
+}
@@ -0,0 +1,547 @@
+// Cache for upstream responses that are passed through unchanged (segments,
+// keys, init sections), and for rewritten media playlists (see playlist.rs).
+
+pub mod disk;
+pub mod memory;
+pub mod playlist;
+
+use actix_web::http::header::HttpDate;
+use bytes::Bytes;
+use futures_util::{future, ready, stream::{self, BoxStream}, Stream, StreamExt};
+use reqwest::{header::HeaderMap, StatusCode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    ops::Range,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use url::Url;
+
+use crate::config;
+
+// Response header saying whether the body came from the cache
+pub const STATUS_HEADER: &str = "X-Cache";
+
+// [cache] section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    // Memory budget for cached bodies and their headers
+    pub max_bytes: u64,
+    // Larger responses are streamed through without being cached
+    pub max_entry_bytes: u64,
+    // Lifetime of responses that come without Cache-Control max-age or Expires
+    pub default_ttl_secs: u64,
+    // Share one upstream fetch between concurrent identical requests; works
+    // with `enabled = false` too
+    pub coalesce: bool,
+    // Keep rewritten media playlists for a while; also independent of `enabled`
+    pub playlists: bool,
+    // Part of the target duration (or part target) a live playlist is kept for
+    pub live_playlist_fraction: f64,
+    // Lifetime of playlists that have ended (VOD, or EVENT with ENDLIST)
+    pub vod_playlist_ttl_secs: u64,
+    // Directory for the on-disk tier; none keeps everything in memory. Read at startup only.
+    pub disk_dir: Option<PathBuf>,
+    pub disk_max_bytes: u64,
+    // Responses fresh for less than this are not written to disk
+    pub disk_min_ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: false,
+            max_bytes: 256 * 1024 * 1024,
+            max_entry_bytes: 16 * 1024 * 1024,
+            default_ttl_secs: 60,
+            coalesce: false,
+            playlists: false,
+            live_playlist_fraction: 0.5,
+            vod_playlist_ttl_secs: 300,
+            disk_dir: None,
+            disk_max_bytes: 10 * 1024 * 1024 * 1024,
+            disk_min_ttl_secs: 600,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_entry_bytes > self.max_bytes {
+            return Err("max_entry_bytes is larger than max_bytes".to_string());
+        }
+        if !(self.live_playlist_fraction > 0.0 && self.live_playlist_fraction <= 1.0) {
+            return Err("live_playlist_fraction must be above 0 and at most 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Upstream request headers that make a response unsuitable for sharing
+const UNCACHEABLE_REQUEST_HEADERS: [&str; 4] = ["range", "if-range", "if-none-match", "if-modified-since"];
+
+// A cached upstream response
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub stored: SystemTime,
+}
+
+impl CachedResponse {
+    // Bytes counted against the cache budget
+    fn size(&self) -> u64 {
+        let headers: usize = self.headers.iter().map(|(k, v)| k.as_str().len() + v.len()).sum();
+        (self.body.len() + headers) as u64
+    }
+
+    // Seconds since the response was stored, for the Age header
+    pub fn age(&self) -> u64 {
+        self.stored.elapsed().map_or(0, |d| d.as_secs())
+    }
+}
+
+// A fresh cached response: held in memory, or found on disk and read from
+// its file as it is sent
+pub enum Hit {
+    Memory(CachedResponse),
+    Disk(disk::Hit),
+}
+
+impl Hit {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Hit::Memory(response) => response.status,
+            Hit::Disk(hit) => hit.status,
+        }
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        match self {
+            Hit::Memory(response) => &response.headers,
+            Hit::Disk(hit) => &hit.headers,
+        }
+    }
+
+    // Seconds since the response was stored, for the Age header
+    pub fn age(&self) -> u64 {
+        match self {
+            Hit::Memory(response) => response.age(),
+            Hit::Disk(hit) => hit.stored.elapsed().map_or(0, |d| d.as_secs()),
+        }
+    }
+
+    // Length of the whole body
+    pub fn size(&self) -> u64 {
+        match self {
+            Hit::Memory(response) => response.body.len() as u64,
+            Hit::Disk(hit) => hit.size,
+        }
+    }
+
+    // Body bytes in `range`. A whole body read from disk is brought back into memory on the way out.
+    pub fn body(self, range: Range<u64>) -> BoxStream<'static, io::Result<Bytes>> {
+        match self {
+            Hit::Memory(response) => {
+                let part = response.body.slice(range.start as usize..range.end as usize);
+                stream::once(future::ready(Ok(part))).boxed()
+            }
+            Hit::Disk(hit) if range == (0..hit.size) => {
+                let (key, ttl) = (hit.key.clone(), hit.ttl);
+                let response = CachedResponse {
+                    status: hit.status,
+                    headers: hit.headers.clone(),
+                    body: Bytes::new(),
+                    stored: hit.stored,
+                };
+                let limit = config::current().cache.max_entry_bytes;
+                Recorder::new(hit.body(range).boxed(), limit, move |body| {
+                    memory::insert(key, CachedResponse { body, ..response }, ttl)
+                })
+                .boxed()
+            }
+            Hit::Disk(hit) => hit.body(range).boxed(),
+        }
+    }
+}
+
+// Cache key: the upstream URL plus every header the proxy sends upstream
+// (origin/referer templates and custom headers can change the response)
+pub fn key(url: &Url, headers: &HeaderMap) -> String {
+    let mut sent: Vec<(&str, &[u8])> = headers.iter().map(|(k, v)| (k.as_str(), v.as_bytes())).collect();
+    sent.sort();
+
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_str());
+    for (name, value) in sent {
+        hasher.update(b"\n");
+        hasher.update(name);
+        hasher.update(b": ");
+        hasher.update(value);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Whether a fetch with these upstream headers may be served from or stored in the cache
+pub fn cacheable_request(headers: &HeaderMap) -> bool {
+    UNCACHEABLE_REQUEST_HEADERS.iter().all(|h| !headers.contains_key(*h))
+}
+
+// Key of the whole response for a plain Range request, which a complete
+// cached copy can answer, along with the requested range
+pub fn range_key(url: &Url, headers: &HeaderMap) -> Option<(String, String)> {
+    let range = headers.get("range")?.to_str().ok()?.to_string();
+    let mut whole = headers.clone();
+    whole.remove("range");
+    cacheable_request(&whole).then(|| (key(url, &whole), range))
+}
+
+// Part of a cached body a Range header selects
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    // No usable single range; the whole body goes out with a 200
+    Whole,
+    // First and last byte, inclusive
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    pub fn parse(value: &str, len: u64) -> ByteRange {
+        let Some(spec) = value.trim().strip_prefix("bytes=") else {
+            return ByteRange::Whole;
+        };
+        // Multiple ranges would need multipart/byteranges; the whole body is also a valid answer
+        if spec.contains(',') {
+            return ByteRange::Whole;
+        }
+        let Some((first, last)) = spec.trim().split_once('-') else {
+            return ByteRange::Whole;
+        };
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty() {
+            // Suffix range: the last n bytes
+            match last.parse::<u64>() {
+                Ok(0) => return ByteRange::Unsatisfiable,
+                Ok(n) => (len.saturating_sub(n), len.saturating_sub(1)),
+                Err(_) => return ByteRange::Whole,
+            }
+        } else {
+            let Ok(first) = first.parse::<u64>() else {
+                return ByteRange::Whole;
+            };
+            let last = match last {
+                "" => len.saturating_sub(1),
+                l => match l.parse::<u64>() {
+                    Ok(l) if l >= first => l.min(len.saturating_sub(1)),
+                    _ => return ByteRange::Whole,
+                },
+            };
+            (first, last)
+        };
+        if len == 0 || range.0 >= len {
+            return ByteRange::Unsatisfiable;
+        }
+        ByteRange::Part(range.0, range.1)
+    }
+}
+
+// How long an upstream response stays fresh; None when it must not be cached.
+// s-maxage and max-age win over Expires, and the default covers neither.
+pub fn freshness(status: StatusCode, headers: &HeaderMap, default_ttl: Duration) -> Option<Duration> {
+    if status != StatusCode::OK || headers.contains_key("set-cookie") {
+        return None;
+    }
+
+    let cache_control = headers
+        .get_all("cache-control")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    if cache_control
+        .iter()
+        .any(|d| matches!(d.as_str(), "no-store" | "no-cache" | "private"))
+    {
+        return None;
+    }
+
+    let directive = |name: &str| {
+        cache_control
+            .iter()
+            .find_map(|d| d.strip_prefix(name)?.strip_prefix('=')?.trim_matches('"').parse::<u64>().ok())
+    };
+    let header_date = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<HttpDate>().ok())
+            .map(SystemTime::from)
+    };
+
+    let ttl = if let Some(secs) = directive("s-maxage").or_else(|| directive("max-age")) {
+        Duration::from_secs(secs)
+    } else if let Some(expires) = header_date("expires") {
+        // Relative to the upstream's own clock when it sent a Date
+        let now = header_date("date").unwrap_or_else(SystemTime::now);
+        expires.duration_since(now).unwrap_or(Duration::ZERO)
+    } else {
+        default_ttl
+    };
+
+    // Time the response already spent in caches upstream
+    let age = headers
+        .get("age")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .map_or(Duration::ZERO, Duration::from_secs);
+    Some(ttl.saturating_sub(age)).filter(|ttl| !ttl.is_zero())
+}
+
+// Passes a body through while keeping a copy, handed to `on_end` once the
+// body has ended. Bodies that fail, are cut short by the client or outgrow
+// `limit` are dropped.
+pub struct Recorder<S, F> {
+    inner: S,
+    pending: Option<Pending<F>>,
+}
+
+struct Pending<F> {
+    body: Vec<u8>,
+    limit: u64,
+    on_end: F,
+}
+
+impl<S, F: FnOnce(Bytes)> Recorder<S, F> {
+    pub fn new(inner: S, limit: u64, on_end: F) -> Self {
+        Recorder {
+            inner,
+            pending: Some(Pending {
+                body: Vec::new(),
+                limit,
+                on_end,
+            }),
+        }
+    }
+}
+
+impl<S, E, F> Stream for Recorder<S, F>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    F: FnOnce(Bytes) + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = ready!(self.inner.poll_next_unpin(cx));
+        match &item {
+            Some(Ok(chunk)) => {
+                if let Some(pending) = &mut self.pending {
+                    if (pending.body.len() + chunk.len()) as u64 > pending.limit {
+                        self.pending = None;
+                    } else {
+                        pending.body.extend_from_slice(chunk);
+                    }
+                }
+            }
+            Some(Err(_)) => self.pending = None,
+            None => {
+                if let Some(pending) = self.pending.take() {
+                    (pending.on_end)(Bytes::from(pending.body));
+                }
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+// Keep a complete upstream response for `ttl`, on disk too if it stays fresh long enough
+pub fn store(key: String, ttl: Duration, status: StatusCode, headers: HeaderMap, body: Bytes) {
+    // A body shorter than announced was cut off upstream
+    let announced = headers
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if announced.is_some_and(|len| len != body.len()) {
+        return;
+    }
+
+    let response = CachedResponse {
+        status,
+        headers,
+        body,
+        stored: SystemTime::now(),
+    };
+    if ttl.as_secs() >= config::current().cache.disk_min_ttl_secs {
+        disk::insert(key.clone(), response.clone(), ttl);
+    }
+    memory::insert(key, response, ttl);
+}
+
+// Whether either tier holds a fresh response for a key
+pub fn contains(key: &str) -> bool {
+    memory::contains(key) || disk::contains(key)
+}
+
+// Fresh cached response for a key, if any
+pub async fn get(key: &str) -> Option<Hit> {
+    if let Some(response) = memory::get(key) {
+        return Some(Hit::Memory(response));
+    }
+    disk::get(key).await.map(Hit::Disk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderName, HeaderValue};
+
+    const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(HeaderName::from_static(name), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    fn fresh_for(pairs: &[(&'static str, &str)]) -> Option<u64> {
+        freshness(StatusCode::OK, &headers(pairs), DEFAULT_TTL).map(|d| d.as_secs())
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        use ByteRange::*;
+        for (value, expected) in [
+            ("bytes=0-99", Part(0, 99)),
+            ("bytes=100-", Part(100, 999)),
+            ("bytes=900-2000", Part(900, 999)),
+            (" bytes= 5 - 9 ", Part(5, 9)),
+            ("bytes=-100", Part(900, 999)),
+            ("bytes=-5000", Part(0, 999)),
+            ("bytes=-0", Unsatisfiable),
+            ("bytes=1000-", Unsatisfiable),
+            ("bytes=1000-1999", Unsatisfiable),
+            ("bytes=0-1,5-9", Whole),
+            ("bytes=9-5", Whole),
+            ("bytes=a-b", Whole),
+            ("bytes=5", Whole),
+            ("items=0-9", Whole),
+        ] {
+            assert_eq!(ByteRange::parse(value, 1000), expected, "{:?}", value);
+        }
+        assert_eq!(ByteRange::parse("bytes=0-", 0), Unsatisfiable);
+        assert_eq!(ByteRange::parse("bytes=-10", 0), Unsatisfiable);
+    }
+
+    #[test]
+    fn freshness_follows_cache_headers() {
+        assert_eq!(fresh_for(&[]), Some(60));
+        assert_eq!(fresh_for(&[("cache-control", "public, max-age=300")]), Some(300));
+        assert_eq!(fresh_for(&[("cache-control", "max-age=300, s-maxage=\"30\"")]), Some(30));
+        assert_eq!(fresh_for(&[("cache-control", "max-age=300"), ("age", "100")]), Some(200));
+        assert_eq!(fresh_for(&[("cache-control", "max-age=300"), ("age", "400")]), None);
+        assert_eq!(fresh_for(&[("cache-control", "max-age=0")]), None);
+        assert_eq!(
+            fresh_for(&[
+                ("date", "Sat, 01 Jun 2024 00:00:00 GMT"),
+                ("expires", "Sat, 01 Jun 2024 00:02:00 GMT"),
+            ]),
+            Some(120)
+        );
+        assert_eq!(
+            fresh_for(&[
+                ("cache-control", "max-age=10"),
+                ("date", "Sat, 01 Jun 2024 00:00:00 GMT"),
+                ("expires", "Sat, 01 Jun 2024 00:02:00 GMT"),
+            ]),
+            Some(10)
+        );
+        assert_eq!(fresh_for(&[("expires", "Sat, 01 Jun 2024 00:00:00 GMT")]), None);
+    }
+
+    #[test]
+    fn freshness_refuses_uncacheable_responses() {
+        for directive in ["no-store", "No-Cache", "private, max-age=300"] {
+            assert_eq!(fresh_for(&[("cache-control", directive)]), None, "{:?}", directive);
+        }
+        assert_eq!(fresh_for(&[("cache-control", "max-age=300"), ("cache-control", "no-store")]), None);
+        assert_eq!(fresh_for(&[("set-cookie", "id=1")]), None);
+        assert_eq!(freshness(StatusCode::PARTIAL_CONTENT, &HeaderMap::new(), DEFAULT_TTL), None);
+        assert_eq!(freshness(StatusCode::NOT_FOUND, &HeaderMap::new(), DEFAULT_TTL), None);
+    }
+
+    #[test]
+    fn conditional_and_range_requests_are_not_cacheable() {
+        assert!(cacheable_request(&headers(&[("origin", "https://a.example"), ("referer", "https://a.example/")])));
+        for name in ["range", "if-range", "if-none-match", "if-modified-since"] {
+            assert!(!cacheable_request(&headers(&[(name, "x")])), "{}", name);
+        }
+    }
+
+    #[test]
+    fn keys_cover_url_and_sent_headers() {
+        let url = Url::parse("https://cdn.example/seg0.ts").unwrap();
+        let base = key(&url, &headers(&[("origin", "https://a.example"), ("referer", "https://a.example/")]));
+        assert_eq!(base.len(), 64);
+        // Header order doesn't matter, values and the URL do
+        assert_eq!(base, key(&url, &headers(&[("referer", "https://a.example/"), ("origin", "https://a.example")])));
+        assert_ne!(base, key(&url, &headers(&[("origin", "https://b.example"), ("referer", "https://a.example/")])));
+        assert_ne!(base, key(&Url::parse("https://cdn.example/seg1.ts").unwrap(), &headers(&[("origin", "https://a.example"), ("referer", "https://a.example/")])));
+    }
+
+    #[test]
+    fn range_requests_map_to_the_whole_response() {
+        let url = Url::parse("https://cdn.example/seg0.ts").unwrap();
+        let plain = headers(&[("origin", "https://a.example")]);
+        let ranged = headers(&[("origin", "https://a.example"), ("range", "bytes=0-99")]);
+        assert_eq!(range_key(&url, &ranged), Some((key(&url, &plain), "bytes=0-99".to_string())));
+        assert_eq!(range_key(&url, &plain), None);
+        let conditional = headers(&[("range", "bytes=0-99"), ("if-range", "\"etag\"")]);
+        assert_eq!(range_key(&url, &conditional), None);
+    }
+
+    #[tokio::test]
+    async fn recorder_keeps_complete_bodies_within_the_limit() {
+        use futures_util::stream;
+        use std::sync::{Arc, Mutex};
+
+        let run = |chunks: Vec<Result<Bytes, std::io::Error>>, limit: u64| async move {
+            let kept = Arc::new(Mutex::new(None));
+            let sink = kept.clone();
+            let recorder = Recorder::new(stream::iter(chunks), limit, move |body| *sink.lock().unwrap() = Some(body));
+            let passed: Vec<_> = recorder.collect().await;
+            let kept = kept.lock().unwrap().take();
+            (passed.len(), kept)
+        };
+        let ok = |s: &'static str| Ok(Bytes::from_static(s.as_bytes()));
+
+        assert_eq!(run(vec![ok("ab"), ok("cd")], 4).await, (2, Some(Bytes::from_static(b"abcd"))));
+        assert_eq!(run(vec![ok("ab"), ok("cde")], 4).await, (2, None));
+        assert_eq!(run(vec![ok("ab"), Err(std::io::Error::other("reset"))], 4).await, (2, None));
+    }
+
+    #[test]
+    fn validates_limits() {
+        assert!(CacheConfig::default().validate().is_ok());
+        let too_big = CacheConfig { max_entry_bytes: 2, max_bytes: 1, ..Default::default() };
+        assert_eq!(too_big.validate().unwrap_err(), "max_entry_bytes is larger than max_bytes");
+        for fraction in [0.0, 1.5, f64::NAN] {
+            let config = CacheConfig { live_playlist_fraction: fraction, ..Default::default() };
+            assert!(config.validate().is_err(), "{}", fraction);
+        }
+    }
+}
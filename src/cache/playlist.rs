@@ -0,0 +1,123 @@
+// Rewritten media playlists. Every player of a live channel re-polls its
+// playlist once per target duration; keeping the rewritten output for part
+// of that interval leaves the origin one request per interval. Playlists
+// that have ended don't change and are kept longer.
+
+use bytes::Bytes;
+use reqwest::{header::HeaderMap, StatusCode};
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+use super::{memory, CacheConfig, CachedResponse};
+use crate::{
+    config,
+    hls::playlist::{MediaPlaylist, Playlist},
+};
+
+// Key for a rewritten playlist: the upstream fetch (see super::key) plus
+// every parameter that changes the rewritten output
+pub fn key(url: &Url, headers: &HeaderMap, rewrite: &[(&str, Option<&str>)]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"playlist\n");
+    hasher.update(super::key(url, headers));
+    for (name, value) in rewrite {
+        hasher.update(b"\n");
+        hasher.update(name);
+        if let Some(value) = value {
+            hasher.update(b"=");
+            hasher.update(value);
+        }
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// How long a rewritten media playlist may be served again. None for master
+// playlists, which players fetch once, and for playlists without a target duration.
+pub fn ttl(media: &MediaPlaylist, config: &CacheConfig) -> Option<Duration> {
+    if media.has_end_list() || media.playlist_type() == Some("VOD") {
+        return Some(Duration::from_secs(config.vod_playlist_ttl_secs)).filter(|ttl| !ttl.is_zero());
+    }
+    // Low-latency players reload once per part, not once per segment
+    let interval = media
+        .part_target()
+        .filter(|t| *t > 0.0)
+        .or_else(|| media.target_duration().map(|t| t as f64))?;
+    Some(Duration::from_secs_f64(interval * config.live_playlist_fraction)).filter(|ttl| !ttl.is_zero())
+}
+
+// Keep a rewritten playlist, if it is a media playlist
+pub fn store(key: String, body: Bytes) {
+    let config = config::current();
+    let Playlist::Media(media) = Playlist::parse(&String::from_utf8_lossy(&body)) else {
+        return;
+    };
+    let Some(ttl) = ttl(&media, &config.cache) else {
+        return;
+    };
+    let response = CachedResponse {
+        status: StatusCode::OK,
+        headers: HeaderMap::new(),
+        body,
+        stored: SystemTime::now(),
+    };
+    memory::insert(key, response, ttl);
+}
+
+pub fn get(key: &str) -> Option<CachedResponse> {
+    memory::get(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ttl_of(text: &str) -> Option<Duration> {
+        match Playlist::parse(text) {
+            Playlist::Media(media) => ttl(&media, &CacheConfig::default()),
+            Playlist::Master(_) => panic!("parsed as a master playlist"),
+        }
+    }
+
+    #[test]
+    fn live_playlists_are_kept_for_part_of_the_target_duration() {
+        let live = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:1\n#EXTINF:6.0,\na.ts";
+        assert_eq!(ttl_of(live), Some(Duration::from_secs(3)));
+        // Leading tags the model doesn't know don't hide the target duration
+        let packaged = "#EXTM3U\n#EXT-X-ALLOW-CACHE:NO\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\na.ts";
+        assert_eq!(ttl_of(packaged), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn low_latency_playlists_follow_the_part_target() {
+        let text = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-PART-INF:PART-TARGET=1.0\n#EXTINF:4.0,\na.mp4";
+        assert_eq!(ttl_of(text), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn ended_playlists_are_kept_longer() {
+        let vod = "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\na.ts\n#EXT-X-ENDLIST";
+        assert_eq!(ttl_of(vod), Some(Duration::from_secs(300)));
+        let typed = "#EXTM3U\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.0,\na.ts";
+        assert_eq!(ttl_of(typed), Some(Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn playlists_without_a_target_duration_are_not_kept() {
+        assert_eq!(ttl_of("#EXTM3U\n#EXTINF:6.0,\na.ts"), None);
+    }
+
+    #[test]
+    fn keys_cover_rewrite_params() {
+        let url = Url::parse("https://cdn.example/live.m3u8").unwrap();
+        let headers = HeaderMap::new();
+        let plain = key(&url, &headers, &[("origin", None), ("strip_ads", None)]);
+        assert_eq!(plain, key(&url, &headers, &[("origin", None), ("strip_ads", None)]));
+        assert_ne!(plain, key(&url, &headers, &[("origin", None), ("strip_ads", Some("1"))]));
+        assert_ne!(plain, super::super::key(&url, &headers));
+    }
+}
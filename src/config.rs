@@ -0,0 +1,321 @@
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::{fmt, fs, path::{Path, PathBuf}, sync::Arc, time::{Duration, SystemTime}};
+
+use url::Url;
+
+use crate::cache::CacheConfig;
+use crate::cors::AllowedOrigins;
+use crate::hls::{ads::{AdFilter, AdFilterConfig}, delta::DeltaConfig, renditions::RenditionFilter, variants::VariantFilter};
+use crate::policy::{HostPolicy, HostPolicyConfig};
+use crate::prefetch::PrefetchConfig;
+use crate::sessions::SessionConfig;
+use crate::signing::SigningConfig;
+use crate::ssrf::{RedirectConfig, SsrfConfig};
+use crate::templates::{DomainGroupConfig, Templates};
+
+// Env var pointing at the config file (TOML, JSON or YAML, picked by extension)
+pub const CONFIG_PATH_ENV: &str = "PROXY_CONFIG";
+
+// How often the config file's mtime is checked for changes
+pub const CONFIG_POLL_ENV: &str = "CONFIG_POLL_SECS";
+
+// Comma-separated allowed origins, used when the config file doesn't list its own
+pub const ALLOWED_ORIGINS_ENV: &str = "ALLOWED_ORIGINS";
+
+// Allowed origins used when neither the config file nor the env sets them
+const DEFAULT_ALLOWED_ORIGINS: [&str; 2] = [
+    "http://localhost:5173",
+    "http://localhost:3000",
+];
+
+// Active config, swapped atomically on reload so in-flight requests keep the snapshot they started with
+static ACTIVE: Lazy<ArcSwap<Config>> = Lazy::new(|| ArcSwap::from_pointee(Config::builtin()));
+
+// Raw shape of the config file - every section is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub domain_groups: Option<Vec<DomainGroupConfig>>,
+    pub allowed_origins: Option<Vec<String>>,
+    pub ssrf: SsrfConfig,
+    pub redirects: RedirectConfig,
+    pub host_policy: HostPolicyConfig,
+    pub signing: SigningConfig,
+    pub sessions: SessionConfig,
+    pub variants: VariantFilter,
+    pub renditions: RenditionFilter,
+    pub ad_filter: AdFilterConfig,
+    pub delta_playlists: DeltaConfig,
+    pub cache: CacheConfig,
+    pub prefetch: PrefetchConfig,
+}
+
+// Validated config the request handlers read from
+pub struct Config {
+    pub templates: Templates,
+    pub allowed_origins: AllowedOrigins,
+    pub ssrf: SsrfConfig,
+    pub redirects: RedirectConfig,
+    pub host_policy: HostPolicy,
+    pub signing: SigningConfig,
+    pub sessions: SessionConfig,
+    // Default variant filter for master playlists; requests can override it
+    pub variants: VariantFilter,
+    // Default audio/subtitle rendition selection, also overridable per request
+    pub renditions: RenditionFilter,
+    pub ad_filter: AdFilter,
+    pub delta_playlists: DeltaConfig,
+    pub cache: CacheConfig,
+    pub prefetch: PrefetchConfig,
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    UnknownFormat(PathBuf),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: parse error: {}", path.display(), e),
+            ConfigError::UnknownFormat(path) => write!(
+                f,
+                "{}: unknown config format (expected .toml, .json, .yaml or .yml)",
+                path.display()
+            ),
+            ConfigError::Invalid(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    // Config made only of compiled-in defaults
+    pub fn builtin() -> Config {
+        Config {
+            templates: Templates::builtin(),
+            allowed_origins: AllowedOrigins::compile(&default_allowed_origins())
+                .expect("default allowed origins must be valid"),
+            ssrf: SsrfConfig::default(),
+            redirects: RedirectConfig::default(),
+            host_policy: HostPolicy::default(),
+            signing: SigningConfig::default(),
+            sessions: SessionConfig::default(),
+            variants: VariantFilter::default(),
+            renditions: RenditionFilter::default(),
+            ad_filter: AdFilter::default(),
+            delta_playlists: DeltaConfig::default(),
+            cache: CacheConfig::default(),
+            prefetch: PrefetchConfig::default(),
+            source: None,
+        }
+    }
+
+    // Config without a file: built-in defaults plus env overrides
+    pub fn from_env() -> Result<Config, ConfigError> {
+        Config::build(FileConfig::default(), None)
+    }
+
+    // Read, parse and validate a config file
+    pub fn from_path(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let file = parse(path, &text)?;
+        Config::build(file, Some(path))
+    }
+
+    fn build(file: FileConfig, source: Option<&Path>) -> Result<Config, ConfigError> {
+        let label = source.map_or_else(|| "environment".to_string(), |p| p.display().to_string());
+        let invalid = |e: String| ConfigError::Invalid(format!("{}: {}", label, e));
+
+        // Fall back to the built-in list when the file doesn't define any groups
+        let templates = match file.domain_groups {
+            Some(groups) => Templates::compile(&groups).map_err(|e| invalid(e.to_string()))?,
+            None => Templates::builtin(),
+        };
+
+        // Config file first, then the env var, then the localhost defaults
+        let origins = file
+            .allowed_origins
+            .or_else(env_allowed_origins)
+            .unwrap_or_else(default_allowed_origins);
+        let allowed_origins = AllowedOrigins::compile(&origins)
+            .map_err(|e| invalid(format!("allowed_origins: {}", e)))?;
+
+        let host_policy = HostPolicy::compile(&file.host_policy)
+            .map_err(|e| invalid(format!("host_policy.{}", e)))?;
+
+        file.signing
+            .validate()
+            .map_err(|e| invalid(format!("signing: {}", e)))?;
+
+        let ad_filter = AdFilter::compile(&file.ad_filter)
+            .map_err(|e| invalid(format!("ad_filter.{}", e)))?;
+
+        file.variants
+            .validate()
+            .map_err(|e| invalid(format!("variants: {}", e)))?;
+
+        file.cache
+            .validate()
+            .map_err(|e| invalid(format!("cache: {}", e)))?;
+
+        file.prefetch
+            .validate()
+            .map_err(|e| invalid(format!("prefetch: {}", e)))?;
+
+        Ok(Config {
+            templates,
+            allowed_origins,
+            ssrf: file.ssrf,
+            redirects: file.redirects,
+            host_policy,
+            signing: file.signing,
+            sessions: file.sessions,
+            variants: file.variants,
+            renditions: file.renditions,
+            ad_filter,
+            delta_playlists: file.delta_playlists,
+            cache: file.cache,
+            prefetch: file.prefetch,
+            source: source.map(Path::to_path_buf),
+        })
+    }
+}
+
+impl Config {
+    // Host allow/deny policy for an upstream URL, about to be fetched or written into a playlist
+    pub fn check_upstream(&self, url: &Url) -> Result<(), String> {
+        self.host_policy.check(url, &self.templates)
+    }
+}
+
+fn default_allowed_origins() -> Vec<String> {
+    DEFAULT_ALLOWED_ORIGINS.iter().map(|o| o.to_string()).collect()
+}
+
+fn env_allowed_origins() -> Option<Vec<String>> {
+    let value = std::env::var(ALLOWED_ORIGINS_ENV).ok()?;
+    let origins: Vec<String> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .map(str::to_string)
+        .collect();
+    (!origins.is_empty()).then_some(origins)
+}
+
+fn parse(path: &Path, text: &str) -> Result<FileConfig, ConfigError> {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+    let parse_err = |e: String| ConfigError::Parse(path.to_path_buf(), e);
+
+    match ext.as_str() {
+        "toml" => toml::from_str(text).map_err(|e| parse_err(e.to_string())),
+        "json" => serde_json::from_str(text).map_err(|e| parse_err(e.to_string())),
+        "yaml" | "yml" => serde_yaml::from_str(text).map_err(|e| parse_err(e.to_string())),
+        _ => Err(ConfigError::UnknownFormat(path.to_path_buf())),
+    }
+}
+
+// Config file path from the environment, if one was set
+pub fn config_path() -> Option<PathBuf> {
+    std::env::var_os(CONFIG_PATH_ENV)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
+// Load the config at startup; a broken config file is a hard error
+pub fn init() -> Result<Arc<Config>, ConfigError> {
+    let config = match config_path() {
+        Some(path) => Config::from_path(&path)?,
+        None => Config::from_env()?,
+    };
+    ACTIVE.store(Arc::new(config));
+    Ok(current())
+}
+
+// Snapshot of the active config - hold on to it for the whole request
+pub fn current() -> Arc<Config> {
+    ACTIVE.load_full()
+}
+
+// Re-read the config file and swap it in; on failure the old config stays active
+pub fn reload() -> Result<Arc<Config>, ConfigError> {
+    let Some(path) = config_path() else {
+        return Ok(current());
+    };
+    let config = Arc::new(Config::from_path(&path)?);
+    ACTIVE.store(config.clone());
+    Ok(config)
+}
+
+fn reload_and_log(trigger: &str) {
+    match reload() {
+        Ok(config) => println!(
+            "Config reloaded ({}): {} domain groups, {} allowed origins",
+            trigger,
+            config.templates.len(),
+            config.allowed_origins.len()
+        ),
+        Err(e) => eprintln!("Config reload ({}) failed, keeping previous config: {}", trigger, e),
+    }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Reload on SIGHUP and whenever the config file's mtime changes
+pub fn spawn_reloader() {
+    let Some(path) = config_path() else {
+        return;
+    };
+
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        while hup.recv().await.is_some() {
+            reload_and_log("SIGHUP");
+        }
+    });
+
+    let poll_secs = std::env::var(CONFIG_POLL_ENV)
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(2);
+    if poll_secs == 0 {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut last_modified = modified_at(&path);
+        let mut interval = tokio::time::interval(Duration::from_secs(poll_secs));
+        loop {
+            interval.tick().await;
+            let modified = modified_at(&path);
+            if modified.is_some() && modified != last_modified {
+                last_modified = modified;
+                reload_and_log("file change");
+            }
+        }
+    });
+}
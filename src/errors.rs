@@ -0,0 +1,224 @@
+use actix_web::{http::header, http::StatusCode, HttpRequest, HttpResponse};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{error::Error, time::Duration};
+use url::Url;
+
+use crate::ssrf;
+
+// Seconds to wait for an upstream connection (DNS, TCP and TLS) before giving up with a 504
+pub const CONNECT_TIMEOUT_ENV: &str = "UPSTREAM_CONNECT_TIMEOUT_SECS";
+
+// Taken from the client request when present, otherwise generated, and sent
+// back on error responses so player reports can be matched with proxy logs
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+static CONNECT_TIMEOUT: Lazy<Duration> = Lazy::new(|| {
+    let secs = std::env::var(CONNECT_TIMEOUT_ENV)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10);
+    Duration::from_secs(secs)
+});
+
+pub fn connect_timeout() -> Duration {
+    *CONNECT_TIMEOUT
+}
+
+// What went wrong, so clients can tell a dead source from a broken proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    // SSRF guard, host policy or redirect policy refused the upstream
+    Blocked,
+    // No connection to the upstream within the connect timeout
+    UpstreamTimeout,
+    // DNS, TCP or TLS failure
+    UpstreamUnreachable,
+    // The upstream answered with an error status
+    UpstreamStatus,
+    // Connected, but the request or body failed
+    UpstreamError,
+}
+
+// JSON error body for a failed upstream fetch
+#[derive(Debug, Serialize)]
+pub struct ProxyError {
+    #[serde(rename = "error")]
+    pub kind: ErrorKind,
+    pub message: String,
+    pub upstream_status: Option<u16>,
+    pub target_host: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl ProxyError {
+    fn new(kind: ErrorKind, message: impl Into<String>) -> ProxyError {
+        ProxyError {
+            kind,
+            message: message.into(),
+            upstream_status: None,
+            target_host: None,
+            request_id: None,
+        }
+    }
+
+    pub fn blocked(reason: impl Into<String>) -> ProxyError {
+        ProxyError::new(ErrorKind::Blocked, reason)
+    }
+
+    pub fn upstream_status(status: reqwest::StatusCode) -> ProxyError {
+        let mut error = ProxyError::new(ErrorKind::UpstreamStatus, format!("Upstream returned {}", status));
+        error.upstream_status = Some(status.as_u16());
+        error
+    }
+
+    pub fn from_reqwest(err: &reqwest::Error) -> ProxyError {
+        if let Some(reason) = ssrf::blocked_reason(err) {
+            return ProxyError::blocked(reason);
+        }
+        let mut error = if err.is_timeout() {
+            ProxyError::new(ErrorKind::UpstreamTimeout, "Timed out connecting to upstream")
+        } else if err.is_connect() {
+            ProxyError::new(ErrorKind::UpstreamUnreachable, format!("Cannot reach upstream: {}", root_cause(err)))
+        } else {
+            ProxyError::new(ErrorKind::UpstreamError, format!("Upstream fetch failed: {}", root_cause(err)))
+        };
+        error.upstream_status = err.status().map(|s| s.as_u16());
+        error
+    }
+
+    pub fn for_target(mut self, url: &Url) -> ProxyError {
+        self.target_host = url.host_str().map(str::to_string);
+        self
+    }
+
+    fn status(&self) -> StatusCode {
+        match self.kind {
+            ErrorKind::Blocked => StatusCode::FORBIDDEN,
+            ErrorKind::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorKind::UpstreamUnreachable | ErrorKind::UpstreamError => StatusCode::BAD_GATEWAY,
+            ErrorKind::UpstreamStatus => self
+                .upstream_status
+                .and_then(|s| StatusCode::from_u16(s).ok())
+                .unwrap_or(StatusCode::BAD_GATEWAY),
+        }
+    }
+
+    pub fn response(mut self, request_id: &str, acao: Option<String>) -> HttpResponse {
+        let host = self.target_host.as_deref().unwrap_or("-");
+        eprintln!("[{}] {:?} from {}: {}", request_id, self.kind, host, self.message);
+        self.request_id = Some(request_id.to_string());
+        HttpResponse::build(self.status())
+            .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, acao.unwrap_or("*".to_string())))
+            .insert_header((header::ACCESS_CONTROL_EXPOSE_HEADERS, REQUEST_ID_HEADER))
+            .insert_header(("Vary", "Origin"))
+            .insert_header((REQUEST_ID_HEADER, request_id))
+            .json(self)
+    }
+}
+
+// Innermost error message, e.g. "failed to lookup address information" or
+// "invalid peer certificate" rather than reqwest's "error sending request"
+fn root_cause(err: &(dyn Error + 'static)) -> String {
+    let mut cause = err;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+// The client's request id if it sent a usable one, otherwise a fresh one
+pub fn request_id(req: &HttpRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128 && v.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn maps_kinds_to_statuses() {
+        assert_eq!(ProxyError::blocked("no").status(), StatusCode::FORBIDDEN);
+        assert_eq!(ProxyError::new(ErrorKind::UpstreamTimeout, "").status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(ProxyError::new(ErrorKind::UpstreamUnreachable, "").status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(ProxyError::new(ErrorKind::UpstreamError, "").status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn upstream_statuses_are_passed_on() {
+        for status in [404, 410, 451, 500, 503] {
+            let error = ProxyError::upstream_status(reqwest::StatusCode::from_u16(status).unwrap());
+            assert_eq!(error.status().as_u16(), status);
+            assert_eq!(error.upstream_status, Some(status));
+        }
+        assert_eq!(
+            ProxyError::upstream_status(reqwest::StatusCode::NOT_FOUND).message,
+            "Upstream returned 404 Not Found"
+        );
+        // Without a usable status of its own it is the proxy's 502
+        let mut error = ProxyError::new(ErrorKind::UpstreamStatus, "");
+        assert_eq!(error.status(), StatusCode::BAD_GATEWAY);
+        error.upstream_status = Some(1000);
+        assert_eq!(error.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[actix_web::test]
+    async fn responds_with_a_json_body() {
+        let url = Url::parse("https://cdn.example.com/live.m3u8").unwrap();
+        let response = ProxyError::upstream_status(reqwest::StatusCode::NOT_FOUND)
+            .for_target(&url)
+            .response("abc123", Some("https://player.example.com".to_string()));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let headers = response.headers();
+        assert_eq!(headers.get(REQUEST_ID_HEADER).unwrap(), "abc123");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://player.example.com");
+        assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "application/json");
+
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "error": "upstream_status",
+                "message": "Upstream returned 404 Not Found",
+                "upstream_status": 404,
+                "target_host": "cdn.example.com",
+                "request_id": "abc123",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_connections_are_unreachable() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let err = reqwest::Client::new()
+            .get(format!("http://127.0.0.1:{}/", port))
+            .send()
+            .await
+            .unwrap_err();
+        let error = ProxyError::from_reqwest(&err);
+        assert_eq!(error.kind, ErrorKind::UpstreamUnreachable);
+        assert_eq!(error.status(), StatusCode::BAD_GATEWAY);
+        assert!(error.message.starts_with("Cannot reach upstream: "), "{}", error.message);
+        assert_eq!(error.upstream_status, None);
+    }
+
+    #[test]
+    fn takes_usable_request_ids_from_the_client() {
+        let with = |value: &str| request_id(&TestRequest::default().insert_header((REQUEST_ID_HEADER, value)).to_http_request());
+        assert_eq!(with("player-42"), "player-42");
+        for unusable in ["", "has space", &"x".repeat(129)] {
+            let generated = with(unusable);
+            assert_eq!(generated.len(), 16, "{:?}", unusable);
+            assert!(generated.bytes().all(|b| b.is_ascii_hexdigit()));
+        }
+        assert_ne!(request_id(&TestRequest::default().to_http_request()), request_id(&TestRequest::default().to_http_request()));
+    }
+}
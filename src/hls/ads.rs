@@ -0,0 +1,477 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+use super::attributes::split_tag;
+use super::playlist::{Line, MediaPlaylist, Segment, Tag};
+use crate::policy::HostRule;
+
+// Streams whose renumbering state is kept; idle ones are dropped first
+const MAX_STREAMS: usize = 1_000;
+const STREAM_IDLE: Duration = Duration::from_secs(10 * 60);
+
+// [ad_filter] section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdFilterConfig {
+    pub enabled: bool,
+    // Segment hosts, same syntax as [host_policy]
+    pub hosts: Vec<String>,
+    // Regexes over the resolved segment URL
+    pub url_patterns: Vec<String>,
+    // #EXT-X-CUE-OUT / -CUE-OUT-CONT / -CUE-IN
+    pub cue_markers: bool,
+    // #EXT-X-DATERANGE with SCTE35-OUT / SCTE35-IN
+    pub scte35: bool,
+    // Runs between two discontinuities shorter than this many seconds; 0 disables
+    pub max_discontinuity_run: f64,
+}
+
+impl Default for AdFilterConfig {
+    fn default() -> Self {
+        AdFilterConfig {
+            enabled: false,
+            hosts: Vec::new(),
+            url_patterns: Vec::new(),
+            cue_markers: true,
+            scte35: true,
+            max_discontinuity_run: 0.0,
+        }
+    }
+}
+
+// Removed segments per stream, so sequence numbers come out the same on every reload
+#[derive(Default)]
+struct StreamState {
+    // Original media sequence numbers of removed segments
+    removed: BTreeSet<u64>,
+    // Removed segments whose discontinuity merged into an existing one
+    merged_discontinuities: BTreeSet<u64>,
+    // Entries of the two sets above that have left the window
+    removed_base: u64,
+    merged_base: u64,
+    last_used: Option<Instant>,
+}
+
+impl StreamState {
+    fn removed_below(&self, seq: u64) -> u64 {
+        self.removed_base + self.removed.range(..seq).count() as u64
+    }
+
+    fn merged_below(&self, seq: u64) -> u64 {
+        self.merged_base + self.merged_discontinuities.range(..seq).count() as u64
+    }
+
+    // Fold everything before the window start into the base counts
+    fn prune(&mut self, window_start: u64) {
+        let kept = self.removed.split_off(&window_start);
+        self.removed_base += std::mem::replace(&mut self.removed, kept).len() as u64;
+        let kept = self.merged_discontinuities.split_off(&window_start);
+        self.merged_base += std::mem::replace(&mut self.merged_discontinuities, kept).len() as u64;
+    }
+}
+
+static STREAMS: Lazy<Mutex<HashMap<String, StreamState>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Default)]
+pub struct AdFilter {
+    enabled: bool,
+    hosts: Vec<HostRule>,
+    url_patterns: Vec<Regex>,
+    cue_markers: bool,
+    scte35: bool,
+    max_discontinuity_run: f64,
+}
+
+// "30", "30.0" or "DURATION=30,..." after #EXT-X-CUE-OUT:
+fn cue_duration(value: &str) -> Option<f64> {
+    value
+        .split(',')
+        .find_map(|part| part.trim().trim_start_matches("DURATION=").parse().ok())
+}
+
+// Whether the playlist's segment numbers are only ever seen in the playlist
+// itself. LL-HLS players ask for segments by upstream number (_HLS_msn, passed
+// on as is) and read LAST-MSN from rendition reports; the segments of a delta
+// update start SKIPPED-SEGMENTS after the media sequence, and removals among
+// the skipped ones can't be reflected.
+fn renumberable(playlist: &MediaPlaylist) -> bool {
+    let server_control = playlist.server_control();
+    playlist.skip().is_none()
+        && playlist.part_target().is_none()
+        && !server_control.is_some_and(|a| a.get("CAN-BLOCK-RELOAD") == Some("YES") || a.get("CAN-SKIP-UNTIL").is_some())
+}
+
+impl AdFilter {
+    pub fn compile(config: &AdFilterConfig) -> Result<AdFilter, String> {
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|h| HostRule::parse(h))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("hosts: {}", e))?;
+        let url_patterns = config
+            .url_patterns
+            .iter()
+            .map(|p| Regex::new(p).map_err(|e| format!("url_patterns: invalid regex {:?}: {}", p, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AdFilter {
+            enabled: config.enabled,
+            hosts,
+            url_patterns,
+            cue_markers: config.cue_markers,
+            scte35: config.scte35,
+            max_discontinuity_run: config.max_discontinuity_run,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn is_ad_url(&self, base: &Url, uri: &str) -> bool {
+        if self.hosts.is_empty() && self.url_patterns.is_empty() {
+            return false;
+        }
+        let Ok(url) = base.join(uri) else {
+            return false;
+        };
+        let host = url.host_str().unwrap_or("").to_ascii_lowercase();
+        self.hosts.iter().any(|rule| rule.matches(&host))
+            || self.url_patterns.iter().any(|re| re.is_match(url.as_str()))
+    }
+
+    // One flag per segment: whether it's an ad
+    fn detect(&self, base: &Url, segments: &[Segment]) -> Vec<bool> {
+        let mut ads = vec![false; segments.len()];
+
+        // Marker-delimited breaks, optionally ended by their announced duration
+        let mut in_break = false;
+        let mut remaining: Option<f64> = None;
+        for (i, segment) in segments.iter().enumerate() {
+            for tag in segment.tags.iter().filter_map(Line::tag) {
+                match tag {
+                    Tag::Unknown(raw) if self.cue_markers => match split_tag(raw) {
+                        ("#EXT-X-CUE-OUT", value) => {
+                            in_break = true;
+                            remaining = value.and_then(cue_duration);
+                        }
+                        // Window starts in the middle of a break
+                        ("#EXT-X-CUE-OUT-CONT", _) => in_break = true,
+                        ("#EXT-X-CUE-IN", _) => {
+                            in_break = false;
+                            remaining = None;
+                        }
+                        _ => {}
+                    },
+                    Tag::DateRange(attrs) if self.scte35 => {
+                        if attrs.get("SCTE35-IN").is_some() {
+                            in_break = false;
+                            remaining = None;
+                        } else if attrs.get("SCTE35-OUT").is_some() {
+                            in_break = true;
+                            remaining = attrs.get_f64("DURATION").or_else(|| attrs.get_f64("PLANNED-DURATION"));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            if in_break {
+                ads[i] = true;
+                if let Some(left) = &mut remaining {
+                    *left -= segment.duration();
+                    if *left <= 0.01 {
+                        in_break = false;
+                        remaining = None;
+                    }
+                }
+            }
+
+            ads[i] |= self.is_ad_url(base, &segment.uri);
+        }
+
+        // Short runs with a discontinuity on both sides
+        if self.max_discontinuity_run > 0.0 {
+            let starts: Vec<usize> = (0..segments.len()).filter(|&i| segments[i].discontinuity()).collect();
+            for pair in starts.windows(2) {
+                let run = &segments[pair[0]..pair[1]];
+                if run.iter().map(Segment::duration).sum::<f64>() < self.max_discontinuity_run {
+                    ads[pair[0]..pair[1]].iter_mut().for_each(|ad| *ad = true);
+                }
+            }
+        }
+
+        ads
+    }
+
+    // Remove ad segments. `key` identifies the stream across reloads, upstream
+    // headers included (see prefetch::playlist_key); `base` resolves relative
+    // segment URIs.
+    pub fn apply(&self, key: &str, base: &Url, playlist: &mut MediaPlaylist) {
+        if !renumberable(playlist) {
+            return;
+        }
+        let first_seq = playlist.media_sequence();
+        let mut ads = self.detect(base, &playlist.segments);
+
+        let mut streams = STREAMS.lock().unwrap();
+        // Once removed, always removed - even after the marker that started
+        // the break has left the window
+        if let Some(state) = streams.get(key) {
+            for (i, ad) in ads.iter_mut().enumerate() {
+                *ad |= state.removed.contains(&(first_seq + i as u64));
+            }
+        }
+        // Streams with earlier removals are renumbered even without ads in view
+        if !ads.contains(&true) && !streams.contains_key(key) {
+            return;
+        }
+        if !ads.contains(&false) {
+            eprintln!("Every segment of {} looks like an ad, leaving it unfiltered", key);
+            return;
+        }
+
+        if streams.len() >= MAX_STREAMS && !streams.contains_key(key) {
+            streams.retain(|_, s| s.last_used.is_some_and(|t| t.elapsed() < STREAM_IDLE));
+        }
+        let state = streams.entry(key.to_string()).or_default();
+        state.last_used = Some(Instant::now());
+        state.prune(first_seq);
+
+        // Discontinuity, key and init section of removed segments still apply
+        // to the next segment that stays
+        let mut carried_discontinuity: Option<u64> = None;
+        let mut carried_key: Option<Line> = None;
+        let mut carried_map: Option<Line> = None;
+        let mut first_kept: Option<u64> = None;
+
+        let segments = std::mem::take(&mut playlist.segments);
+        for (i, (mut segment, is_ad)) in segments.into_iter().zip(ads).enumerate() {
+            let seq = first_seq + i as u64;
+            if is_ad {
+                state.removed.insert(seq);
+                for line in segment.tags {
+                    match line.tag() {
+                        Some(Tag::Discontinuity) => match carried_discontinuity {
+                            Some(_) => {
+                                state.merged_discontinuities.insert(seq);
+                            }
+                            None => carried_discontinuity = Some(seq),
+                        },
+                        Some(Tag::Key(_)) => carried_key = Some(line),
+                        Some(Tag::Map(_)) => carried_map = Some(line),
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+
+            first_kept.get_or_insert(seq);
+            let has = |segment: &Segment, pick: fn(&Tag) -> bool| segment.tags.iter().filter_map(Line::tag).any(pick);
+            if let Some(line) = carried_map.take().filter(|_| !has(&segment, |t| matches!(t, Tag::Map(_)))) {
+                segment.tags.insert(0, line);
+            }
+            if let Some(line) = carried_key.take().filter(|_| !has(&segment, |t| matches!(t, Tag::Key(_)))) {
+                segment.tags.insert(0, line);
+            }
+            if let Some(origin) = carried_discontinuity.take() {
+                if segment.discontinuity() {
+                    state.merged_discontinuities.insert(origin);
+                } else {
+                    segment.tags.insert(0, Line::Tag(Tag::Discontinuity));
+                }
+            }
+            playlist.segments.push(segment);
+        }
+
+        // Renumber as if the removed segments never existed
+        let first_kept = first_kept.expect("at least one segment is kept");
+        let media_sequence = first_kept - state.removed_below(first_kept);
+        let discontinuity_sequence = playlist
+            .discontinuity_sequence()
+            .saturating_sub(state.merged_below(first_seq));
+        if media_sequence != first_seq {
+            playlist.set_media_sequence(media_sequence);
+        }
+        if discontinuity_sequence != playlist.discontinuity_sequence() {
+            playlist.set_discontinuity_sequence(discontinuity_sequence);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hls::playlist::Playlist;
+
+    fn media(text: &str) -> MediaPlaylist {
+        match Playlist::parse(text) {
+            Playlist::Media(media) => media,
+            Playlist::Master(_) => panic!("parsed as a master playlist"),
+        }
+    }
+
+    fn filter(config: AdFilterConfig) -> AdFilter {
+        AdFilter::compile(&AdFilterConfig { enabled: true, ..config }).unwrap()
+    }
+
+    // Filter `text` as the stream `key` and return the output
+    fn strip(filter: &AdFilter, key: &str, text: &str) -> String {
+        let base = Url::parse("https://cdn.example/live/index.m3u8").unwrap();
+        let mut playlist = media(text);
+        filter.apply(key, &base, &mut playlist);
+        playlist.to_string()
+    }
+
+    #[test]
+    fn strips_cue_marked_breaks_and_keeps_numbering_across_reloads() {
+        let filter = filter(AdFilterConfig::default());
+        let key = "test://ads/cue";
+
+        let first = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:0\n#EXTINF:4.0,\nc0.ts\n\
+                     #EXT-X-DISCONTINUITY\n#EXT-X-CUE-OUT:8\n#EXTINF:4.0,\nad1.ts\n#EXTINF:4.0,\nad2.ts\n\
+                     #EXT-X-DISCONTINUITY\n#EXT-X-CUE-IN\n#EXTINF:4.0,\nc3.ts";
+        assert_eq!(
+            strip(&filter, key, first),
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:0\n#EXTINF:4.0,\nc0.ts\n\
+             #EXT-X-DISCONTINUITY\n#EXT-X-CUE-IN\n#EXTINF:4.0,\nc3.ts"
+        );
+
+        // The CUE-OUT has left the window, but ad2 stays removed and c3 keeps
+        // sequence number 1 and discontinuity number 1
+        let second = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:2\n#EXT-X-DISCONTINUITY-SEQUENCE:1\n\
+                      #EXTINF:4.0,\nad2.ts\n#EXT-X-DISCONTINUITY\n#EXTINF:4.0,\nc3.ts\n#EXTINF:4.0,\nc4.ts";
+        assert_eq!(
+            strip(&filter, key, second),
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:1\n#EXT-X-DISCONTINUITY-SEQUENCE:0\n\
+             #EXT-X-DISCONTINUITY\n#EXTINF:4.0,\nc3.ts\n#EXTINF:4.0,\nc4.ts"
+        );
+
+        // No ads in view anymore, still renumbered
+        let third = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:4\n#EXT-X-DISCONTINUITY-SEQUENCE:2\n\
+                     #EXTINF:4.0,\nc4.ts\n#EXTINF:4.0,\nc5.ts";
+        assert_eq!(
+            strip(&filter, key, third),
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:2\n#EXT-X-DISCONTINUITY-SEQUENCE:1\n\
+             #EXTINF:4.0,\nc4.ts\n#EXTINF:4.0,\nc5.ts"
+        );
+    }
+
+    #[test]
+    fn renumbers_playlists_with_unknown_header_tags() {
+        let filter = filter(AdFilterConfig::default());
+        let text = "#EXTM3U\n#EXT-X-ALLOW-CACHE:NO\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:500\n\
+                    #EXT-X-CUE-OUT-CONT\n#EXTINF:4.0,\nad500.ts\n#EXT-X-CUE-IN\n#EXTINF:4.0,\nc501.ts";
+        assert_eq!(
+            strip(&filter, "test://ads/unknown-header", text),
+            "#EXTM3U\n#EXT-X-ALLOW-CACHE:NO\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:500\n\
+             #EXT-X-CUE-IN\n#EXTINF:4.0,\nc501.ts"
+        );
+
+        let next = "#EXTM3U\n#EXT-X-ALLOW-CACHE:NO\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:501\n\
+                    #EXTINF:4.0,\nc501.ts\n#EXTINF:4.0,\nc502.ts";
+        assert_eq!(
+            strip(&filter, "test://ads/unknown-header", next),
+            "#EXTM3U\n#EXT-X-ALLOW-CACHE:NO\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:500\n\
+             #EXTINF:4.0,\nc501.ts\n#EXTINF:4.0,\nc502.ts"
+        );
+    }
+
+    #[test]
+    fn cue_markers_can_be_turned_off() {
+        let filter = filter(AdFilterConfig { cue_markers: false, ..Default::default() });
+        let text = "#EXTM3U\n#EXTINF:4.0,\nc0.ts\n#EXT-X-CUE-OUT:4\n#EXTINF:4.0,\nad1.ts\n#EXTINF:4.0,\nc2.ts";
+        assert_eq!(strip(&filter, "test://ads/cue-off", text), text);
+    }
+
+    #[test]
+    fn strips_scte35_dateranges() {
+        let filter = filter(AdFilterConfig::default());
+        let text = "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:7\n#EXTINF:4.0,\nc0.ts\n\
+                    #EXT-X-DATERANGE:ID=\"b1\",START-DATE=\"2024-01-01T00:00:04Z\",SCTE35-OUT=0xFC01\n\
+                    #EXTINF:4.0,\nad1.ts\n#EXTINF:4.0,\nad2.ts\n\
+                    #EXT-X-DATERANGE:ID=\"b1\",START-DATE=\"2024-01-01T00:00:04Z\",SCTE35-IN=0xFC02\n\
+                    #EXTINF:4.0,\nc3.ts";
+        let out = strip(&filter, "test://ads/scte35", text);
+        assert!(!out.contains("ad1.ts") && !out.contains("ad2.ts"));
+        assert!(out.starts_with("#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:7\n#EXTINF:4.0,\nc0.ts\n"));
+        assert!(out.ends_with("SCTE35-IN=0xFC02\n#EXTINF:4.0,\nc3.ts"));
+    }
+
+    #[test]
+    fn strips_by_host_and_url_pattern() {
+        let filter = filter(AdFilterConfig {
+            hosts: vec!["*.ads.example.com".to_string()],
+            url_patterns: vec!["/adbreak/".to_string()],
+            ..Default::default()
+        });
+        let text = "#EXTM3U\n#EXTINF:4.0,\nc0.ts\n#EXTINF:4.0,\nhttps://x.ads.example.com/a.ts\n\
+                    #EXTINF:4.0,\nadbreak/b.ts\n#EXTINF:4.0,\nhttps://ads.example.com/c.ts";
+        assert_eq!(
+            strip(&filter, "test://ads/hosts", text),
+            "#EXTM3U\n#EXTINF:4.0,\nc0.ts\n#EXTINF:4.0,\nhttps://ads.example.com/c.ts"
+        );
+    }
+
+    #[test]
+    fn strips_short_discontinuity_runs() {
+        let filter = filter(AdFilterConfig { max_discontinuity_run: 10.0, ..Default::default() });
+        let text = "#EXTM3U\n#EXTINF:4.0,\nc0.ts\n#EXT-X-DISCONTINUITY\n#EXTINF:4.0,\na1.ts\n#EXTINF:4.0,\na2.ts\n\
+                    #EXT-X-DISCONTINUITY\n#EXTINF:4.0,\nc3.ts\n#EXTINF:4.0,\nc4.ts\n#EXTINF:4.0,\nc5.ts\n\
+                    #EXT-X-DISCONTINUITY\n#EXTINF:4.0,\nc6.ts";
+        assert_eq!(
+            strip(&filter, "test://ads/discontinuity-runs", text),
+            "#EXTM3U\n#EXTINF:4.0,\nc0.ts\n#EXT-X-DISCONTINUITY\n#EXTINF:4.0,\nc3.ts\n#EXTINF:4.0,\nc4.ts\n\
+             #EXTINF:4.0,\nc5.ts\n#EXT-X-DISCONTINUITY\n#EXTINF:4.0,\nc6.ts"
+        );
+    }
+
+    #[test]
+    fn carries_keys_init_sections_and_discontinuities_over() {
+        let filter = filter(AdFilterConfig::default());
+        let text = "#EXTM3U\n#EXTINF:4.0,\nc0.ts\n#EXT-X-CUE-OUT:4\n#EXT-X-DISCONTINUITY\n\
+                    #EXT-X-KEY:METHOD=AES-128,URI=\"k2.bin\"\n#EXT-X-MAP:URI=\"init2.mp4\"\n#EXTINF:4.0,\nad1.mp4\n\
+                    #EXTINF:4.0,\nc2.mp4";
+        assert_eq!(
+            strip(&filter, "test://ads/carry", text),
+            "#EXTM3U\n#EXTINF:4.0,\nc0.ts\n#EXT-X-DISCONTINUITY\n#EXT-X-KEY:METHOD=AES-128,URI=\"k2.bin\"\n\
+             #EXT-X-MAP:URI=\"init2.mp4\"\n#EXTINF:4.0,\nc2.mp4"
+        );
+    }
+
+    #[test]
+    fn leaves_low_latency_and_delta_playlists_alone() {
+        let filter = filter(AdFilterConfig::default());
+        let segments = "#EXT-X-MEDIA-SEQUENCE:3\n#EXTINF:4.0,\nc0.ts\n#EXT-X-CUE-OUT:4\n#EXTINF:4.0,\nad1.ts\n#EXTINF:4.0,\nc2.ts";
+        for header in [
+            "#EXT-X-PART-INF:PART-TARGET=1.0",
+            "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES",
+            "#EXT-X-SERVER-CONTROL:CAN-SKIP-UNTIL=24.0",
+            "#EXT-X-SKIP:SKIPPED-SEGMENTS=2",
+        ] {
+            let text = format!("#EXTM3U\n{}\n{}", header, segments);
+            assert_eq!(strip(&filter, "test://ads/ll-hls", &text), text);
+        }
+    }
+
+    #[test]
+    fn leaves_playlists_that_are_all_ads_alone() {
+        let filter = filter(AdFilterConfig::default());
+        let text = "#EXTM3U\n#EXT-X-CUE-OUT-CONT\n#EXTINF:4.0,\nad1.ts\n#EXTINF:4.0,\nad2.ts";
+        assert_eq!(strip(&filter, "test://ads/all-ads", text), text);
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let err = |config| AdFilter::compile(&config).err().unwrap();
+        assert!(err(AdFilterConfig { hosts: vec!["*".to_string()], ..Default::default() }).starts_with("hosts: "));
+        assert!(err(AdFilterConfig { url_patterns: vec!["(".to_string()], ..Default::default() }).starts_with("url_patterns: "));
+    }
+}
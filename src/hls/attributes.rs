@@ -0,0 +1,246 @@
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct Attribute<'a> {
+    pub name: &'a str,
+    // Value without surrounding quotes
+    pub value: &'a str,
+    pub quoted: bool,
+    // Byte range of the raw value (quotes included) in the source
+    value_start: usize,
+    value_end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Parsed attribute list that remembers where every value came from, so it
+// can be written back byte-for-byte with only selected values replaced.
+#[derive(Debug, Clone)]
+pub struct AttributeList<'a> {
+    source: &'a str,
+    attrs: Vec<Attribute<'a>>,
+}
+
+impl<'a> AttributeList<'a> {
+    // Parse `NAME=VALUE,NAME="quoted, with commas",...`
+    pub fn parse(source: &'a str) -> Result<AttributeList<'a>, ParseError> {
+        let bytes = source.as_bytes();
+        let mut attrs = Vec::new();
+        let mut pos = 0;
+        let err = |position, message| ParseError { position, message };
+
+        while pos < bytes.len() {
+            pos = skip_whitespace(bytes, pos);
+            if pos >= bytes.len() {
+                break;
+            }
+
+            // NAME
+            let name_start = pos;
+            while pos < bytes.len() && bytes[pos] != b'=' && bytes[pos] != b',' {
+                pos += 1;
+            }
+            if pos >= bytes.len() || bytes[pos] != b'=' {
+                return Err(err(name_start, "attribute without value"));
+            }
+            let name = source[name_start..pos].trim();
+            if name.is_empty() {
+                return Err(err(name_start, "empty attribute name"));
+            }
+            pos += 1;
+
+            // VALUE
+            let value_start = pos;
+            let quoted = bytes.get(pos) == Some(&b'"');
+            let value = if quoted {
+                let close = source[pos + 1..]
+                    .find('"')
+                    .ok_or_else(|| err(pos, "unterminated quoted string"))?;
+                pos += close + 2;
+                &source[value_start + 1..pos - 1]
+            } else {
+                // hex, decimal, float, resolution or enumerated - all end at the next comma
+                while pos < bytes.len() && bytes[pos] != b',' {
+                    pos += 1;
+                }
+                source[value_start..pos].trim_end()
+            };
+            let value_end = value_start + source[value_start..pos].trim_end().len();
+
+            attrs.push(Attribute {
+                name,
+                value,
+                quoted,
+                value_start,
+                value_end,
+            });
+
+            pos = skip_whitespace(bytes, pos);
+            match bytes.get(pos) {
+                Some(b',') => pos += 1,
+                None => break,
+                Some(_) => return Err(err(pos, "expected ',' between attributes")),
+            }
+        }
+
+        Ok(AttributeList { source, attrs })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Attribute<'a>> {
+        self.attrs.iter()
+    }
+
+    // Re-serialize, replacing the values `replace` returns Some for. New
+    // values are written as quoted strings; everything else, including
+    // separators and untouched values, is copied from the source unchanged.
+    pub fn rewrite<E>(
+        &self,
+        mut replace: impl FnMut(&Attribute<'a>) -> Result<Option<String>, E>,
+    ) -> Result<String, E> {
+        let mut out = String::with_capacity(self.source.len() + 64);
+        let mut copied = 0;
+
+        for attr in &self.attrs {
+            if let Some(new_value) = replace(attr)? {
+                out.push_str(&self.source[copied..attr.value_start]);
+                out.push('"');
+                out.push_str(&new_value);
+                out.push('"');
+                copied = attr.value_end;
+            }
+        }
+        out.push_str(&self.source[copied..]);
+        Ok(out)
+    }
+}
+
+fn skip_whitespace(bytes: &[u8], mut pos: usize) -> usize {
+    while pos < bytes.len() && (bytes[pos] == b' ' || bytes[pos] == b'\t') {
+        pos += 1;
+    }
+    pos
+}
+
+// Split "#EXT-X-KEY:METHOD=..." into ("#EXT-X-KEY", Some("METHOD=..."))
+pub fn split_tag(line: &str) -> (&str, Option<&str>) {
+    match line.find(':') {
+        Some(colon) => (&line[..colon], Some(&line[colon + 1..])),
+        None => (line, None),
+    }
+}
+
+// Attributes whose value is a URI the proxy has to rewrite. SERVER-URI is
+// the content steering server (#EXT-X-CONTENT-STEERING); URL isn't in the
+// spec, but some packagers use it in place of URI.
+pub fn is_uri_attribute(name: &str) -> bool {
+    matches!(name, "URI" | "URL" | "SERVER-URI" | "X-ASSET-URI" | "X-ASSET-LIST")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(source: &str) -> Vec<(&str, &str, bool)> {
+        AttributeList::parse(source)
+            .unwrap()
+            .iter()
+            .map(|a| (a.name, a.value, a.quoted))
+            .collect()
+    }
+
+    fn uppercase_uris(source: &str) -> String {
+        AttributeList::parse(source)
+            .unwrap()
+            .rewrite(|attr| -> Result<_, ()> {
+                Ok(is_uri_attribute(attr.name).then(|| attr.value.to_uppercase()))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_every_value_type() {
+        assert_eq!(
+            values(r#"BANDWIDTH=1280000,FRAME-RATE=29.970,RESOLUTION=1280x720,IV=0x1a2B,HDCP-LEVEL=TYPE-0,URI="a.m3u8""#),
+            vec![
+                ("BANDWIDTH", "1280000", false),
+                ("FRAME-RATE", "29.970", false),
+                ("RESOLUTION", "1280x720", false),
+                ("IV", "0x1a2B", false),
+                ("HDCP-LEVEL", "TYPE-0", false),
+                ("URI", "a.m3u8", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn quoted_values_keep_their_commas() {
+        assert_eq!(
+            values(r#"CODECS="avc1.64001f,mp4a.40.2",BANDWIDTH=1"#),
+            vec![("CODECS", "avc1.64001f,mp4a.40.2", true), ("BANDWIDTH", "1", false)]
+        );
+    }
+
+    #[test]
+    fn tolerates_whitespace_around_separators() {
+        assert_eq!(
+            values(r#" METHOD=AES-128 , URI="k.bin" "#),
+            vec![("METHOD", "AES-128", false), ("URI", "k.bin", true)]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lists() {
+        let error = |s| AttributeList::parse(s).unwrap_err().message;
+        assert_eq!(error(r#"URI="a.m3u8"#), "unterminated quoted string");
+        assert_eq!(error("METHOD"), "attribute without value");
+        assert_eq!(error("=1"), "empty attribute name");
+        assert_eq!(error(r#"URI="a"B=1"#), "expected ',' between attributes");
+    }
+
+    #[test]
+    fn rewrites_only_uri_attributes() {
+        assert_eq!(
+            uppercase_uris(r#"URI="init.mp4",BYTERANGE="720@0""#),
+            r#"URI="INIT.MP4",BYTERANGE="720@0""#
+        );
+        assert_eq!(
+            uppercase_uris(r#"SERVER-URI="steer.json",PATHWAY-ID="cdn-a""#),
+            r#"SERVER-URI="STEER.JSON",PATHWAY-ID="cdn-a""#
+        );
+    }
+
+    #[test]
+    fn untouched_bytes_are_copied_as_they_were() {
+        let source = r#"METHOD=AES-128 ,  URI="k.bin",IV=0x00FF ,KEYFORMAT="identity""#;
+        assert_eq!(uppercase_uris(source), r#"METHOD=AES-128 ,  URI="K.BIN",IV=0x00FF ,KEYFORMAT="identity""#);
+
+        let unchanged = AttributeList::parse(source)
+            .unwrap()
+            .rewrite(|_| -> Result<Option<String>, ()> { Ok(None) })
+            .unwrap();
+        assert_eq!(unchanged, source);
+    }
+
+    #[test]
+    fn unquoted_uri_is_written_back_quoted() {
+        assert_eq!(uppercase_uris("URI=a.m3u8,X=1"), r#"URI="A.M3U8",X=1"#);
+    }
+
+    #[test]
+    fn splits_tag_name_from_value() {
+        assert_eq!(split_tag("#EXT-X-KEY:METHOD=NONE"), ("#EXT-X-KEY", Some("METHOD=NONE")));
+        assert_eq!(split_tag("#EXT-X-ENDLIST"), ("#EXT-X-ENDLIST", None));
+    }
+}
@@ -0,0 +1,337 @@
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+use super::playlist::{Line, MediaPlaylist, Segment, Tag};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeltaMode {
+    // Delta updates (#EXT-X-SKIP) go to the client as-is, with URIs rewritten
+    #[default]
+    Passthrough,
+    // The proxy requests deltas from the upstream itself and fills the skipped
+    // segments in from its last full copy, so clients always get complete playlists
+    Expand,
+}
+
+// [delta_playlists] section of the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeltaConfig {
+    pub mode: DeltaMode,
+}
+
+impl DeltaConfig {
+    pub fn expand(&self) -> bool {
+        self.mode == DeltaMode::Expand
+    }
+}
+
+// Full copies kept for expansion; the oldest is dropped beyond this
+const MAX_ENTRIES: usize = 1_000;
+
+// A copy not refreshed within this long is no use for expansion anymore
+const MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+struct Entry {
+    playlist: MediaPlaylist,
+    // Seconds from the end the upstream is willing to skip, from CAN-SKIP-UNTIL
+    can_skip_until: f64,
+    stored: Instant,
+}
+
+// Last full upstream version of each live playlist, by cache_key
+static LAST_FULL: Lazy<Mutex<HashMap<String, Entry>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Upstream playlist URL without the LL-HLS delivery directives
+pub fn cache_key(url: &Url) -> String {
+    playlist_url(url).into()
+}
+
+pub fn playlist_url(url: &Url) -> Url {
+    without_directives(url, |_| true)
+}
+
+pub fn without_skip(url: &Url) -> Url {
+    without_directives(url, |name| name == "_HLS_skip")
+}
+
+fn without_directives(url: &Url, drop: impl Fn(&str) -> bool) -> Url {
+    let mut stripped = url.clone();
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| !(k.starts_with("_HLS_") && drop(k)))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    if kept.is_empty() {
+        stripped.set_query(None);
+    } else {
+        stripped.query_pairs_mut().clear().extend_pairs(kept);
+    }
+    stripped
+}
+
+// Whether the next upstream request for this playlist can ask for a delta:
+// we hold a recent enough full copy and the upstream advertised skipping
+pub fn should_request_delta(key: &str) -> bool {
+    let cache = LAST_FULL.lock().unwrap();
+    cache.get(key).is_some_and(|entry| {
+        let max_age = Duration::from_secs_f64(entry.can_skip_until / 2.0).min(MAX_AGE);
+        entry.stored.elapsed() < max_age
+    })
+}
+
+// Remember a full (or already expanded) playlist for expanding later deltas
+pub fn store(key: &str, playlist: &MediaPlaylist) {
+    let can_skip_until = playlist
+        .server_control()
+        .and_then(|a| a.get_f64("CAN-SKIP-UNTIL"))
+        .unwrap_or(0.0);
+    let mut cache = LAST_FULL.lock().unwrap();
+    if can_skip_until <= 0.0 || playlist.has_end_list() {
+        cache.remove(key);
+        return;
+    }
+
+    if cache.len() >= MAX_ENTRIES && !cache.contains_key(key) {
+        let oldest = cache
+            .iter()
+            .min_by_key(|(_, e)| e.stored)
+            .map(|(k, _)| k.clone());
+        if let Some(oldest) = oldest {
+            cache.remove(&oldest);
+        }
+    }
+    cache.insert(
+        key.to_string(),
+        Entry {
+            playlist: playlist.clone(),
+            can_skip_until,
+            stored: Instant::now(),
+        },
+    );
+}
+
+// Replace the EXT-X-SKIP of a delta update with the segments it stands for.
+// Returns false, leaving the playlist untouched, when our copy doesn't cover them.
+pub fn expand(key: &str, delta: &mut MediaPlaylist) -> bool {
+    let Some(skip) = delta.skip() else {
+        return true;
+    };
+    let Some(skipped) = skip.get_u64("SKIPPED-SEGMENTS") else {
+        return false;
+    };
+    let removed_dateranges: Vec<String> = skip
+        .get("RECENTLY-REMOVED-DATERANGES")
+        .map(|v| v.split('\t').map(str::to_string).collect())
+        .unwrap_or_default();
+
+    let cache = LAST_FULL.lock().unwrap();
+    let Some(entry) = cache.get(key) else {
+        return false;
+    };
+    let previous = &entry.playlist;
+
+    // Skipped segments are the first `skipped` ones from the delta's media sequence on
+    let Some(start) = delta.media_sequence().checked_sub(previous.media_sequence()) else {
+        return false;
+    };
+    let (start, end) = (start as usize, start as usize + skipped as usize);
+    if end > previous.segments.len() {
+        return false;
+    }
+
+    let mut restored: Vec<Segment> = previous.segments[start..end].to_vec();
+    for segment in &mut restored {
+        segment.tags.retain(|line| match line {
+            Line::Tag(Tag::DateRange(a)) => !a
+                .get("ID")
+                .is_some_and(|id| removed_dateranges.iter().any(|r| r == id)),
+            _ => true,
+        });
+    }
+
+    // Keys and init sections carry over from earlier segments; the first restored
+    // segment has to repeat whichever were in effect at that point
+    if let Some(first) = restored.first_mut() {
+        let earlier = &previous.segments[..start];
+        let inherited = |pick: fn(&Tag) -> bool| -> Option<Line> {
+            if first.tags.iter().filter_map(Line::tag).any(pick) {
+                return None;
+            }
+            earlier
+                .iter()
+                .rev()
+                .flat_map(|s| s.tags.iter().rev())
+                .find(|l| l.tag().is_some_and(pick))
+                .cloned()
+        };
+        let key_tag = inherited(|t| matches!(t, Tag::Key(_)));
+        let map_tag = inherited(|t| matches!(t, Tag::Map(_)));
+        for line in [map_tag, key_tag].into_iter().flatten() {
+            first.tags.insert(0, line);
+        }
+    }
+    drop(cache);
+
+    delta.header.retain(|l| !matches!(l, Line::Tag(Tag::Skip(_))));
+    delta.segments.splice(0..0, restored);
+    true
+}
+
+// Drop CAN-SKIP-UNTIL from what clients see, so they never ask for deltas
+// themselves once the proxy handles them
+pub fn hide_skip_support(playlist: &mut MediaPlaylist) {
+    playlist.header.retain_mut(|line| match line {
+        Line::Tag(Tag::ServerControl(attrs)) => {
+            attrs.remove("CAN-SKIP-UNTIL");
+            attrs.remove("CAN-SKIP-DATERANGES");
+            attrs.iter().next().is_some()
+        }
+        _ => true,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hls::playlist::Playlist;
+
+    const FULL: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,CAN-SKIP-UNTIL=24.0,CAN-SKIP-DATERANGES=YES
+#EXT-X-MEDIA-SEQUENCE:10
+#EXT-X-MAP:URI="init.mp4"
+#EXT-X-KEY:METHOD=AES-128,URI="key1.bin"
+#EXTINF:4.0,
+seg10.mp4
+#EXT-X-DATERANGE:ID="gone",START-DATE="2024-01-01T00:00:04Z"
+#EXTINF:4.0,
+seg11.mp4
+#EXT-X-DATERANGE:ID="kept",START-DATE="2024-01-01T00:00:08Z"
+#EXTINF:4.0,
+seg12.mp4
+#EXTINF:4.0,
+seg13.mp4"#;
+
+    const DELTA: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,CAN-SKIP-UNTIL=24.0,CAN-SKIP-DATERANGES=YES
+#EXT-X-MEDIA-SEQUENCE:11
+#EXT-X-SKIP:SKIPPED-SEGMENTS=2,RECENTLY-REMOVED-DATERANGES="gone"
+#EXTINF:4.0,
+seg13.mp4
+#EXTINF:4.0,
+seg14.mp4"#;
+
+    fn media(text: &str) -> MediaPlaylist {
+        match Playlist::parse(text) {
+            Playlist::Media(media) => media,
+            Playlist::Master(_) => panic!("parsed as a master playlist"),
+        }
+    }
+
+    fn uris(playlist: &MediaPlaylist) -> Vec<&str> {
+        playlist.segments.iter().map(|s| s.uri.as_str()).collect()
+    }
+
+    #[test]
+    fn expands_a_delta_from_the_stored_copy() {
+        let key = "test://delta/expands";
+        store(key, &media(FULL));
+        assert!(should_request_delta(key));
+
+        let mut delta = media(DELTA);
+        assert!(expand(key, &mut delta));
+        assert_eq!(delta.skip(), None);
+        assert_eq!(delta.media_sequence(), 11);
+        assert_eq!(uris(&delta), vec!["seg11.mp4", "seg12.mp4", "seg13.mp4", "seg14.mp4"]);
+
+        // The key and init section in effect at seg11 are repeated on it
+        let first = &delta.segments[0];
+        assert_eq!(first.key().and_then(|k| k.get("URI")), Some("key1.bin"));
+        assert_eq!(first.map().and_then(|m| m.get("URI")), Some("init.mp4"));
+        // ...and the date range the delta reported as removed is gone
+        assert!(!delta.to_string().contains(r#"ID="gone""#));
+        assert!(delta.to_string().contains(r#"ID="kept""#));
+    }
+
+    #[test]
+    fn expands_playlists_with_vendor_tags_before_the_media_sequence() {
+        let key = "test://delta/vendor-tags";
+        let vendor = |text: &str| text.replace("#EXT-X-MEDIA-SEQUENCE", "#EXT-X-VENDOR-BUILD:7\n#EXT-X-MEDIA-SEQUENCE");
+        let full = media(&vendor(FULL));
+        assert_eq!(full.media_sequence(), 10);
+        store(key, &full);
+
+        let mut delta = media(&vendor(DELTA));
+        assert_eq!(delta.media_sequence(), 11);
+        assert!(expand(key, &mut delta));
+        assert_eq!(uris(&delta), vec!["seg11.mp4", "seg12.mp4", "seg13.mp4", "seg14.mp4"]);
+        assert!(delta.to_string().contains("#EXT-X-VENDOR-BUILD:7\n#EXT-X-MEDIA-SEQUENCE:11\n"));
+    }
+
+    #[test]
+    fn refuses_to_expand_what_the_copy_does_not_cover() {
+        let key = "test://delta/uncovered";
+        assert!(!expand(key, &mut media(DELTA)));
+
+        store(key, &media(FULL));
+        // Starts before the stored copy
+        let mut early = media(&DELTA.replace("MEDIA-SEQUENCE:11", "MEDIA-SEQUENCE:9"));
+        assert!(!expand(key, &mut early));
+        // Skips past its end
+        let mut late = media(&DELTA.replace("SKIPPED-SEGMENTS=2", "SKIPPED-SEGMENTS=4"));
+        assert!(!expand(key, &mut late));
+        assert!(late.skip().is_some());
+        assert_eq!(uris(&late), vec!["seg13.mp4", "seg14.mp4"]);
+    }
+
+    #[test]
+    fn full_playlists_pass_through_expand() {
+        let mut full = media(FULL);
+        assert!(expand("test://delta/full", &mut full));
+        assert_eq!(full.to_string(), FULL);
+    }
+
+    #[test]
+    fn ended_or_unskippable_playlists_are_not_stored() {
+        let key = "test://delta/not-stored";
+        store(key, &media(FULL));
+        store(key, &media(&format!("{}\n#EXT-X-ENDLIST", FULL)));
+        assert!(!should_request_delta(key));
+
+        store(key, &media(&FULL.replace("CAN-SKIP-UNTIL=24.0,", "")));
+        assert!(!should_request_delta(key));
+    }
+
+    #[test]
+    fn hides_skip_support_from_clients() {
+        let mut playlist = media(FULL);
+        hide_skip_support(&mut playlist);
+        let control = playlist.server_control().unwrap();
+        assert_eq!(control.to_string(), "CAN-BLOCK-RELOAD=YES");
+
+        let mut only_skip = media(&FULL.replace("CAN-BLOCK-RELOAD=YES,", ""));
+        hide_skip_support(&mut only_skip);
+        assert!(only_skip.server_control().is_none());
+        assert!(!only_skip.to_string().contains("EXT-X-SERVER-CONTROL"));
+    }
+
+    #[test]
+    fn strips_delivery_directives() {
+        let url = Url::parse("https://cdn.example/live.m3u8?token=a&_HLS_msn=12&_HLS_part=1&_HLS_skip=YES").unwrap();
+        assert_eq!(without_skip(&url).as_str(), "https://cdn.example/live.m3u8?token=a&_HLS_msn=12&_HLS_part=1");
+        assert_eq!(playlist_url(&url).as_str(), "https://cdn.example/live.m3u8?token=a");
+        assert_eq!(cache_key(&url), "https://cdn.example/live.m3u8?token=a");
+
+        let bare = Url::parse("https://cdn.example/live.m3u8?_HLS_skip=v2").unwrap();
+        assert_eq!(without_skip(&bare).as_str(), "https://cdn.example/live.m3u8");
+    }
+}
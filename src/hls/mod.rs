@@ -0,0 +1,10 @@
+// HLS (RFC 8216) playlist parsing helpers
+
+pub mod ads;
+pub mod attributes;
+pub mod delta;
+pub mod playlist;
+pub mod renditions;
+pub mod stream;
+pub mod subtitles;
+pub mod variants;
@@ -0,0 +1,1150 @@
+// Typed M3U8 model. Master and media playlists parse into tags and
+// segments/variants; serializing writes every untouched line back as it was.
+
+use std::fmt::{self, Write as _};
+
+use super::attributes::{is_uri_attribute, split_tag, AttributeList};
+
+// Owned attribute list. Keeps the source text so untouched tags serialize
+// byte-for-byte; any structural edit falls back to canonical NAME=VALUE form.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attributes {
+    list: Vec<Attribute>,
+    raw: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: String,
+    pub value: String,
+    pub quoted: bool,
+}
+
+impl Attributes {
+    pub fn parse(source: &str) -> Option<Attributes> {
+        let parsed = AttributeList::parse(source).ok()?;
+        let list = parsed
+            .iter()
+            .map(|a| Attribute {
+                name: a.name.to_string(),
+                value: a.value.to_string(),
+                quoted: a.quoted,
+            })
+            .collect();
+        Some(Attributes {
+            list,
+            raw: Some(source.to_string()),
+        })
+    }
+
+    pub fn new() -> Attributes {
+        Attributes {
+            list: Vec::new(),
+            raw: None,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.list
+            .iter()
+            .find(|a| a.name == name)
+            .map(|a| a.value.as_str())
+    }
+
+    // decimal-integer
+    pub fn get_u64(&self, name: &str) -> Option<u64> {
+        self.get(name)?.parse().ok()
+    }
+
+    // decimal-floating-point / signed-decimal-floating-point
+    pub fn get_f64(&self, name: &str) -> Option<f64> {
+        self.get(name)?.parse().ok()
+    }
+
+    // decimal-resolution, e.g. 1920x1080
+    pub fn get_resolution(&self, name: &str) -> Option<(u64, u64)> {
+        let (w, h) = self.get(name)?.split_once('x')?;
+        Some((w.parse().ok()?, h.parse().ok()?))
+    }
+
+    // Comma-separated quoted list, e.g. CODECS="avc1.64001f,mp4a.40.2"
+    pub fn get_list(&self, name: &str) -> Vec<&str> {
+        self.get(name)
+            .map(|v| v.split(',').map(str::trim).filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Attribute> {
+        self.list.iter()
+    }
+
+    pub fn set(&mut self, name: &str, value: impl Into<String>, quoted: bool) {
+        let value = value.into();
+        match self.list.iter_mut().find(|a| a.name == name) {
+            Some(attr) => {
+                attr.value = value;
+                attr.quoted = quoted;
+            }
+            None => self.list.push(Attribute {
+                name: name.to_string(),
+                value,
+                quoted,
+            }),
+        }
+        self.raw = None;
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let index = self.list.iter().position(|a| a.name == name)?;
+        self.raw = None;
+        Some(self.list.remove(index).value)
+    }
+
+    // Replace URI-bearing values, keeping every other byte of the source intact
+    pub fn try_rewrite_uris<E>(
+        &mut self,
+        mut f: impl FnMut(&str) -> Result<Option<String>, E>,
+    ) -> Result<(), E> {
+        let mut replaced = Vec::new();
+        for (index, attr) in self.list.iter().enumerate() {
+            if is_uri_attribute(&attr.name) {
+                if let Some(new_value) = f(&attr.value)? {
+                    replaced.push((index, new_value));
+                }
+            }
+        }
+        if replaced.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(raw) = &self.raw {
+            let mut pending = replaced.iter();
+            let mut next = pending.next();
+            let mut position = 0;
+            let rewritten = AttributeList::parse(raw).ok().and_then(|parsed| {
+                parsed
+                    .rewrite(|_| -> Result<Option<String>, ()> {
+                        let value = match next {
+                            Some((index, value)) if *index == position => {
+                                next = pending.next();
+                                Some(value.clone())
+                            }
+                            _ => None,
+                        };
+                        position += 1;
+                        Ok(value)
+                    })
+                    .ok()
+            });
+            self.raw = rewritten;
+        }
+
+        for (index, value) in replaced {
+            self.list[index].value = value;
+            self.list[index].quoted = true;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Attributes {
+    fn default() -> Self {
+        Attributes::new()
+    }
+}
+
+impl fmt::Display for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(raw) = &self.raw {
+            return f.write_str(raw);
+        }
+        for (i, attr) in self.list.iter().enumerate() {
+            if i > 0 {
+                f.write_char(',')?;
+            }
+            if attr.quoted {
+                write!(f, "{}=\"{}\"", attr.name, attr.value)?;
+            } else {
+                write!(f, "{}={}", attr.name, attr.value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub length: u64,
+    pub offset: Option<u64>,
+}
+
+impl ByteRange {
+    fn parse(value: &str) -> Option<ByteRange> {
+        let (length, offset) = match value.split_once('@') {
+            Some((l, o)) => (l, Some(o.trim().parse().ok()?)),
+            None => (value, None),
+        };
+        Some(ByteRange {
+            length: length.trim().parse().ok()?,
+            offset,
+        })
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{}@{}", self.length, offset),
+            None => write!(f, "{}", self.length),
+        }
+    }
+}
+
+// #EXTINF:<duration>,[<title>]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inf {
+    pub duration: f64,
+    // Duration as written, so "4.000" doesn't come back as "4"
+    duration_text: String,
+    pub title: String,
+}
+
+impl Inf {
+    pub fn new(duration: f64, title: impl Into<String>) -> Inf {
+        Inf {
+            duration,
+            duration_text: format!("{:.3}", duration),
+            title: title.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    ExtM3u,
+    Version(u64),
+    IndependentSegments,
+    Start(Attributes),
+    Define(Attributes),
+
+    // Master playlist tags
+    StreamInf(Attributes),
+    IFrameStreamInf(Attributes),
+    Media(Attributes),
+    SessionData(Attributes),
+    SessionKey(Attributes),
+    ContentSteering(Attributes),
+
+    // Media playlist tags
+    TargetDuration(u64),
+    MediaSequence(u64),
+    DiscontinuitySequence(u64),
+    PlaylistType(String),
+    EndList,
+    IFramesOnly,
+    ServerControl(Attributes),
+    PartInf(Attributes),
+    Skip(Attributes),
+
+    // Media segment tags
+    Inf(Inf),
+    ByteRange(ByteRange),
+    Discontinuity,
+    Key(Attributes),
+    Map(Attributes),
+    ProgramDateTime(String),
+    DateRange(Attributes),
+    Gap,
+    Bitrate(u64),
+    Part(Attributes),
+    PreloadHint(Attributes),
+    RenditionReport(Attributes),
+
+    // Anything unrecognised (or malformed), kept verbatim
+    Unknown(String),
+}
+
+impl Tag {
+    fn parse(line: &str) -> Tag {
+        let (name, value) = split_tag(line);
+        Tag::parse_parts(name, value).unwrap_or_else(|| Tag::Unknown(line.to_string()))
+    }
+
+    fn parse_parts(name: &str, value: Option<&str>) -> Option<Tag> {
+        let attrs = || value.and_then(Attributes::parse);
+        let int = || value.and_then(|v| v.trim().parse::<u64>().ok());
+
+        let tag = match (name, value) {
+            ("#EXTM3U", None) => Tag::ExtM3u,
+            ("#EXT-X-VERSION", _) => Tag::Version(int()?),
+            ("#EXT-X-INDEPENDENT-SEGMENTS", None) => Tag::IndependentSegments,
+            ("#EXT-X-START", _) => Tag::Start(attrs()?),
+            ("#EXT-X-DEFINE", _) => Tag::Define(attrs()?),
+
+            ("#EXT-X-STREAM-INF", _) => Tag::StreamInf(attrs()?),
+            ("#EXT-X-I-FRAME-STREAM-INF", _) => Tag::IFrameStreamInf(attrs()?),
+            ("#EXT-X-MEDIA", _) => Tag::Media(attrs()?),
+            ("#EXT-X-SESSION-DATA", _) => Tag::SessionData(attrs()?),
+            ("#EXT-X-SESSION-KEY", _) => Tag::SessionKey(attrs()?),
+            ("#EXT-X-CONTENT-STEERING", _) => Tag::ContentSteering(attrs()?),
+
+            ("#EXT-X-TARGETDURATION", _) => Tag::TargetDuration(int()?),
+            ("#EXT-X-MEDIA-SEQUENCE", _) => Tag::MediaSequence(int()?),
+            ("#EXT-X-DISCONTINUITY-SEQUENCE", _) => Tag::DiscontinuitySequence(int()?),
+            ("#EXT-X-PLAYLIST-TYPE", Some(v)) => Tag::PlaylistType(v.trim().to_string()),
+            ("#EXT-X-ENDLIST", None) => Tag::EndList,
+            ("#EXT-X-I-FRAMES-ONLY", None) => Tag::IFramesOnly,
+            ("#EXT-X-SERVER-CONTROL", _) => Tag::ServerControl(attrs()?),
+            ("#EXT-X-PART-INF", _) => Tag::PartInf(attrs()?),
+            ("#EXT-X-SKIP", _) => Tag::Skip(attrs()?),
+
+            ("#EXTINF", Some(v)) => {
+                let (duration_text, title) = v.split_once(',').unwrap_or((v, ""));
+                Tag::Inf(Inf {
+                    duration: duration_text.trim().parse().ok()?,
+                    duration_text: duration_text.to_string(),
+                    title: title.to_string(),
+                })
+            }
+            ("#EXT-X-BYTERANGE", Some(v)) => Tag::ByteRange(ByteRange::parse(v)?),
+            ("#EXT-X-DISCONTINUITY", None) => Tag::Discontinuity,
+            ("#EXT-X-KEY", _) => Tag::Key(attrs()?),
+            ("#EXT-X-MAP", _) => Tag::Map(attrs()?),
+            ("#EXT-X-PROGRAM-DATE-TIME", Some(v)) => Tag::ProgramDateTime(v.to_string()),
+            ("#EXT-X-DATERANGE", _) => Tag::DateRange(attrs()?),
+            ("#EXT-X-GAP", None) => Tag::Gap,
+            ("#EXT-X-BITRATE", _) => Tag::Bitrate(int()?),
+            ("#EXT-X-PART", _) => Tag::Part(attrs()?),
+            ("#EXT-X-PRELOAD-HINT", _) => Tag::PreloadHint(attrs()?),
+            ("#EXT-X-RENDITION-REPORT", _) => Tag::RenditionReport(attrs()?),
+
+            _ => return None,
+        };
+        Some(tag)
+    }
+
+    pub fn attributes_mut(&mut self) -> Option<&mut Attributes> {
+        match self {
+            Tag::Start(a)
+            | Tag::Define(a)
+            | Tag::StreamInf(a)
+            | Tag::IFrameStreamInf(a)
+            | Tag::Media(a)
+            | Tag::SessionData(a)
+            | Tag::SessionKey(a)
+            | Tag::ContentSteering(a)
+            | Tag::ServerControl(a)
+            | Tag::PartInf(a)
+            | Tag::Skip(a)
+            | Tag::Key(a)
+            | Tag::Map(a)
+            | Tag::DateRange(a)
+            | Tag::Part(a)
+            | Tag::PreloadHint(a)
+            | Tag::RenditionReport(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    // Tags that describe the whole media playlist rather than one segment
+    fn is_playlist_level(&self) -> bool {
+        matches!(
+            self,
+            Tag::ExtM3u
+                | Tag::Version(_)
+                | Tag::IndependentSegments
+                | Tag::Start(_)
+                | Tag::Define(_)
+                | Tag::TargetDuration(_)
+                | Tag::MediaSequence(_)
+                | Tag::DiscontinuitySequence(_)
+                | Tag::PlaylistType(_)
+                | Tag::IFramesOnly
+                | Tag::ServerControl(_)
+                | Tag::PartInf(_)
+                | Tag::Skip(_)
+        )
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tag::ExtM3u => f.write_str("#EXTM3U"),
+            Tag::Version(v) => write!(f, "#EXT-X-VERSION:{}", v),
+            Tag::IndependentSegments => f.write_str("#EXT-X-INDEPENDENT-SEGMENTS"),
+            Tag::Start(a) => write!(f, "#EXT-X-START:{}", a),
+            Tag::Define(a) => write!(f, "#EXT-X-DEFINE:{}", a),
+            Tag::StreamInf(a) => write!(f, "#EXT-X-STREAM-INF:{}", a),
+            Tag::IFrameStreamInf(a) => write!(f, "#EXT-X-I-FRAME-STREAM-INF:{}", a),
+            Tag::Media(a) => write!(f, "#EXT-X-MEDIA:{}", a),
+            Tag::SessionData(a) => write!(f, "#EXT-X-SESSION-DATA:{}", a),
+            Tag::SessionKey(a) => write!(f, "#EXT-X-SESSION-KEY:{}", a),
+            Tag::ContentSteering(a) => write!(f, "#EXT-X-CONTENT-STEERING:{}", a),
+            Tag::TargetDuration(v) => write!(f, "#EXT-X-TARGETDURATION:{}", v),
+            Tag::MediaSequence(v) => write!(f, "#EXT-X-MEDIA-SEQUENCE:{}", v),
+            Tag::DiscontinuitySequence(v) => write!(f, "#EXT-X-DISCONTINUITY-SEQUENCE:{}", v),
+            Tag::PlaylistType(v) => write!(f, "#EXT-X-PLAYLIST-TYPE:{}", v),
+            Tag::EndList => f.write_str("#EXT-X-ENDLIST"),
+            Tag::IFramesOnly => f.write_str("#EXT-X-I-FRAMES-ONLY"),
+            Tag::ServerControl(a) => write!(f, "#EXT-X-SERVER-CONTROL:{}", a),
+            Tag::PartInf(a) => write!(f, "#EXT-X-PART-INF:{}", a),
+            Tag::Skip(a) => write!(f, "#EXT-X-SKIP:{}", a),
+            Tag::Inf(inf) => write!(f, "#EXTINF:{},{}", inf.duration_text, inf.title),
+            Tag::ByteRange(r) => write!(f, "#EXT-X-BYTERANGE:{}", r),
+            Tag::Discontinuity => f.write_str("#EXT-X-DISCONTINUITY"),
+            Tag::Key(a) => write!(f, "#EXT-X-KEY:{}", a),
+            Tag::Map(a) => write!(f, "#EXT-X-MAP:{}", a),
+            Tag::ProgramDateTime(v) => write!(f, "#EXT-X-PROGRAM-DATE-TIME:{}", v),
+            Tag::DateRange(a) => write!(f, "#EXT-X-DATERANGE:{}", a),
+            Tag::Gap => f.write_str("#EXT-X-GAP"),
+            Tag::Bitrate(v) => write!(f, "#EXT-X-BITRATE:{}", v),
+            Tag::Part(a) => write!(f, "#EXT-X-PART:{}", a),
+            Tag::PreloadHint(a) => write!(f, "#EXT-X-PRELOAD-HINT:{}", a),
+            Tag::RenditionReport(a) => write!(f, "#EXT-X-RENDITION-REPORT:{}", a),
+            Tag::Unknown(raw) => f.write_str(raw),
+        }
+    }
+}
+
+// One line of a playlist
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Blank,
+    Comment(String),
+    Tag(Tag),
+    Uri(String),
+}
+
+impl Line {
+    pub fn parse(line: &str) -> Line {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            Line::Blank
+        } else if line.starts_with("#EXT") {
+            Line::Tag(Tag::parse(line))
+        } else if line.starts_with('#') {
+            Line::Comment(line.to_string())
+        } else {
+            Line::Uri(line.trim().to_string())
+        }
+    }
+
+    pub fn tag(&self) -> Option<&Tag> {
+        match self {
+            Line::Tag(tag) => Some(tag),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Blank => Ok(()),
+            Line::Comment(c) => f.write_str(c),
+            Line::Tag(tag) => tag.fmt(f),
+            Line::Uri(uri) => f.write_str(uri),
+        }
+    }
+}
+
+// #EXT-X-STREAM-INF plus the variant playlist URI that follows it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub stream_inf: Attributes,
+    // Lines between the tag and the URI (rare, but kept in place)
+    pub extra: Vec<Line>,
+    pub uri: String,
+}
+
+impl Variant {
+    pub fn bandwidth(&self) -> Option<u64> {
+        self.stream_inf.get_u64("BANDWIDTH")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MasterEntry {
+    Variant(Variant),
+    Line(Line),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MasterPlaylist {
+    pub entries: Vec<MasterEntry>,
+}
+
+impl MasterPlaylist {
+    pub fn variants(&self) -> impl Iterator<Item = &Variant> {
+        self.entries.iter().filter_map(|e| match e {
+            MasterEntry::Variant(v) => Some(v),
+            _ => None,
+        })
+    }
+}
+
+// Tags leading up to a segment URI, and the URI itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub tags: Vec<Line>,
+    pub uri: String,
+}
+
+impl Segment {
+    fn find<T>(&self, f: impl Fn(&Tag) -> Option<T>) -> Option<T> {
+        self.tags.iter().filter_map(Line::tag).find_map(f)
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.find(|t| match t {
+            Tag::Inf(inf) => Some(inf.duration),
+            _ => None,
+        })
+        .unwrap_or(0.0)
+    }
+
+    pub fn byte_range(&self) -> Option<ByteRange> {
+        self.find(|t| match t {
+            Tag::ByteRange(r) => Some(*r),
+            _ => None,
+        })
+    }
+
+    pub fn discontinuity(&self) -> bool {
+        self.find(|t| matches!(t, Tag::Discontinuity).then_some(())).is_some()
+    }
+
+    // #EXT-X-KEY set right before this segment (keys carry over to later segments)
+    pub fn key(&self) -> Option<&Attributes> {
+        self.tags.iter().filter_map(Line::tag).find_map(|t| match t {
+            Tag::Key(a) => Some(a),
+            _ => None,
+        })
+    }
+
+    pub fn map(&self) -> Option<&Attributes> {
+        self.tags.iter().filter_map(Line::tag).find_map(|t| match t {
+            Tag::Map(a) => Some(a),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MediaPlaylist {
+    // Lines up to the last playlist-level tag before the first segment
+    pub header: Vec<Line>,
+    pub segments: Vec<Segment>,
+    // Lines after the last segment URI: ENDLIST, preload hints, rendition
+    // reports and parts of a segment still being written
+    pub trailer: Vec<Line>,
+}
+
+impl MediaPlaylist {
+    fn header_tag<T>(&self, f: impl Fn(&Tag) -> Option<T>) -> Option<T> {
+        self.header.iter().filter_map(Line::tag).find_map(f)
+    }
+
+    pub fn target_duration(&self) -> Option<u64> {
+        self.header_tag(|t| match t {
+            Tag::TargetDuration(v) => Some(*v),
+            _ => None,
+        })
+    }
+
+    // PART-TARGET of EXT-X-PART-INF, for low-latency playlists
+    pub fn part_target(&self) -> Option<f64> {
+        self.header_tag(|t| match t {
+            Tag::PartInf(a) => a.get_f64("PART-TARGET"),
+            _ => None,
+        })
+    }
+
+    pub fn media_sequence(&self) -> u64 {
+        self.header_tag(|t| match t {
+            Tag::MediaSequence(v) => Some(*v),
+            _ => None,
+        })
+        .unwrap_or(0)
+    }
+
+    pub fn discontinuity_sequence(&self) -> u64 {
+        self.header_tag(|t| match t {
+            Tag::DiscontinuitySequence(v) => Some(*v),
+            _ => None,
+        })
+        .unwrap_or(0)
+    }
+
+    pub fn playlist_type(&self) -> Option<&str> {
+        self.header.iter().filter_map(Line::tag).find_map(|t| match t {
+            Tag::PlaylistType(v) => Some(v.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn has_end_list(&self) -> bool {
+        self.trailer
+            .iter()
+            .chain(self.header.iter())
+            .any(|l| matches!(l, Line::Tag(Tag::EndList)))
+    }
+
+    pub fn set_media_sequence(&mut self, value: u64) {
+        self.set_header_tag(Tag::MediaSequence(value), |t| matches!(t, Tag::MediaSequence(_)));
+    }
+
+    pub fn set_discontinuity_sequence(&mut self, value: u64) {
+        self.set_header_tag(Tag::DiscontinuitySequence(value), |t| {
+            matches!(t, Tag::DiscontinuitySequence(_))
+        });
+    }
+
+    // Replace a header tag in place, or add it at the end of the header
+    fn set_header_tag(&mut self, tag: Tag, is_same: impl Fn(&Tag) -> bool) {
+        match self.header.iter_mut().find(|l| l.tag().is_some_and(&is_same)) {
+            Some(line) => *line = Line::Tag(tag),
+            None => self.header.push(Line::Tag(tag)),
+        }
+    }
+
+    pub fn server_control(&self) -> Option<&Attributes> {
+        self.header.iter().filter_map(Line::tag).find_map(|t| match t {
+            Tag::ServerControl(a) => Some(a),
+            _ => None,
+        })
+    }
+
+    // EXT-X-SKIP of a delta update
+    pub fn skip(&self) -> Option<&Attributes> {
+        self.header.iter().filter_map(Line::tag).find_map(|t| match t {
+            Tag::Skip(a) => Some(a),
+            _ => None,
+        })
+    }
+
+    pub fn total_duration(&self) -> f64 {
+        self.segments.iter().map(Segment::duration).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Playlist {
+    Master(MasterPlaylist),
+    Media(MediaPlaylist),
+}
+
+impl Playlist {
+    // Lenient parse: malformed tags are kept verbatim as Tag::Unknown
+    pub fn parse(text: &str) -> Playlist {
+        // Not allowed in playlists (RFC 8216 4.1), but some origins send one
+        let text = text.strip_prefix('\u{FEFF}').unwrap_or(text);
+        let lines: Vec<Line> = text.lines().map(Line::parse).collect();
+
+        let is_master = lines.iter().any(|l| {
+            matches!(
+                l,
+                Line::Tag(Tag::StreamInf(_) | Tag::IFrameStreamInf(_) | Tag::Media(_))
+            )
+        });
+
+        if is_master {
+            Playlist::Master(parse_master(lines))
+        } else {
+            Playlist::Media(parse_media(lines))
+        }
+    }
+
+    // Replace every URI in the playlist: segment and variant lines plus URI
+    // attributes of any tag. `f` gets the URI as written in the playlist.
+    pub fn try_rewrite_uris<E>(&mut self, mut f: impl FnMut(&str) -> Result<String, E>) -> Result<(), E> {
+        match self {
+            Playlist::Master(master) => {
+                for entry in &mut master.entries {
+                    match entry {
+                        MasterEntry::Line(line) => rewrite_line(line, &mut f)?,
+                        MasterEntry::Variant(variant) => {
+                            variant.stream_inf.try_rewrite_uris(|v| rewrite_uri(v, &mut f))?;
+                            for line in &mut variant.extra {
+                                rewrite_line(line, &mut f)?;
+                            }
+                            if let Some(uri) = rewrite_uri(&variant.uri, &mut f)? {
+                                variant.uri = uri;
+                            }
+                        }
+                    }
+                }
+            }
+            Playlist::Media(media) => {
+                for line in &mut media.header {
+                    rewrite_line(line, &mut f)?;
+                }
+                for segment in &mut media.segments {
+                    for line in &mut segment.tags {
+                        rewrite_line(line, &mut f)?;
+                    }
+                    if let Some(uri) = rewrite_uri(&segment.uri, &mut f)? {
+                        segment.uri = uri;
+                    }
+                }
+                for line in &mut media.trailer {
+                    rewrite_line(line, &mut f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether a URI as written resolves to an http(s) URL. Relative references
+// do; skd:// (FairPlay) and data: keys are for the player alone.
+fn is_http_uri(uri: &str) -> bool {
+    let scheme = uri.split_once(':').map(|(scheme, _)| scheme).filter(|scheme| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    });
+    scheme.is_none_or(|s| s.eq_ignore_ascii_case("http") || s.eq_ignore_ascii_case("https"))
+}
+
+// New value for a URI, or None to leave it as written
+fn rewrite_uri<E>(uri: &str, f: &mut impl FnMut(&str) -> Result<String, E>) -> Result<Option<String>, E> {
+    if is_http_uri(uri) {
+        f(uri).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+// Rewrite the URIs of one line, as try_rewrite_uris does for the whole playlist
+pub fn rewrite_line<E>(line: &mut Line, f: &mut impl FnMut(&str) -> Result<String, E>) -> Result<(), E> {
+    match line {
+        Line::Uri(uri) => {
+            if let Some(new_uri) = rewrite_uri(uri, f)? {
+                *uri = new_uri;
+            }
+        }
+        Line::Tag(Tag::Unknown(raw)) => {
+            // Vendor tags can carry URIs too (e.g. X-ASSET-LIST on interstitials)
+            let (name, Some(value)) = split_tag(raw) else {
+                return Ok(());
+            };
+            let Ok(list) = AttributeList::parse(value) else {
+                return Ok(());
+            };
+            if !list.iter().any(|a| is_uri_attribute(a.name)) {
+                return Ok(());
+            }
+            let rewritten = list.rewrite(|attr| {
+                if is_uri_attribute(attr.name) {
+                    rewrite_uri(attr.value, f)
+                } else {
+                    Ok(None)
+                }
+            })?;
+            *raw = format!("{}:{}", name, rewritten);
+        }
+        Line::Tag(tag) => {
+            if let Some(attrs) = tag.attributes_mut() {
+                attrs.try_rewrite_uris(|v| rewrite_uri(v, f))?;
+            }
+        }
+        Line::Blank | Line::Comment(_) => {}
+    }
+    Ok(())
+}
+
+fn parse_master(lines: Vec<Line>) -> MasterPlaylist {
+    let mut entries = Vec::with_capacity(lines.len());
+    let mut pending: Option<(Attributes, Vec<Line>)> = None;
+
+    for line in lines {
+        match (line, pending.take()) {
+            (Line::Uri(uri), Some((stream_inf, extra))) => {
+                entries.push(MasterEntry::Variant(Variant { stream_inf, extra, uri }));
+            }
+            (Line::Tag(Tag::StreamInf(attrs)), previous) => {
+                // A STREAM-INF without a URI is kept as a plain line
+                if let Some((attrs, extra)) = previous {
+                    entries.push(MasterEntry::Line(Line::Tag(Tag::StreamInf(attrs))));
+                    entries.extend(extra.into_iter().map(MasterEntry::Line));
+                }
+                pending = Some((attrs, Vec::new()));
+            }
+            (line, Some((attrs, mut extra))) => {
+                extra.push(line);
+                pending = Some((attrs, extra));
+            }
+            (line, None) => entries.push(MasterEntry::Line(line)),
+        }
+    }
+
+    if let Some((attrs, extra)) = pending {
+        entries.push(MasterEntry::Line(Line::Tag(Tag::StreamInf(attrs))));
+        entries.extend(extra.into_iter().map(MasterEntry::Line));
+    }
+
+    MasterPlaylist { entries }
+}
+
+fn parse_media(lines: Vec<Line>) -> MediaPlaylist {
+    let mut playlist = MediaPlaylist::default();
+    let mut pending: Vec<Line> = Vec::new();
+
+    for line in lines {
+        match line {
+            Line::Uri(uri) => {
+                if playlist.segments.is_empty() {
+                    // The header runs through the last playlist-level tag, so unknown
+                    // tags between those (EXT-X-ALLOW-CACHE and such) stay in it, while
+                    // ones after it (a CUE-OUT opening the window) belong to the segment
+                    let header_len = pending
+                        .iter()
+                        .rposition(|l| l.tag().is_some_and(Tag::is_playlist_level))
+                        .map_or(0, |i| i + 1);
+                    playlist.header = pending.drain(..header_len).collect();
+                }
+                playlist.segments.push(Segment {
+                    tags: std::mem::take(&mut pending),
+                    uri,
+                });
+            }
+            line => pending.push(line),
+        }
+    }
+
+    if playlist.segments.is_empty() {
+        playlist.header = pending;
+    } else {
+        playlist.trailer = pending;
+    }
+    playlist
+}
+
+impl fmt::Display for MasterPlaylist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        let mut line = |f: &mut fmt::Formatter<'_>, value: &dyn fmt::Display| {
+            if !first {
+                f.write_char('\n')?;
+            }
+            first = false;
+            value.fmt(f)
+        };
+
+        for entry in &self.entries {
+            match entry {
+                MasterEntry::Line(l) => line(f, l)?,
+                MasterEntry::Variant(v) => {
+                    line(f, &Tag::StreamInf(v.stream_inf.clone()))?;
+                    for extra in &v.extra {
+                        line(f, extra)?;
+                    }
+                    line(f, &v.uri)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MediaPlaylist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        let mut line = |f: &mut fmt::Formatter<'_>, value: &dyn fmt::Display| {
+            if !first {
+                f.write_char('\n')?;
+            }
+            first = false;
+            value.fmt(f)
+        };
+
+        for l in &self.header {
+            line(f, l)?;
+        }
+        for segment in &self.segments {
+            for l in &segment.tags {
+                line(f, l)?;
+            }
+            line(f, &segment.uri)?;
+        }
+        for l in &self.trailer {
+            line(f, l)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Playlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Playlist::Master(m) => m.fmt(f),
+            Playlist::Media(m) => m.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER: &str = r#"#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-SESSION-DATA:DATA-ID="com.example.title",VALUE="Example"
+#EXT-X-SESSION-KEY:METHOD=SAMPLE-AES,URI="skd://key",KEYFORMAT="com.apple.streamingkeydelivery"
+#EXT-X-CONTENT-STEERING:SERVER-URI="steering.json",PATHWAY-ID="cdn-a"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="aud",NAME="English",LANGUAGE="en",DEFAULT=YES,URI="audio/en.m3u8"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="subs",NAME="English",LANGUAGE="en",URI="subs/en.m3u8"
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,RESOLUTION=1280x720,CODECS="avc1.64001f,mp4a.40.2",AUDIO="aud",SUBTITLES="subs"
+720p.m3u8
+# a comment between variants
+
+#EXT-X-STREAM-INF:BANDWIDTH=2560000 , RESOLUTION=1920x1080,FRAME-RATE=29.970
+1080p.m3u8
+#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH=86000,URI="iframe.m3u8"
+#EXT-X-VENDOR-THING:X-ASSET-LIST="ads.json",ID=1"#;
+
+    const MEDIA: &str = r#"#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-TARGETDURATION:4
+#EXT-X-MEDIA-SEQUENCE:100
+#EXT-X-DISCONTINUITY-SEQUENCE:3
+#EXT-X-MAP:URI="init.mp4",BYTERANGE="720@0"
+#EXT-X-KEY:METHOD=AES-128,URI="key.bin",IV=0x0000000000000000000000000000ABCD
+#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:00.000Z
+#EXTINF:4.000,first
+#EXT-X-BYTERANGE:1000@720
+seg100.mp4
+#EXT-X-DISCONTINUITY
+#EXT-X-DATERANGE:ID="ad",START-DATE="2024-01-01T00:00:04Z",DURATION=4.0
+#EXTINF:4.0,
+seg101.mp4
+#EXT-X-GAP
+#EXTINF:3.5,
+seg102.mp4
+#EXT-X-ENDLIST"#;
+
+    const LOW_LATENCY: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=3.0,CAN-SKIP-UNTIL=24.0
+#EXT-X-PART-INF:PART-TARGET=1.0
+#EXT-X-MEDIA-SEQUENCE:20
+#EXT-X-SKIP:SKIPPED-SEGMENTS=3
+#EXT-X-PART:DURATION=1.0,URI="seg23.0.mp4",INDEPENDENT=YES
+#EXTINF:4.0,
+seg23.mp4
+#EXT-X-PRELOAD-HINT:TYPE=PART,URI="seg24.0.mp4"
+#EXT-X-RENDITION-REPORT:URI="../audio/index.m3u8",LAST-MSN=23,LAST-PART=0"#;
+
+    fn media(text: &str) -> MediaPlaylist {
+        match Playlist::parse(text) {
+            Playlist::Media(media) => media,
+            Playlist::Master(_) => panic!("parsed as a master playlist"),
+        }
+    }
+
+    fn master(text: &str) -> MasterPlaylist {
+        match Playlist::parse(text) {
+            Playlist::Master(master) => master,
+            Playlist::Media(_) => panic!("parsed as a media playlist"),
+        }
+    }
+
+    fn rewritten(text: &str) -> String {
+        let mut playlist = Playlist::parse(text);
+        playlist
+            .try_rewrite_uris(|uri| -> Result<String, ()> { Ok(format!("/p/{}", uri)) })
+            .unwrap();
+        playlist.to_string()
+    }
+
+    #[test]
+    fn round_trips_byte_for_byte() {
+        for text in [MASTER, MEDIA, LOW_LATENCY] {
+            assert_eq!(Playlist::parse(text).to_string(), text);
+        }
+    }
+
+    #[test]
+    fn round_trips_unknown_and_malformed_tags() {
+        let text = "#EXTM3U\n#EXT-X-ALLOW-CACHE:NO\n#EXT-X-TARGETDURATION:abc\n# packager 1.2\n\
+                    #EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:500\n#EXT-X-CUE-OUT:30\n#EXTINF:4,\na.ts\n#EXT-X-CUE-IN";
+        let media = media(text);
+        // The header ends at MEDIA-SEQUENCE, the last playlist-level tag, with the
+        // unknown and malformed tags and the comment before it kept in place
+        assert_eq!(media.header.len(), 6);
+        assert!(matches!(&media.header[1], Line::Tag(Tag::Unknown(raw)) if raw == "#EXT-X-ALLOW-CACHE:NO"));
+        assert!(matches!(&media.header[2], Line::Tag(Tag::Unknown(raw)) if raw == "#EXT-X-TARGETDURATION:abc"));
+        assert!(matches!(&media.header[5], Line::Tag(Tag::MediaSequence(500))));
+        assert!(matches!(&media.segments[0].tags[0], Line::Tag(Tag::Unknown(raw)) if raw == "#EXT-X-CUE-OUT:30"));
+        assert_eq!(media.target_duration(), Some(4));
+        assert_eq!(media.media_sequence(), 500);
+        assert_eq!(media.trailer.len(), 1);
+        assert_eq!(media.to_string(), text);
+    }
+
+    #[test]
+    fn sequence_numbers_after_unknown_tags_are_replaced_in_place() {
+        let mut media = media("#EXTM3U\n#EXT-X-ALLOW-CACHE:NO\n#EXT-X-MEDIA-SEQUENCE:500\n#EXTINF:4,\na.ts");
+        media.set_media_sequence(7);
+        assert_eq!(media.to_string(), "#EXTM3U\n#EXT-X-ALLOW-CACHE:NO\n#EXT-X-MEDIA-SEQUENCE:7\n#EXTINF:4,\na.ts");
+    }
+
+    #[test]
+    fn parses_master_playlist() {
+        let master = master(MASTER);
+        let variants: Vec<_> = master.variants().collect();
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].uri, "720p.m3u8");
+        assert_eq!(variants[0].bandwidth(), Some(1_280_000));
+        assert_eq!(variants[0].stream_inf.get_resolution("RESOLUTION"), Some((1280, 720)));
+        assert_eq!(variants[0].stream_inf.get_list("CODECS"), vec!["avc1.64001f", "mp4a.40.2"]);
+        assert_eq!(variants[1].stream_inf.get_f64("FRAME-RATE"), Some(29.97));
+        // The comment and blank line before the second variant are master-level lines
+        assert_eq!(variants[1].extra, Vec::<Line>::new());
+
+        let tags: Vec<&Tag> = master
+            .entries
+            .iter()
+            .filter_map(|e| match e {
+                MasterEntry::Line(Line::Tag(tag)) => Some(tag),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tags.iter().filter(|t| matches!(t, Tag::Media(_))).count(), 2);
+        assert!(tags.iter().any(|t| matches!(t, Tag::SessionData(a) if a.get("VALUE") == Some("Example"))));
+        assert!(tags.iter().any(|t| matches!(t, Tag::SessionKey(a) if a.get("METHOD") == Some("SAMPLE-AES"))));
+        assert!(tags.iter().any(|t| matches!(t, Tag::IFrameStreamInf(a) if a.get("URI") == Some("iframe.m3u8"))));
+    }
+
+    #[test]
+    fn parses_media_playlist() {
+        let media = media(MEDIA);
+        assert_eq!(media.target_duration(), Some(4));
+        assert_eq!(media.media_sequence(), 100);
+        assert_eq!(media.discontinuity_sequence(), 3);
+        assert!(media.has_end_list());
+        assert_eq!(media.segments.len(), 3);
+        assert!((media.total_duration() - 11.5).abs() < 1e-9);
+
+        let first = &media.segments[0];
+        assert_eq!(first.uri, "seg100.mp4");
+        assert_eq!(first.duration(), 4.0);
+        assert_eq!(first.byte_range(), Some(ByteRange { length: 1000, offset: Some(720) }));
+        assert_eq!(first.key().and_then(|k| k.get("URI")), Some("key.bin"));
+        assert_eq!(first.map().and_then(|m| m.get("BYTERANGE")), Some("720@0"));
+        assert!(first.tags.iter().any(|l| matches!(l, Line::Tag(Tag::ProgramDateTime(_)))));
+        assert!(!first.discontinuity());
+
+        assert!(media.segments[1].discontinuity());
+        assert_eq!(media.segments[1].key(), None);
+        assert!(media.segments[2].tags.iter().any(|l| matches!(l, Line::Tag(Tag::Gap))));
+    }
+
+    #[test]
+    fn parses_low_latency_playlist() {
+        let media = media(LOW_LATENCY);
+        assert_eq!(media.part_target(), Some(1.0));
+        assert_eq!(media.server_control().and_then(|a| a.get_f64("CAN-SKIP-UNTIL")), Some(24.0));
+        assert_eq!(media.skip().and_then(|a| a.get_u64("SKIPPED-SEGMENTS")), Some(3));
+        assert_eq!(media.segments.len(), 1);
+        assert!(matches!(&media.segments[0].tags[0], Line::Tag(Tag::Part(a)) if a.get("URI") == Some("seg23.0.mp4")));
+        assert_eq!(media.trailer.len(), 2);
+    }
+
+    #[test]
+    fn playlist_without_segments_keeps_everything_in_the_header() {
+        let media = media("#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-ENDLIST");
+        assert!(media.segments.is_empty());
+        assert_eq!(media.header.len(), 3);
+        assert!(media.has_end_list());
+    }
+
+    #[test]
+    fn accepts_crlf_line_endings() {
+        let media = media("#EXTM3U\r\n#EXT-X-TARGETDURATION:4\r\n#EXTINF:4.0,\r\na.ts\r\n");
+        assert_eq!(media.target_duration(), Some(4));
+        assert_eq!(media.segments[0].uri, "a.ts");
+        assert_eq!(media.to_string(), "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4.0,\na.ts");
+    }
+
+    #[test]
+    fn rewrites_every_uri_in_a_master_playlist() {
+        let out = rewritten(MASTER);
+        for expected in [
+            r#"#EXT-X-SESSION-KEY:METHOD=SAMPLE-AES,URI="skd://key",KEYFORMAT="com.apple.streamingkeydelivery""#,
+            r#"#EXT-X-CONTENT-STEERING:SERVER-URI="/p/steering.json",PATHWAY-ID="cdn-a""#,
+            r#"#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="aud",NAME="English",LANGUAGE="en",DEFAULT=YES,URI="/p/audio/en.m3u8""#,
+            "\n/p/720p.m3u8\n",
+            "#EXT-X-STREAM-INF:BANDWIDTH=2560000 , RESOLUTION=1920x1080,FRAME-RATE=29.970\n",
+            "\n/p/1080p.m3u8\n",
+            r#"#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH=86000,URI="/p/iframe.m3u8""#,
+            r#"#EXT-X-VENDOR-THING:X-ASSET-LIST="/p/ads.json",ID=1"#,
+        ] {
+            assert!(out.contains(expected), "missing {:?} in\n{}", expected, out);
+        }
+        assert_eq!(out.lines().count(), MASTER.lines().count());
+    }
+
+    #[test]
+    fn rewrites_every_uri_in_a_media_playlist() {
+        let out = rewritten(MEDIA);
+        assert!(out.contains(r#"#EXT-X-MAP:URI="/p/init.mp4",BYTERANGE="720@0""#));
+        assert!(out.contains(r#"#EXT-X-KEY:METHOD=AES-128,URI="/p/key.bin",IV=0x0000000000000000000000000000ABCD"#));
+        assert!(out.contains("\n/p/seg100.mp4\n"));
+        assert!(out.contains("#EXT-X-BYTERANGE:1000@720\n"));
+        assert!(out.ends_with("/p/seg102.mp4\n#EXT-X-ENDLIST"));
+
+        let out = rewritten(LOW_LATENCY);
+        assert!(out.contains(r#"#EXT-X-PART:DURATION=1.0,URI="/p/seg23.0.mp4",INDEPENDENT=YES"#));
+        assert!(out.contains(r#"#EXT-X-PRELOAD-HINT:TYPE=PART,URI="/p/seg24.0.mp4""#));
+        assert!(out.contains(r#"#EXT-X-RENDITION-REPORT:URI="/p/../audio/index.m3u8",LAST-MSN=23,LAST-PART=0"#));
+    }
+
+    #[test]
+    fn leaves_non_http_uris_to_the_player() {
+        let text = "#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"skd://asset-1\",KEYFORMAT=\"com.apple.streamingkeydelivery\"\n\
+                    #EXT-X-KEY:METHOD=AES-128,URI=\"data:text/plain;base64,AAECAwQFBgcICQoLDA0ODw==\"\n\
+                    #EXT-X-VENDOR-AD:URL=\"HTTPS://ads.example/a.json\"\n\
+                    #EXTINF:4.0,\n//cdn.example/seg0.ts\n#EXTINF:4.0,\nseg:1.ts\n#EXTINF:4.0,\nhttp://cdn.example/seg2.ts";
+        assert_eq!(
+            rewritten(text),
+            "#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"skd://asset-1\",KEYFORMAT=\"com.apple.streamingkeydelivery\"\n\
+             #EXT-X-KEY:METHOD=AES-128,URI=\"data:text/plain;base64,AAECAwQFBgcICQoLDA0ODw==\"\n\
+             #EXT-X-VENDOR-AD:URL=\"/p/HTTPS://ads.example/a.json\"\n\
+             #EXTINF:4.0,\n/p///cdn.example/seg0.ts\n#EXTINF:4.0,\nseg:1.ts\n#EXTINF:4.0,\n/p/http://cdn.example/seg2.ts"
+        );
+    }
+
+    #[test]
+    fn rewrite_errors_stop_the_rewrite() {
+        let mut playlist = Playlist::parse(MEDIA);
+        let result = playlist.try_rewrite_uris(|uri| if uri == "key.bin" { Err(uri.to_string()) } else { Ok(uri.to_string()) });
+        assert_eq!(result, Err("key.bin".to_string()));
+    }
+
+    #[test]
+    fn edited_attributes_fall_back_to_canonical_form() {
+        let mut attrs = Attributes::parse(r#"TYPE=SUBTITLES , NAME="English",DEFAULT=YES"#).unwrap();
+        assert_eq!(attrs.to_string(), r#"TYPE=SUBTITLES , NAME="English",DEFAULT=YES"#);
+        attrs.set("DEFAULT", "NO", false);
+        attrs.set("URI", "subs.m3u8", true);
+        assert_eq!(attrs.remove("NAME").as_deref(), Some("English"));
+        assert_eq!(attrs.to_string(), r#"TYPE=SUBTITLES,DEFAULT=NO,URI="subs.m3u8""#);
+    }
+
+    #[test]
+    fn sequence_numbers_can_be_set() {
+        let mut media = media(MEDIA);
+        media.set_media_sequence(90);
+        media.set_discontinuity_sequence(1);
+        assert_eq!(media.media_sequence(), 90);
+        assert_eq!(media.discontinuity_sequence(), 1);
+        assert!(media.to_string().contains("#EXT-X-MEDIA-SEQUENCE:90\n#EXT-X-DISCONTINUITY-SEQUENCE:1\n"));
+
+        let mut bare = self::media("#EXTM3U\n#EXTINF:4,\na.ts");
+        bare.set_media_sequence(5);
+        assert_eq!(bare.to_string(), "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:5\n#EXTINF:4,\na.ts");
+    }
+}
@@ -0,0 +1,320 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+use super::playlist::{Attributes, Line, MasterEntry, MasterPlaylist, Tag};
+
+// [renditions] section of the config file; every option can also be set per
+// request with a query param of the same name (lists comma-separated).
+// The selection lists apply to AUDIO and SUBTITLES renditions: when any is
+// set, a rendition is kept if it matches at least one entry.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenditionFilter {
+    // LANGUAGE tags; "en" also matches "en-US"
+    pub languages: Vec<String>,
+    pub names: Vec<String>,
+    pub group_ids: Vec<String>,
+    // Make the first rendition in this language DEFAULT=YES in each group
+    pub default_language: Option<String>,
+    // false drops CLOSED-CAPTIONS renditions and sets CLOSED-CAPTIONS=NONE on variants
+    pub closed_captions: bool,
+}
+
+impl Default for RenditionFilter {
+    fn default() -> Self {
+        RenditionFilter {
+            languages: Vec::new(),
+            names: Vec::new(),
+            group_ids: Vec::new(),
+            default_language: None,
+            closed_captions: true,
+        }
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// RFC 5646 prefix match: "en" matches "en" and "en-US" but not "eng"
+fn language_matches(wanted: &str, language: &str) -> bool {
+    let (wanted, language) = (wanted.to_ascii_lowercase(), language.to_ascii_lowercase());
+    language == wanted
+        || (language.starts_with(&wanted) && language.as_bytes().get(wanted.len()) == Some(&b'-'))
+}
+
+// (index in master.entries, rendition attributes)
+fn media_entries(master: &MasterPlaylist) -> impl Iterator<Item = (usize, &Attributes)> {
+    master.entries.iter().enumerate().filter_map(|(i, e)| match e {
+        MasterEntry::Line(Line::Tag(Tag::Media(a))) => Some((i, a)),
+        _ => None,
+    })
+}
+
+impl RenditionFilter {
+    // This filter with any options given in the request query applied on top
+    pub fn with_query(&self, query: &HashMap<String, String>) -> RenditionFilter {
+        let mut filter = self.clone();
+        if let Some(v) = query.get("languages") {
+            filter.languages = parse_list(v);
+        }
+        if let Some(v) = query.get("names") {
+            filter.names = parse_list(v);
+        }
+        if let Some(v) = query.get("group_ids") {
+            filter.group_ids = parse_list(v);
+        }
+        if let Some(v) = query.get("default_language") {
+            filter.default_language = Some(v.trim().to_string()).filter(|v| !v.is_empty());
+        }
+        if let Some(v) = query.get("closed_captions") {
+            filter.closed_captions = v == "true" || v == "1";
+        }
+        filter
+    }
+
+    pub fn is_noop(&self) -> bool {
+        !self.selects() && self.default_language.is_none() && self.closed_captions
+    }
+
+    fn selects(&self) -> bool {
+        !self.languages.is_empty() || !self.names.is_empty() || !self.group_ids.is_empty()
+    }
+
+    fn matches(&self, attrs: &Attributes) -> bool {
+        let language = attrs.get("LANGUAGE");
+        language.is_some_and(|l| self.languages.iter().any(|w| language_matches(w, l)))
+            || attrs.get("NAME").is_some_and(|n| self.names.iter().any(|w| w == n))
+            || attrs.get("GROUP-ID").is_some_and(|g| self.group_ids.iter().any(|w| w == g))
+    }
+
+    // Whether a rendition survives, ignoring group consistency
+    fn keeps(&self, attrs: &Attributes, select_audio: bool) -> bool {
+        match attrs.get("TYPE") {
+            Some("CLOSED-CAPTIONS") => self.closed_captions,
+            Some("AUDIO") if select_audio => self.matches(attrs),
+            Some("SUBTITLES") if self.selects() => self.matches(attrs),
+            _ => true,
+        }
+    }
+
+    pub fn apply(&self, master: &mut MasterPlaylist) {
+        if self.is_noop() {
+            return;
+        }
+
+        // Variants whose AUDIO group loses every rendition go too. If that would
+        // leave no variant at all, audio renditions are left alone.
+        let mut select_audio = self.selects();
+        let (mut dropped_audio, dropped_subtitles) = self.emptied_groups(master, select_audio);
+        let survivors = master
+            .variants()
+            .filter(|v| !v.stream_inf.get("AUDIO").is_some_and(|g| dropped_audio.contains(g)))
+            .count();
+        if survivors == 0 && !dropped_audio.is_empty() {
+            eprintln!("Rendition filter would drop every variant, keeping all audio renditions");
+            select_audio = false;
+            dropped_audio.clear();
+        }
+
+        master.entries.retain_mut(|entry| match entry {
+            MasterEntry::Line(Line::Tag(Tag::Media(attrs))) => self.keeps(attrs, select_audio),
+            MasterEntry::Variant(variant) => {
+                let attrs = &mut variant.stream_inf;
+                if attrs.get("AUDIO").is_some_and(|g| dropped_audio.contains(g)) {
+                    return false;
+                }
+                if attrs.get("SUBTITLES").is_some_and(|g| dropped_subtitles.contains(g)) {
+                    attrs.remove("SUBTITLES");
+                }
+                if !self.closed_captions && attrs.get("CLOSED-CAPTIONS").is_some_and(|g| g != "NONE") {
+                    attrs.set("CLOSED-CAPTIONS", "NONE", false);
+                }
+                true
+            }
+            _ => true,
+        });
+
+        if let Some(language) = &self.default_language {
+            self.force_default(master, language);
+        }
+    }
+
+    // AUDIO and SUBTITLES group ids that would have no rendition left
+    fn emptied_groups(&self, master: &MasterPlaylist, select_audio: bool) -> (HashSet<String>, HashSet<String>) {
+        let mut before: HashMap<(String, String), bool> = HashMap::new();
+        for (_, attrs) in media_entries(master) {
+            let (Some(kind), Some(group)) = (attrs.get("TYPE"), attrs.get("GROUP-ID")) else {
+                continue;
+            };
+            let kept = before.entry((kind.to_string(), group.to_string())).or_insert(false);
+            *kept |= self.keeps(attrs, select_audio);
+        }
+
+        let emptied = |wanted: &str| {
+            before
+                .iter()
+                .filter(|((kind, _), kept)| kind == wanted && !**kept)
+                .map(|((_, group), _)| group.clone())
+                .collect()
+        };
+        (emptied("AUDIO"), emptied("SUBTITLES"))
+    }
+
+    // First matching rendition of each AUDIO/SUBTITLES group becomes the
+    // default; the others in that group stop being one
+    fn force_default(&self, master: &mut MasterPlaylist, language: &str) {
+        let mut chosen: HashMap<(String, String), usize> = HashMap::new();
+        for (index, attrs) in media_entries(master) {
+            let (Some(kind @ ("AUDIO" | "SUBTITLES")), Some(group)) = (attrs.get("TYPE"), attrs.get("GROUP-ID")) else {
+                continue;
+            };
+            if attrs.get("LANGUAGE").is_some_and(|l| language_matches(language, l)) {
+                chosen.entry((kind.to_string(), group.to_string())).or_insert(index);
+            }
+        }
+
+        for (index, entry) in master.entries.iter_mut().enumerate() {
+            let MasterEntry::Line(Line::Tag(Tag::Media(attrs))) = entry else {
+                continue;
+            };
+            let key = match (attrs.get("TYPE"), attrs.get("GROUP-ID")) {
+                (Some(kind), Some(group)) => (kind.to_string(), group.to_string()),
+                _ => continue,
+            };
+            match chosen.get(&key) {
+                Some(&default) if default == index => {
+                    attrs.set("DEFAULT", "YES", false);
+                    // DEFAULT=YES requires AUTOSELECT=YES
+                    attrs.set("AUTOSELECT", "YES", false);
+                }
+                Some(_) if attrs.get("DEFAULT") == Some("YES") => attrs.set("DEFAULT", "NO", false),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hls::playlist::Playlist;
+
+    const MASTER: &str = r#"#EXTM3U
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="aac",NAME="English",LANGUAGE="en-US",DEFAULT=YES,AUTOSELECT=YES,URI="aac/en.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="aac",NAME="Deutsch",LANGUAGE="de",URI="aac/de.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="ac3",NAME="Deutsch",LANGUAGE="de",DEFAULT=YES,URI="ac3/de.m3u8"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="subs",NAME="English",LANGUAGE="en",URI="subs/en.m3u8"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="forced",NAME="Forced",LANGUAGE="de",URI="subs/forced.m3u8"
+#EXT-X-MEDIA:TYPE=CLOSED-CAPTIONS,GROUP-ID="cc",NAME="CC1",LANGUAGE="en",INSTREAM-ID="CC1"
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,AUDIO="aac",SUBTITLES="subs",CLOSED-CAPTIONS="cc"
+aac.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1380000,AUDIO="ac3",SUBTITLES="forced",CLOSED-CAPTIONS="cc"
+ac3.m3u8"#;
+
+    fn filter(query: &[(&str, &str)]) -> RenditionFilter {
+        let query = query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        RenditionFilter::default().with_query(&query)
+    }
+
+    fn apply(filter: &RenditionFilter) -> MasterPlaylist {
+        let Playlist::Master(mut master) = Playlist::parse(MASTER) else {
+            panic!("parsed as a media playlist");
+        };
+        filter.apply(&mut master);
+        master
+    }
+
+    // NAME of every rendition left, as "TYPE/GROUP-ID/NAME"
+    fn renditions(master: &MasterPlaylist) -> Vec<String> {
+        media_entries(master)
+            .map(|(_, a)| format!("{}/{}/{}", a.get("TYPE").unwrap(), a.get("GROUP-ID").unwrap(), a.get("NAME").unwrap()))
+            .collect()
+    }
+
+    fn variants(master: &MasterPlaylist) -> Vec<&str> {
+        master.variants().map(|v| v.uri.as_str()).collect()
+    }
+
+    #[test]
+    fn matches_language_prefixes() {
+        assert!(language_matches("en", "en"));
+        assert!(language_matches("EN", "en-US"));
+        assert!(!language_matches("en", "eng"));
+        assert!(!language_matches("en-US", "en"));
+    }
+
+    #[test]
+    fn drops_variants_whose_audio_group_empties() {
+        let master = apply(&filter(&[("languages", "en")]));
+        assert_eq!(renditions(&master), ["AUDIO/aac/English", "SUBTITLES/subs/English", "CLOSED-CAPTIONS/cc/CC1"]);
+        // ac3 has no English rendition left, so its variant can't play
+        assert_eq!(variants(&master), ["aac.m3u8"]);
+    }
+
+    #[test]
+    fn keeps_every_audio_rendition_rather_than_every_variant_going() {
+        let master = apply(&filter(&[("languages", "fr")]));
+        assert_eq!(
+            renditions(&master),
+            ["AUDIO/aac/English", "AUDIO/aac/Deutsch", "AUDIO/ac3/Deutsch", "CLOSED-CAPTIONS/cc/CC1"]
+        );
+        assert_eq!(variants(&master), ["aac.m3u8", "ac3.m3u8"]);
+        // Subtitle groups that emptied are no longer referenced
+        assert!(master.variants().all(|v| v.stream_inf.get("SUBTITLES").is_none()));
+    }
+
+    #[test]
+    fn unreferences_emptied_subtitle_groups() {
+        let master = apply(&filter(&[("languages", "de")]));
+        assert_eq!(
+            renditions(&master),
+            ["AUDIO/aac/Deutsch", "AUDIO/ac3/Deutsch", "SUBTITLES/forced/Forced", "CLOSED-CAPTIONS/cc/CC1"]
+        );
+        assert_eq!(variants(&master), ["aac.m3u8", "ac3.m3u8"]);
+        let subtitles: Vec<_> = master.variants().map(|v| v.stream_inf.get("SUBTITLES")).collect();
+        assert_eq!(subtitles, [None, Some("forced")]);
+    }
+
+    #[test]
+    fn selects_by_name_and_group() {
+        let master = apply(&filter(&[("group_ids", "ac3,subs")]));
+        assert_eq!(renditions(&master), ["AUDIO/ac3/Deutsch", "SUBTITLES/subs/English", "CLOSED-CAPTIONS/cc/CC1"]);
+        assert_eq!(variants(&master), ["ac3.m3u8"]);
+
+        let master = apply(&filter(&[("names", "Deutsch")]));
+        assert_eq!(renditions(&master), ["AUDIO/aac/Deutsch", "AUDIO/ac3/Deutsch", "CLOSED-CAPTIONS/cc/CC1"]);
+    }
+
+    #[test]
+    fn drops_closed_captions() {
+        let master = apply(&filter(&[("closed_captions", "false")]));
+        assert!(!renditions(&master).iter().any(|r| r.starts_with("CLOSED-CAPTIONS")));
+        assert!(master.variants().all(|v| v.stream_inf.get("CLOSED-CAPTIONS") == Some("NONE")));
+    }
+
+    #[test]
+    fn forces_one_default_per_group() {
+        let master = apply(&filter(&[("default_language", "de")]));
+        let defaults: Vec<_> = media_entries(&master)
+            .map(|(_, a)| (a.get("NAME").unwrap(), a.get("DEFAULT"), a.get("AUTOSELECT")))
+            .collect();
+        assert_eq!(
+            defaults,
+            [
+                ("English", Some("NO"), Some("YES")),
+                ("Deutsch", Some("YES"), Some("YES")),
+                ("Deutsch", Some("YES"), Some("YES")),
+                ("English", None, None),
+                ("Forced", Some("YES"), Some("YES")),
+                ("CC1", None, None),
+            ]
+        );
+        assert!(RenditionFilter::default().is_noop());
+    }
+}
@@ -0,0 +1,266 @@
+// Line-by-line playlist rewriting over a streamed body, for playlists that
+// need no whole-playlist processing. Output matches rewriting the parsed
+// playlist: lines joined with "\n", CRLF endings normalized.
+
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use std::{error::Error, io};
+
+use super::playlist::{rewrite_line, Line};
+
+// A line this long without a newline is not a playlist we want to hold
+const MAX_LINE: usize = 1024 * 1024;
+
+pub struct LineRewriter<F> {
+    // Start of a line whose end hasn't arrived yet
+    partial: Vec<u8>,
+    first: bool,
+    rewrite: F,
+}
+
+impl<F: FnMut(&str) -> Result<String, String>> LineRewriter<F> {
+    // `rewrite` maps each URI as written in the playlist, like Playlist::try_rewrite_uris
+    pub fn new(rewrite: F) -> Self {
+        LineRewriter {
+            partial: Vec::new(),
+            first: true,
+            rewrite,
+        }
+    }
+
+    // Rewrite every line completed by `chunk`; a trailing partial line waits for the next one
+    pub fn push(&mut self, mut chunk: &[u8]) -> Result<Bytes, String> {
+        let mut out = String::with_capacity(chunk.len() + chunk.len() / 2);
+        while let Some(end) = memchr::memchr(b'\n', chunk) {
+            if self.partial.is_empty() {
+                self.write_line(&chunk[..end], &mut out)?;
+            } else {
+                self.partial.extend_from_slice(&chunk[..end]);
+                let line = std::mem::take(&mut self.partial);
+                self.write_line(&line, &mut out)?;
+            }
+            chunk = &chunk[end + 1..];
+        }
+        self.partial.extend_from_slice(chunk);
+        if self.partial.len() > MAX_LINE {
+            return Err("Playlist line too long".to_string());
+        }
+        Ok(Bytes::from(out))
+    }
+
+    // Rewrite the last line once the body has ended
+    pub fn finish(&mut self) -> Result<Bytes, String> {
+        let mut out = String::new();
+        if !self.partial.is_empty() {
+            let line = std::mem::take(&mut self.partial);
+            self.write_line(&line, &mut out)?;
+        }
+        Ok(Bytes::from(out))
+    }
+
+    fn write_line(&mut self, raw: &[u8], out: &mut String) -> Result<(), String> {
+        let text = String::from_utf8_lossy(raw);
+        let text = if self.first { text.strip_prefix('\u{FEFF}').unwrap_or(&text) } else { &text };
+        let mut line = Line::parse(text);
+        rewrite_line(&mut line, &mut self.rewrite)?;
+        if !self.first {
+            out.push('\n');
+        }
+        self.first = false;
+        out.push_str(&line.to_string());
+        Ok(())
+    }
+}
+
+// Rewritten body, emitted as upstream chunks arrive. Headers are gone by the
+// time a bad line shows up, so a rewrite error ends the stream with an error;
+// rewrites that can be refused (host policy) don't stream.
+pub fn rewrite_body<S, E, F>(body: S, rewriter: LineRewriter<F>) -> impl Stream<Item = Result<Bytes, io::Error>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Error + Send + Sync + 'static,
+    F: FnMut(&str) -> Result<String, String>,
+{
+    stream::unfold(Some((body, rewriter)), |state| async move {
+        let (mut body, mut rewriter) = state?;
+        let rewritten = match body.next().await {
+            Some(Ok(chunk)) => rewriter.push(&chunk).map_err(io::Error::other),
+            Some(Err(e)) => Err(io::Error::other(e)),
+            None => return Some((rewriter.finish().map_err(io::Error::other), None)),
+        };
+        match rewritten {
+            Ok(bytes) => Some((Ok(bytes), Some((body, rewriter)))),
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+// Whether a body that doesn't announce itself as a playlist starts like one.
+// Reads just enough chunks to tell; returns them so they can be sent on.
+pub async fn sniff_m3u8<S, E>(body: &mut S) -> Result<(bool, Vec<u8>), E>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    const MAGIC: &[u8] = b"#EXTM3U";
+    // UTF-8 byte order mark, which Playlist::parse and LineRewriter drop too
+    const BOM: &[u8] = b"\xEF\xBB\xBF";
+    // Leading whitespace this long and it isn't a playlist
+    const MAX_PREFIX: usize = 4 * 1024;
+    let mut prefix: Vec<u8> = Vec::new();
+    loop {
+        let partial_bom = !prefix.is_empty() && prefix.len() < BOM.len() && BOM.starts_with(&prefix);
+        let text = prefix.strip_prefix(BOM).unwrap_or(&prefix);
+        let start = text.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(text.len());
+        let seen = &text[start..];
+        if !partial_bom && (seen.len() >= MAGIC.len() || !MAGIC.starts_with(seen)) {
+            return Ok((seen.starts_with(MAGIC), prefix));
+        }
+        if prefix.len() >= MAX_PREFIX {
+            return Ok((false, prefix));
+        }
+        match body.next().await {
+            Some(chunk) => prefix.extend_from_slice(&chunk?),
+            None => return Ok((false, prefix)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hls::playlist::Playlist;
+
+    const PLAYLIST: &str = "#EXTM3U\r\n#EXT-X-TARGETDURATION:4\r\n#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"\r\n\
+                            #EXTINF:4.0,\r\nseg0.ts\r\n# comment\r\n\r\n#EXTINF:4.0,\r\nseg1.ts\r\n#EXT-X-ENDLIST\r\n";
+
+    fn prefix(uri: &str) -> Result<String, String> {
+        Ok(format!("/p/{}", uri))
+    }
+
+    fn buffered(text: &str) -> String {
+        let mut playlist = Playlist::parse(text);
+        playlist.try_rewrite_uris(prefix).unwrap();
+        playlist.to_string()
+    }
+
+    // Feed `text` to a LineRewriter in chunks of `size` bytes
+    fn streamed(text: &str, size: usize) -> String {
+        let mut rewriter = LineRewriter::new(prefix);
+        let mut out = Vec::new();
+        for chunk in text.as_bytes().chunks(size) {
+            out.extend_from_slice(&rewriter.push(chunk).unwrap());
+        }
+        out.extend_from_slice(&rewriter.finish().unwrap());
+        String::from_utf8(out).unwrap()
+    }
+
+    fn body(chunks: &[&'static str]) -> impl Stream<Item = Result<Bytes, io::Error>> + Unpin {
+        stream::iter(chunks.iter().map(|c| Ok(Bytes::from_static(c.as_bytes()))).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn matches_the_buffered_rewrite_for_any_chunking() {
+        let expected = buffered(PLAYLIST);
+        assert_eq!(
+            expected,
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-KEY:METHOD=AES-128,URI=\"/p/key.bin\"\n\
+             #EXTINF:4.0,\n/p/seg0.ts\n# comment\n\n#EXTINF:4.0,\n/p/seg1.ts\n#EXT-X-ENDLIST"
+        );
+        for size in [1, 2, 3, 7, 16, PLAYLIST.len()] {
+            assert_eq!(streamed(PLAYLIST, size), expected, "chunk size {}", size);
+        }
+    }
+
+    #[test]
+    fn rewrites_a_final_line_without_newline() {
+        let text = "#EXTM3U\n#EXTINF:4.0,\nseg0.ts";
+        assert_eq!(streamed(text, 5), "#EXTM3U\n#EXTINF:4.0,\n/p/seg0.ts");
+        assert_eq!(streamed(text, 5), buffered(text));
+    }
+
+    #[test]
+    fn holds_partial_lines_until_they_end() {
+        let mut rewriter = LineRewriter::new(prefix);
+        assert_eq!(rewriter.push(b"#EXTM3U\nseg").unwrap(), "#EXTM3U");
+        assert_eq!(rewriter.push(b"0.t").unwrap(), "");
+        assert_eq!(rewriter.push(b"s\r\n").unwrap(), "\n/p/seg0.ts");
+        assert_eq!(rewriter.finish().unwrap(), "");
+    }
+
+    #[test]
+    fn refuses_overlong_lines() {
+        let mut rewriter = LineRewriter::new(prefix);
+        assert!(rewriter.push(&vec![b'a'; MAX_LINE]).is_ok());
+        assert_eq!(rewriter.push(b"a").unwrap_err(), "Playlist line too long");
+    }
+
+    #[tokio::test]
+    async fn rewrite_body_streams_and_ends_on_errors() {
+        let chunks: Vec<_> = rewrite_body(body(&["#EXTM3U\nse", "g0.ts\n"]), LineRewriter::new(prefix))
+            .collect()
+            .await;
+        let out: Vec<u8> = chunks.into_iter().flat_map(|c| c.unwrap().to_vec()).collect();
+        assert_eq!(out, b"#EXTM3U\n/p/seg0.ts");
+
+        let refusing = LineRewriter::new(|uri: &str| if uri == "bad.ts" { Err("refused".to_string()) } else { prefix(uri) });
+        let chunks: Vec<_> = rewrite_body(body(&["#EXTM3U\nbad.ts\n", "seg1.ts\n"]), refusing).collect().await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].as_ref().unwrap_err().to_string(), "refused");
+    }
+
+    #[tokio::test]
+    async fn sniffs_playlists_across_chunks() {
+        let (is_m3u8, prefix) = sniff_m3u8(&mut body(&["\r\n  #EXT", "M3U\n#EXT-X-VERSION:3\n", "rest"])).await.unwrap();
+        assert!(is_m3u8);
+        assert_eq!(prefix, b"\r\n  #EXTM3U\n#EXT-X-VERSION:3\n");
+
+        let (is_m3u8, prefix) = sniff_m3u8(&mut body(&["#EX", "T-X-KEY"])).await.unwrap();
+        assert!(!is_m3u8);
+        assert_eq!(prefix, b"#EXT-X-KEY");
+
+        let (is_m3u8, prefix) = sniff_m3u8(&mut body(&["\x47\x40\x11", "more"])).await.unwrap();
+        assert!(!is_m3u8);
+        assert_eq!(prefix, b"\x47\x40\x11");
+
+        let (is_m3u8, prefix) = sniff_m3u8(&mut body(&["#EXTM"])).await.unwrap();
+        assert!(!is_m3u8);
+        assert_eq!(prefix, b"#EXTM");
+    }
+
+    #[tokio::test]
+    async fn sniffs_playlists_behind_a_byte_order_mark() {
+        let (is_m3u8, prefix) = sniff_m3u8(&mut body(&["\u{FEFF}#EXTM3U\n", "seg0.ts\n"])).await.unwrap();
+        assert!(is_m3u8);
+        assert_eq!(prefix, "\u{FEFF}#EXTM3U\n".as_bytes());
+
+        // Split across chunks, and followed by whitespace
+        let chunks = [&b"\xEF"[..], b"\xBB", b"\xBF\r\n#EXT", b"M3U\n"];
+        let mut split = stream::iter(chunks.map(|c| Ok::<_, io::Error>(Bytes::from_static(c))));
+        let (is_m3u8, prefix) = sniff_m3u8(&mut split).await.unwrap();
+        assert!(is_m3u8);
+        assert_eq!(prefix, b"\xEF\xBB\xBF\r\n#EXTM3U\n");
+
+        let (is_m3u8, prefix) = sniff_m3u8(&mut body(&["\u{FEFF}<html>"])).await.unwrap();
+        assert!(!is_m3u8);
+        assert_eq!(prefix, "\u{FEFF}<html>".as_bytes());
+        let (is_m3u8, prefix) = sniff_m3u8(&mut body(&["\u{FEFF}"])).await.unwrap();
+        assert!(!is_m3u8);
+        assert_eq!(prefix, "\u{FEFF}".as_bytes());
+    }
+
+    #[test]
+    fn drops_a_leading_byte_order_mark() {
+        let text = "\u{FEFF}#EXTM3U\n#EXTINF:4.0,\nseg0.ts";
+        let expected = "#EXTM3U\n#EXTINF:4.0,\n/p/seg0.ts";
+        assert_eq!(buffered(text), expected);
+        assert_eq!(streamed(text, 2), expected);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_endless_whitespace() {
+        let mut blank = stream::repeat_with(|| Ok::<_, io::Error>(Bytes::from_static(b" \r\n\t".as_slice())));
+        let (is_m3u8, prefix) = sniff_m3u8(&mut blank).await.unwrap();
+        assert!(!is_m3u8);
+        assert!(prefix.len() >= 4 * 1024 && prefix.len() < 4 * 1024 + 4, "{}", prefix.len());
+    }
+}
@@ -0,0 +1,217 @@
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use url::Url;
+
+use super::playlist::{Attributes, Inf, Line, MasterEntry, MasterPlaylist, MediaPlaylist, Segment, Tag};
+
+// Subtitle group the injected tracks go into for variants that have none yet
+const INJECTED_GROUP: &str = "injected-subs";
+
+// One external WebVTT track, from the `subtitles` query param:
+// [{"url": "https://.../en.vtt", "language": "en", "name": "English"}]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TrackParam {
+    url: String,
+    language: Option<String>,
+    name: String,
+    #[serde(default)]
+    default: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SubtitleTrack {
+    pub url: Url,
+    pub language: Option<String>,
+    pub name: String,
+    pub default: bool,
+}
+
+// Written into quoted-string attributes, which can't hold these (RFC 8216 4.2)
+fn check_quotable(what: &str, value: &str) -> Result<(), String> {
+    if value.contains(['"', '\r', '\n']) {
+        return Err(format!("Invalid subtitle {}: {:?}", what, value));
+    }
+    Ok(())
+}
+
+pub fn parse_tracks(json: &str) -> Result<Vec<SubtitleTrack>, String> {
+    let params: Vec<TrackParam> = serde_json::from_str(json).map_err(|e| format!("Invalid subtitles: {}", e))?;
+    params
+        .into_iter()
+        .map(|param| {
+            let url = Url::parse(param.url.trim()).map_err(|_| format!("Invalid subtitle URL: {}", param.url))?;
+            check_quotable("name", &param.name)?;
+            if let Some(language) = &param.language {
+                check_quotable("language", language)?;
+            }
+            Ok(SubtitleTrack {
+                url,
+                language: param.language,
+                name: param.name,
+                default: param.default,
+            })
+        })
+        .collect()
+}
+
+// Add an EXT-X-MEDIA entry per track to every subtitle group the variants
+// use, and put variants without subtitles into a new group holding just the
+// injected tracks. `uri_for` gives the (proxied) subtitle playlist URI.
+pub fn inject(master: &mut MasterPlaylist, tracks: &[SubtitleTrack], uri_for: impl Fn(&SubtitleTrack) -> String) {
+    if tracks.is_empty() {
+        return;
+    }
+
+    // A default injected track takes over from the group's current default
+    if tracks.iter().any(|t| t.default) {
+        for entry in &mut master.entries {
+            if let MasterEntry::Line(Line::Tag(Tag::Media(attrs))) = entry {
+                if attrs.get("TYPE") == Some("SUBTITLES") && attrs.get("DEFAULT") == Some("YES") {
+                    attrs.set("DEFAULT", "NO", false);
+                }
+            }
+        }
+    }
+
+    let mut groups: BTreeSet<String> = BTreeSet::new();
+    for entry in &mut master.entries {
+        if let MasterEntry::Variant(variant) = entry {
+            match variant.stream_inf.get("SUBTITLES") {
+                Some(group) => {
+                    groups.insert(group.to_string());
+                }
+                None => {
+                    variant.stream_inf.set("SUBTITLES", INJECTED_GROUP, true);
+                    groups.insert(INJECTED_GROUP.to_string());
+                }
+            }
+        }
+    }
+
+    // Only the first track marked default becomes one
+    let default_track = tracks.iter().position(|t| t.default);
+    let mut lines = Vec::with_capacity(groups.len() * tracks.len());
+    for group in &groups {
+        for (index, track) in tracks.iter().enumerate() {
+            let mut attrs = Attributes::new();
+            attrs.set("TYPE", "SUBTITLES", false);
+            attrs.set("GROUP-ID", group.as_str(), true);
+            attrs.set("NAME", track.name.as_str(), true);
+            if let Some(language) = &track.language {
+                attrs.set("LANGUAGE", language.as_str(), true);
+            }
+            let default = if default_track == Some(index) { "YES" } else { "NO" };
+            attrs.set("DEFAULT", default, false);
+            attrs.set("AUTOSELECT", "YES", false);
+            attrs.set("URI", uri_for(track), true);
+            lines.push(MasterEntry::Line(Line::Tag(Tag::Media(attrs))));
+        }
+    }
+
+    // Renditions go ahead of the first variant that can refer to them
+    let at = master
+        .entries
+        .iter()
+        .position(|e| matches!(e, MasterEntry::Variant(_)))
+        .unwrap_or(master.entries.len());
+    master.entries.splice(at..at, lines);
+}
+
+// Single-segment VOD playlist covering the whole stream with one WebVTT file
+pub fn media_playlist(duration: f64, vtt_uri: String) -> MediaPlaylist {
+    let header = vec![
+        Line::Tag(Tag::ExtM3u),
+        Line::Tag(Tag::Version(3)),
+        Line::Tag(Tag::TargetDuration(duration.ceil().max(1.0) as u64)),
+        Line::Tag(Tag::MediaSequence(0)),
+        Line::Tag(Tag::PlaylistType("VOD".to_string())),
+    ];
+    MediaPlaylist {
+        header,
+        segments: vec![Segment {
+            tags: vec![Line::Tag(Tag::Inf(Inf::new(duration, "")))],
+            uri: vtt_uri,
+        }],
+        trailer: vec![Line::Tag(Tag::EndList)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hls::playlist::Playlist;
+
+    const MASTER: &str = r#"#EXTM3U
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="subs",NAME="English",LANGUAGE="en",DEFAULT=YES,URI="subs/en.m3u8"
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,SUBTITLES="subs"
+720p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=640000
+360p.m3u8"#;
+
+    #[test]
+    fn parses_tracks() {
+        let tracks = parse_tracks(
+            r#"[{"url": " https://subs.example.com/de.vtt ", "language": "de", "name": "Deutsch", "default": true},
+                {"url": "https://subs.example.com/fr.vtt", "name": "Français"}]"#,
+        )
+        .unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].url.as_str(), "https://subs.example.com/de.vtt");
+        assert_eq!((tracks[0].language.as_deref(), tracks[0].default), (Some("de"), true));
+        assert_eq!((tracks[1].language.as_deref(), tracks[1].default), (None, false));
+
+        assert!(parse_tracks("{}").is_err());
+        assert!(parse_tracks(r#"[{"url": "https://a/b.vtt"}]"#).is_err());
+        assert!(parse_tracks(r#"[{"url": "https://a/b.vtt", "name": "x", "kind": "cc"}]"#).is_err());
+        assert_eq!(
+            parse_tracks(r#"[{"url": "not a url", "name": "x"}]"#).unwrap_err(),
+            "Invalid subtitle URL: not a url"
+        );
+    }
+
+    #[test]
+    fn refuses_names_that_would_break_out_of_the_attribute() {
+        for name in [r#"En",URI="https://evil.example/x"#, "En\r\n#EXT-X-ENDLIST", "En\nglish", "En\r"] {
+            let json = serde_json::json!([{"url": "https://a.example/en.vtt", "name": name}]).to_string();
+            assert!(parse_tracks(&json).unwrap_err().starts_with("Invalid subtitle name"), "{:?}", name);
+            let json = serde_json::json!([{"url": "https://a.example/en.vtt", "name": "x", "language": name}]).to_string();
+            assert!(parse_tracks(&json).unwrap_err().starts_with("Invalid subtitle language"), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn injects_tracks_into_every_group() {
+        let Playlist::Master(mut master) = Playlist::parse(MASTER) else {
+            panic!("parsed as a media playlist");
+        };
+        let tracks = parse_tracks(
+            r#"[{"url": "https://a.example/de.vtt", "language": "de", "name": "Deutsch", "default": true},
+                {"url": "https://a.example/fr.vtt", "name": "Français", "default": true}]"#,
+        )
+        .unwrap();
+        inject(&mut master, &tracks, |t| format!("/sub?u={}", t.url));
+
+        assert_eq!(
+            master.to_string(),
+            r#"#EXTM3U
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="subs",NAME="English",LANGUAGE="en",DEFAULT=NO,URI="subs/en.m3u8"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="injected-subs",NAME="Deutsch",LANGUAGE="de",DEFAULT=YES,AUTOSELECT=YES,URI="/sub?u=https://a.example/de.vtt"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="injected-subs",NAME="Français",DEFAULT=NO,AUTOSELECT=YES,URI="/sub?u=https://a.example/fr.vtt"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="subs",NAME="Deutsch",LANGUAGE="de",DEFAULT=YES,AUTOSELECT=YES,URI="/sub?u=https://a.example/de.vtt"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID="subs",NAME="Français",DEFAULT=NO,AUTOSELECT=YES,URI="/sub?u=https://a.example/fr.vtt"
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,SUBTITLES="subs"
+720p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=640000,SUBTITLES="injected-subs"
+360p.m3u8"#
+        );
+    }
+
+    #[test]
+    fn builds_a_single_segment_playlist() {
+        assert_eq!(
+            media_playlist(5400.5, "/sub.vtt".to_string()).to_string(),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:5401\n#EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXTINF:5400.500,\n/sub.vtt\n#EXT-X-ENDLIST"
+        );
+    }
+}
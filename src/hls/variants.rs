@@ -0,0 +1,340 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use super::playlist::{Attributes, Line, MasterEntry, MasterPlaylist, Tag};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VariantOrder {
+    // Lowest BANDWIDTH first
+    Asc,
+    // Highest BANDWIDTH first
+    Desc,
+}
+
+// [variants] section of the config file; every option can also be set per
+// request with a query param of the same name (lists comma-separated).
+// Resolutions are "WIDTHxHEIGHT" or just a height ("720" / "720p").
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VariantFilter {
+    pub min_bandwidth: Option<u64>,
+    pub max_bandwidth: Option<u64>,
+    pub min_resolution: Option<String>,
+    pub max_resolution: Option<String>,
+    // Keep only variants whose every codec starts with one of these
+    pub codecs: Vec<String>,
+    // Drop variants using any codec that starts with one of these
+    pub exclude_codecs: Vec<String>,
+    pub max_frame_rate: Option<f64>,
+    // NONE, TYPE-0 or TYPE-1
+    pub max_hdcp_level: Option<String>,
+    pub drop_iframes: bool,
+    pub order: Option<VariantOrder>,
+}
+
+// (width, height) bound; width is None when only a height was given
+type Resolution = (Option<u64>, u64);
+
+fn parse_resolution(value: &str) -> Result<Resolution, String> {
+    let value = value.trim();
+    let parsed = match value.split_once(['x', 'X']) {
+        Some((w, h)) => w.parse().ok().zip(h.parse().ok()).map(|(w, h)| (Some(w), h)),
+        None => value.trim_end_matches('p').parse().ok().map(|h| (None, h)),
+    };
+    parsed.ok_or_else(|| format!("invalid resolution {:?}", value))
+}
+
+fn hdcp_rank(level: &str) -> Option<u8> {
+    match level {
+        "NONE" => Some(0),
+        "TYPE-0" => Some(1),
+        "TYPE-1" => Some(2),
+        _ => None,
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl VariantFilter {
+    pub fn validate(&self) -> Result<(), String> {
+        for resolution in [&self.min_resolution, &self.max_resolution].into_iter().flatten() {
+            parse_resolution(resolution)?;
+        }
+        if let Some(level) = &self.max_hdcp_level {
+            hdcp_rank(level).ok_or_else(|| format!("invalid HDCP level {:?}", level))?;
+        }
+        Ok(())
+    }
+
+    // This filter with any options given in the request query applied on top
+    pub fn with_query(&self, query: &HashMap<String, String>) -> Result<VariantFilter, String> {
+        let mut filter = self.clone();
+        let number = |name: &str| -> Result<Option<u64>, String> {
+            query
+                .get(name)
+                .map(|v| v.trim().parse().map_err(|_| format!("Invalid {}: {}", name, v)))
+                .transpose()
+        };
+
+        if let Some(v) = number("min_bandwidth")? {
+            filter.min_bandwidth = Some(v);
+        }
+        if let Some(v) = number("max_bandwidth")? {
+            filter.max_bandwidth = Some(v);
+        }
+        if let Some(v) = query.get("min_resolution") {
+            filter.min_resolution = Some(v.clone());
+        }
+        if let Some(v) = query.get("max_resolution") {
+            filter.max_resolution = Some(v.clone());
+        }
+        if let Some(v) = query.get("codecs") {
+            filter.codecs = parse_list(v);
+        }
+        if let Some(v) = query.get("exclude_codecs") {
+            filter.exclude_codecs = parse_list(v);
+        }
+        if let Some(v) = query.get("max_frame_rate") {
+            let rate = v.trim().parse().map_err(|_| format!("Invalid max_frame_rate: {}", v))?;
+            filter.max_frame_rate = Some(rate);
+        }
+        if let Some(v) = query.get("max_hdcp_level") {
+            filter.max_hdcp_level = Some(v.trim().to_ascii_uppercase());
+        }
+        if let Some(v) = query.get("drop_iframes") {
+            filter.drop_iframes = v == "true" || v == "1";
+        }
+        if let Some(v) = query.get("order") {
+            filter.order = match v.as_str() {
+                "asc" => Some(VariantOrder::Asc),
+                "desc" => Some(VariantOrder::Desc),
+                _ => return Err(format!("Invalid order: {} (expected asc or desc)", v)),
+            };
+        }
+
+        filter.validate()?;
+        Ok(filter)
+    }
+
+    pub fn is_noop(&self) -> bool {
+        self.min_bandwidth.is_none()
+            && self.max_bandwidth.is_none()
+            && self.min_resolution.is_none()
+            && self.max_resolution.is_none()
+            && self.codecs.is_empty()
+            && self.exclude_codecs.is_empty()
+            && self.max_frame_rate.is_none()
+            && self.max_hdcp_level.is_none()
+            && !self.drop_iframes
+            && self.order.is_none()
+    }
+
+    // Whether a STREAM-INF or I-FRAME-STREAM-INF passes. Attributes a
+    // variant doesn't declare never exclude it.
+    fn accepts(&self, attrs: &Attributes) -> bool {
+        if let Some(bandwidth) = attrs.get_u64("BANDWIDTH") {
+            if self.min_bandwidth.is_some_and(|min| bandwidth < min)
+                || self.max_bandwidth.is_some_and(|max| bandwidth > max)
+            {
+                return false;
+            }
+        }
+
+        if let Some((width, height)) = attrs.get_resolution("RESOLUTION") {
+            let min = self.min_resolution.as_deref().and_then(|r| parse_resolution(r).ok());
+            let max = self.max_resolution.as_deref().and_then(|r| parse_resolution(r).ok());
+            if let Some((min_w, min_h)) = min {
+                if height < min_h || min_w.is_some_and(|w| width < w) {
+                    return false;
+                }
+            }
+            if let Some((max_w, max_h)) = max {
+                if height > max_h || max_w.is_some_and(|w| width > w) {
+                    return false;
+                }
+            }
+        }
+
+        let codecs = attrs.get_list("CODECS");
+        let starts_with_any = |codec: &str, prefixes: &[String]| {
+            let codec = codec.to_ascii_lowercase();
+            prefixes.iter().any(|p| codec.starts_with(&p.to_ascii_lowercase()))
+        };
+        if !self.codecs.is_empty() && !codecs.iter().all(|c| starts_with_any(c, &self.codecs)) {
+            return false;
+        }
+        if codecs.iter().any(|c| starts_with_any(c, &self.exclude_codecs)) {
+            return false;
+        }
+
+        if let (Some(max), Some(rate)) = (self.max_frame_rate, attrs.get_f64("FRAME-RATE")) {
+            if rate > max {
+                return false;
+            }
+        }
+
+        if let Some(max) = self.max_hdcp_level.as_deref().and_then(hdcp_rank) {
+            let level = attrs.get("HDCP-LEVEL").and_then(hdcp_rank).unwrap_or(0);
+            if level > max {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // Drop and reorder variants. A filter that would leave no variant at all
+    // is ignored rather than producing an unplayable playlist.
+    pub fn apply(&self, master: &mut MasterPlaylist) {
+        if self.is_noop() {
+            return;
+        }
+
+        let keeps_a_variant = master.variants().any(|v| self.accepts(&v.stream_inf));
+        if !keeps_a_variant {
+            eprintln!("Variant filter would drop every variant, leaving playlist unfiltered");
+            return;
+        }
+
+        master.entries.retain(|entry| match entry {
+            MasterEntry::Variant(v) => self.accepts(&v.stream_inf),
+            MasterEntry::Line(Line::Tag(Tag::IFrameStreamInf(attrs))) => {
+                !self.drop_iframes && self.accepts(attrs)
+            }
+            _ => true,
+        });
+
+        if let Some(order) = self.order {
+            // Sort the variants among themselves, leaving every other line where it was
+            let slots: Vec<usize> = master
+                .entries
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| matches!(e, MasterEntry::Variant(_)))
+                .map(|(i, _)| i)
+                .collect();
+            let mut variants: Vec<MasterEntry> = slots
+                .iter()
+                .map(|&i| std::mem::replace(&mut master.entries[i], MasterEntry::Line(Line::Blank)))
+                .collect();
+            let bandwidth = |e: &MasterEntry| match e {
+                MasterEntry::Variant(v) => v.bandwidth().unwrap_or(0),
+                MasterEntry::Line(_) => 0,
+            };
+            match order {
+                VariantOrder::Asc => variants.sort_by_key(bandwidth),
+                VariantOrder::Desc => variants.sort_by_key(|e| std::cmp::Reverse(bandwidth(e))),
+            }
+            for (slot, variant) in slots.into_iter().zip(variants) {
+                master.entries[slot] = variant;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hls::playlist::Playlist;
+
+    const MASTER: &str = r#"#EXTM3U
+#EXT-X-INDEPENDENT-SEGMENTS
+#EXT-X-STREAM-INF:BANDWIDTH=2560000,RESOLUTION=1920x1080,CODECS="hvc1.2.4.L123.B0,mp4a.40.2",FRAME-RATE=59.940,HDCP-LEVEL=TYPE-1
+1080p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=640000,RESOLUTION=640x360,CODECS="avc1.42e01e,mp4a.40.2"
+360p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,RESOLUTION=1280x720,CODECS="avc1.64001f,mp4a.40.2",FRAME-RATE=29.970,HDCP-LEVEL=NONE
+720p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=96000,CODECS="mp4a.40.2"
+audio.m3u8
+#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH=86000,RESOLUTION=640x360,URI="iframe.m3u8""#;
+
+    fn filter(query: &[(&str, &str)]) -> VariantFilter {
+        let query = query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        VariantFilter::default().with_query(&query).unwrap()
+    }
+
+    // URIs of what is left, in order
+    fn kept(filter: &VariantFilter) -> Vec<String> {
+        let Playlist::Master(mut master) = Playlist::parse(MASTER) else {
+            panic!("parsed as a media playlist");
+        };
+        filter.apply(&mut master);
+        let mut uris: Vec<String> = master.variants().map(|v| v.uri.clone()).collect();
+        if master.to_string().contains("iframe.m3u8") {
+            uris.push("iframe.m3u8".to_string());
+        }
+        uris
+    }
+
+    #[test]
+    fn parses_resolutions() {
+        assert_eq!(parse_resolution("1280x720"), Ok((Some(1280), 720)));
+        assert_eq!(parse_resolution(" 1920X1080 "), Ok((Some(1920), 1080)));
+        assert_eq!(parse_resolution("720"), Ok((None, 720)));
+        assert_eq!(parse_resolution("720p"), Ok((None, 720)));
+        for invalid in ["", "hd", "1280x", "x720", "1280x720p", "-720"] {
+            assert!(parse_resolution(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn filters_by_resolution() {
+        assert_eq!(kept(&filter(&[("max_resolution", "720p")])), ["360p.m3u8", "720p.m3u8", "audio.m3u8", "iframe.m3u8"]);
+        assert_eq!(kept(&filter(&[("min_resolution", "1280x720")])), ["1080p.m3u8", "720p.m3u8", "audio.m3u8"]);
+        // Both dimensions count when a width is given
+        assert_eq!(kept(&filter(&[("max_resolution", "1000x1080")])), ["360p.m3u8", "audio.m3u8", "iframe.m3u8"]);
+    }
+
+    #[test]
+    fn filters_by_bandwidth_codecs_frame_rate_and_hdcp() {
+        assert_eq!(
+            kept(&filter(&[("min_bandwidth", "100000"), ("max_bandwidth", "2000000")])),
+            ["360p.m3u8", "720p.m3u8"]
+        );
+        assert_eq!(kept(&filter(&[("codecs", "avc1, mp4a")])), ["360p.m3u8", "720p.m3u8", "audio.m3u8", "iframe.m3u8"]);
+        assert_eq!(kept(&filter(&[("exclude_codecs", "HVC1")])), ["360p.m3u8", "720p.m3u8", "audio.m3u8", "iframe.m3u8"]);
+        assert_eq!(kept(&filter(&[("max_frame_rate", "30")])), ["360p.m3u8", "720p.m3u8", "audio.m3u8", "iframe.m3u8"]);
+        assert_eq!(kept(&filter(&[("max_hdcp_level", "type-0")])), ["360p.m3u8", "720p.m3u8", "audio.m3u8", "iframe.m3u8"]);
+        assert_eq!(kept(&filter(&[("drop_iframes", "1")])), ["1080p.m3u8", "360p.m3u8", "720p.m3u8", "audio.m3u8"]);
+    }
+
+    #[test]
+    fn orders_variants_by_bandwidth() {
+        assert_eq!(kept(&filter(&[("order", "asc")])), ["audio.m3u8", "360p.m3u8", "720p.m3u8", "1080p.m3u8", "iframe.m3u8"]);
+        assert_eq!(kept(&filter(&[("order", "desc")])), ["1080p.m3u8", "720p.m3u8", "360p.m3u8", "audio.m3u8", "iframe.m3u8"]);
+    }
+
+    #[test]
+    fn serves_the_playlist_unfiltered_when_every_variant_would_go() {
+        let all = ["1080p.m3u8", "360p.m3u8", "720p.m3u8", "audio.m3u8", "iframe.m3u8"];
+        assert_eq!(kept(&filter(&[("min_bandwidth", "5000000")])), all);
+        assert_eq!(kept(&filter(&[("codecs", "av01")])), all);
+        // Ordering is skipped along with the filter
+        assert_eq!(kept(&filter(&[("min_bandwidth", "5000000"), ("order", "asc")])), all);
+        assert_eq!(kept(&VariantFilter::default()), all);
+    }
+
+    #[test]
+    fn rejects_invalid_query_options() {
+        let query = |k: &str, v: &str| HashMap::from([(k.to_string(), v.to_string())]);
+        let base = VariantFilter::default();
+        assert_eq!(base.with_query(&query("min_bandwidth", "lots")).unwrap_err(), "Invalid min_bandwidth: lots");
+        assert!(base.with_query(&query("max_resolution", "big")).is_err());
+        assert!(base.with_query(&query("max_hdcp_level", "TYPE-2")).is_err());
+        assert!(base.with_query(&query("order", "random")).is_err());
+        assert!(base.with_query(&query("max_frame_rate", "fast")).is_err());
+
+        // Query options override the configured ones
+        let configured = VariantFilter { max_bandwidth: Some(1), ..Default::default() };
+        assert_eq!(configured.with_query(&query("max_bandwidth", "700000")).unwrap().max_bandwidth, Some(700000));
+    }
+}
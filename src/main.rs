@@ -1,35 +1,36 @@
 use actix_web::{
-    get, http::header, middleware::Compress, web::Bytes, App, HttpRequest, HttpResponse,
+    body::SizedStream, get, http::header, middleware::Compress, web, App, HttpRequest, HttpResponse,
     HttpServer, Responder, http::Method,
 };
-use futures_util::{stream::StreamExt};
+use futures_util::stream::{self, StreamExt};
 use once_cell::sync::Lazy;
-use reqwest::{
-    header::{HeaderName, HeaderValue},
-    Client,
-};
-use std::{collections::HashMap, str::FromStr};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 use url::Url;
 use tokio::task;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use errors::ProxyError;
+use upstream::CLIENT;
+use hls::{
+    delta,
+    playlist::{MasterPlaylist, Playlist},
+    renditions::RenditionFilter,
+    stream::{rewrite_body, sniff_m3u8, LineRewriter},
+    subtitles::{self, SubtitleTrack},
+    variants::VariantFilter,
+};
 
+mod cache;
+mod config;
+mod cors;
+mod errors;
+mod hls;
+mod policy;
+mod prefetch;
+mod sessions;
+mod signing;
+mod ssrf;
 mod templates;
-
-// Allowed origins - more permissive for production
-static ALLOWED_ORIGINS: Lazy<[&str; 2]> = Lazy::new(|| [
-    "http://localhost:5173",
-    "http://localhost:3000"
-]);
-
-// Reqwest client pool
-static CLIENT: Lazy<Client> = Lazy::new(|| {
-    Client::builder()
-        .pool_idle_timeout(std::time::Duration::from_secs(90))
-        .http2_adaptive_window(true)
-        .pool_max_idle_per_host(10)
-        .danger_accept_invalid_certs(true)  // Accept invalid SSL certificates
-        .build()
-        .expect("Failed to build reqwest client")
-});
+mod upstream;
 
 static ENABLE_CORS: Lazy<bool> = Lazy::new(|| {
     std::env::var("ENABLE_CORS")
@@ -37,43 +38,32 @@ static ENABLE_CORS: Lazy<bool> = Lazy::new(|| {
         .unwrap_or(false)
 });
 
-fn validate_url(url: &str) -> Result<String, HttpResponse> {
+// LL-HLS delivery directives players append to playlist requests. They are
+// forwarded to the upstream, which holds the response until the requested
+// segment/part exists, so blocking reloads work through the proxy.
+const HLS_DIRECTIVES: [&str; 3] = ["_HLS_msn", "_HLS_part", "_HLS_skip"];
+
+fn validate_url(url: &str) -> Result<String, String> {
     let url = url.trim();
     
     // Just validate it's a proper URL
     if Url::parse(url).is_ok() {
         Ok(url.to_string())
     } else {
-        Err(HttpResponse::BadRequest().body(format!("Invalid URL: {}", url)))
+        Err(format!("Invalid URL: {}", url))
     }
 }
 
-// Check if request has valid Origin or Referer - more permissive
+// Check if request has an allowed Origin, falling back to the Referer's origin
 fn get_valid_origin(req: &HttpRequest) -> Option<String> {
     if !*ENABLE_CORS {
         return None;
     }
 
-    if let Some(origin) = req.headers().get(header::ORIGIN) {
-        if let Ok(origin_str) = origin.to_str() {
-            if ALLOWED_ORIGINS.contains(&origin_str) {
-                return Some(origin_str.to_string());
-            }
-        }
-    }
-
-    if let Some(referer) = req.headers().get(header::REFERER) {
-        if let Ok(referer_str) = referer.to_str() {
-            if let Some(allowed) = ALLOWED_ORIGINS
-                .iter()
-                .find(|origin| referer_str.starts_with(*origin))
-            {
-                return Some((*allowed).to_string());
-            }
-        }
-    }
-
-    None
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    config::current()
+        .allowed_origins
+        .match_request(header(header::ORIGIN), header(header::REFERER))
 }
 
 fn get_url(line: &str, base: &Url) -> Url {
@@ -83,130 +73,264 @@ fn get_url(line: &str, base: &Url) -> Url {
     base.join(line).unwrap_or_else(|_| base.clone())
 }
 
-#[inline]
-fn process_m3u8_line(
-    line: &str,
-    scrape_url: &Url,
-    origin_param: &Option<String>,  
-) -> String {
-    if line.is_empty() {
-        return String::new();
+// JSON params (`headers`, `subtitles`) are either raw JSON or, as written
+// into rewritten playlists, base64url-encoded JSON. Returns the JSON text.
+fn decode_json_param(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.starts_with('{') || value.starts_with('[') {
+        return Some(value.to_string());
+    }
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+}
+
+// Compact form of the headers JSON for child URLs - avoids percent-encoding every brace and quote
+fn encode_headers_param(json: &str) -> String {
+    URL_SAFE_NO_PAD.encode(json.as_bytes())
+}
+
+// Per-playlist state carried into every rewritten child URL
+struct RewriteContext<'a> {
+    scrape_url: &'a Url,
+    origin_param: Option<&'a str>,
+    // Already in compact encoded form
+    headers_param: Option<&'a str>,
+    session: Option<&'a sessions::Session>,
+    config: &'a config::Config,
+}
+
+// Query string for a proxied child URL: url, origin, headers and, in signing mode, expires/sig
+fn proxy_query(resolved: &Url, ctx: &RewriteContext) -> String {
+    let mut new_q = String::with_capacity(resolved.as_str().len() + 50);
+    new_q.push_str("url=");
+    new_q.push_str(&urlencoding::encode(resolved.as_str()));
+    if let Some(o) = ctx.origin_param {
+        new_q.push_str("&origin=");
+        new_q.push_str(&urlencoding::encode(o));
+    }
+    if let Some(h) = ctx.headers_param {
+        new_q.push_str("&headers=");
+        new_q.push_str(h);
+    }
+    if let Some(signature) = ctx.config.signing.sign_child(resolved.as_str(), ctx.origin_param, ctx.headers_param) {
+        new_q.push_str(&signature);
+    }
+    new_q
+}
+
+// Proxy URL written into a rewritten playlist for an upstream URL
+fn proxy_url(resolved: &Url, ctx: &RewriteContext) -> String {
+    if let Some(session) = ctx.session {
+        return session.path_for(resolved);
+    }
+    let new_q = proxy_query(resolved, ctx);
+    let mut url = String::with_capacity(new_q.len() + 2);
+    url.push_str("/?");
+    url.push_str(&new_q);
+    url
+}
+
+// Proxy URL for a URI as written in the playlist. Fails if it targets a
+// host the host policy refuses.
+fn rewrite_uri(uri: &str, ctx: &RewriteContext) -> Result<String, String> {
+    let resolved = get_url(uri, ctx.scrape_url);
+    ctx.config.check_upstream(&resolved)?;
+    Ok(proxy_url(&resolved, ctx))
+}
+
+// Point every URI in the playlist back at the proxy
+fn rewrite_playlist(playlist: &mut Playlist, ctx: &RewriteContext) -> Result<(), String> {
+    playlist.try_rewrite_uris(|uri| rewrite_uri(uri, ctx))
+}
+
+// Copy the client's _HLS_* directives onto the upstream URL, replacing any
+// already there, in the order the spec lists them
+fn apply_hls_directives(url: &mut Url, client_query: &str) {
+    let directives: Vec<(&str, String)> = HLS_DIRECTIVES
+        .iter()
+        .filter_map(|&name| {
+            client_query.split('&').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                if key != name {
+                    return None;
+                }
+                Some((name, urlencoding::decode(value).ok()?.into_owned()))
+            })
+        })
+        .collect();
+    if directives.is_empty() {
+        return;
     }
+
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| !HLS_DIRECTIVES.contains(&k.as_ref()))
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    let mut pairs = url.query_pairs_mut();
+    pairs.clear();
+    for (k, v) in &kept {
+        pairs.append_pair(k, v);
+    }
+    for (k, v) in &directives {
+        pairs.append_pair(k, v);
+    }
+}
+
+// Length of the stream in seconds, from the first variant's media playlist
+async fn stream_duration(
+    master: &MasterPlaylist,
+    base: &Url,
+    headers: &reqwest::header::HeaderMap,
+    config: &config::Config,
+) -> Option<f64> {
+    let url = get_url(&master.variants().next()?.uri, base);
+    ssrf::check_url(&url, &config.ssrf).ok()?;
+    config.check_upstream(&url).ok()?;
+    let resp = CLIENT.get(url.as_str()).headers(headers.clone()).send().await.ok()?;
+    let text = resp.error_for_status().ok()?.text().await.ok()?;
+    match Playlist::parse(&text) {
+        Playlist::Media(media) => Some(media.total_duration()).filter(|d| *d > 0.0),
+        Playlist::Master(_) => None,
+    }
+}
+
+// 200 response for a playlist the proxy rewrote or generated
+fn playlist_response(acao: Option<String>, body: String) -> HttpResponse {
+    playlist_response_builder(acao).body(body)
+}
+
+fn playlist_response_builder(acao: Option<String>) -> actix_web::HttpResponseBuilder {
+    let mut builder = HttpResponse::Ok();
+    builder
+        .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, acao.unwrap_or("*".to_string())))
+        .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, OPTIONS, HEAD"))
+        .insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, Authorization, Range, Origin, Accept, Accept-Encoding, Accept-Language, Cache-Control, Pragma, Sec-Fetch-Dest, Sec-Fetch-Mode, Sec-Fetch-Site, Sec-Ch-Ua, Sec-Ch-Ua-Mobile, Sec-Ch-Ua-Platform, Connection"))
+        .insert_header((header::ACCESS_CONTROL_EXPOSE_HEADERS, "Content-Length, Content-Range, Accept-Ranges, Content-Type, Cache-Control, Expires, Vary, ETag, Last-Modified"))
+        .insert_header((header::CROSS_ORIGIN_RESOURCE_POLICY, "cross-origin"))
+        .insert_header(("Vary", "Origin"))
+        .content_type("application/vnd.apple.mpegurl")
+        .insert_header((header::CACHE_CONTROL, "no-cache, no-store, must-revalidate"));
+    builder
+}
+
+// reqwest and actix-web are built on different versions of the http crate
+fn actix_status(status: reqwest::StatusCode) -> actix_web::http::StatusCode {
+    actix_web::http::StatusCode::from_u16(status.as_u16()).unwrap_or(actix_web::http::StatusCode::BAD_GATEWAY)
+}
+
+// Response passing an upstream body through: CORS headers plus the upstream's
+// status and content headers
+fn passthrough_response(
+    status: reqwest::StatusCode,
+    acao: Option<String>,
+    upstream_headers: &reqwest::header::HeaderMap,
+) -> actix_web::HttpResponseBuilder {
+    let mut response_builder = HttpResponse::build(actix_status(status));
     
-    let first_char = unsafe { line.as_bytes().get_unchecked(0) };
+    // Set CORS headers for all responses - more permissive
+    response_builder.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, acao.unwrap_or("*".to_string())));
+    response_builder.insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, OPTIONS, HEAD"));
+    response_builder.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, Authorization, Range, Origin, Accept, Accept-Encoding, Accept-Language, Cache-Control, Pragma, Sec-Fetch-Dest, Sec-Fetch-Mode, Sec-Fetch-Site, Sec-Ch-Ua, Sec-Ch-Ua-Mobile, Sec-Ch-Ua-Platform, Connection"));
+    response_builder.insert_header((header::ACCESS_CONTROL_EXPOSE_HEADERS, "Content-Length, Content-Range, Accept-Ranges, Content-Type, Cache-Control, Expires, Vary, ETag, Last-Modified"));
+    response_builder.insert_header((header::CROSS_ORIGIN_RESOURCE_POLICY, "cross-origin"));
+    response_builder.insert_header(("Vary", "Origin"));
     
-    if (*first_char) == b'#' {
-        // Comment line processing
-        if line.len() > 11 && line.as_bytes()[10] == b'K' && line.starts_with("#EXT-X-KEY") {
-            // #EXT-X-KEY processing
-            if let Some(uri_start) = line.find("URI=\"") {
-                let key_uri_start = uri_start + 5;
-                if let Some(quote_pos) = line[key_uri_start..].find('"') {
-                    let key_uri_end = key_uri_start + quote_pos;
-                    let key_uri = &line[key_uri_start..key_uri_end];
-                    let resolved = get_url(key_uri, scrape_url);
-                    
-                    let mut new_q = String::with_capacity(resolved.as_str().len() + 50);
-                    new_q.push_str("url=");
-                    new_q.push_str(&urlencoding::encode(resolved.as_str()));
-                    if let Some(o) = origin_param {
-                        new_q.push_str("&origin=");
-                        new_q.push_str(o);
-                    }
-                    
-                    let mut result = String::with_capacity(line.len() + new_q.len());
-                    result.push_str(&line[..key_uri_start]);
-                    result.push_str("/?");
-                    result.push_str(&new_q);
-                    result.push_str(&line[key_uri_end..]);
-                    return result;
-                }
-            }
-            return line.to_string();
+    // Copy important headers from the original response
+    for (name, value) in upstream_headers.iter() {
+        let header_name = name.as_str().to_lowercase();
+        if header_name == "content-type" 
+            || header_name == "content-length" 
+            || header_name == "content-range"
+            || header_name == "accept-ranges"
+            || header_name == "cache-control"
+            || header_name == "expires"
+            || header_name == "last-modified"
+            || header_name == "etag"
+            || header_name == "content-encoding"
+            || header_name == "vary" {
+            response_builder.insert_header((name.as_str(), value.as_bytes()));
         }
-        
-        if line.len() > 16 && line.starts_with("#EXT-X-MAP:URI=\"") {
-            // #EXT-X-MAP processing
-            let inner_url = &line[16..line.len()-1]; // Remove prefix and trailing quote
-            let resolved = get_url(inner_url, scrape_url);
-            
-            let mut new_q = String::with_capacity(resolved.as_str().len() + 50);
-            new_q.push_str("url=");
-            new_q.push_str(&urlencoding::encode(resolved.as_str()));
-            if let Some(o) = origin_param {
-                new_q.push_str("&origin=");
-                new_q.push_str(o);
-            }
-            
-            let mut fixed = String::from("#EXT-X-MAP:URI=\"/?");
-            fixed.push_str(&new_q);
-            fixed.push('"');
-            return fixed;
+    }
+    response_builder
+}
+
+// Debug header with the URLs the upstream fetch was redirected to, in order,
+// when [redirects] expose_chain is set
+fn add_redirect_chain(response: &mut HttpResponse, chain: &[Url]) {
+    if chain.is_empty() || !config::current().redirects.expose_chain {
+        return;
+    }
+    let hops: Vec<&str> = chain.iter().map(Url::as_str).collect();
+    if let (Ok(name), Ok(value)) = (
+        header::HeaderName::from_bytes(ssrf::REDIRECT_CHAIN_HEADER.as_bytes()),
+        header::HeaderValue::from_str(&hops.join(" ")),
+    ) {
+        response.headers_mut().insert(name, value);
+    }
+}
+
+// Response for a cache hit, with the upstream headers it was stored with,
+// cut down to the client's Range if it sent one
+fn cached_response(cached: cache::Hit, range: Option<&str>, acao: Option<String>) -> HttpResponse {
+    let age = cached.age().to_string();
+    let len = cached.size();
+    match range.map_or(cache::ByteRange::Whole, |r| cache::ByteRange::parse(r, len)) {
+        cache::ByteRange::Whole => passthrough_response(cached.status(), acao, cached.headers())
+            .insert_header((header::AGE, age))
+            .insert_header((cache::STATUS_HEADER, "HIT"))
+            .body(SizedStream::new(len, cached.body(0..len))),
+        cache::ByteRange::Part(first, last) => {
+            let mut headers = cached.headers().clone();
+            headers.remove("content-length");
+            passthrough_response(reqwest::StatusCode::PARTIAL_CONTENT, acao, &headers)
+                .insert_header((header::CONTENT_RANGE, format!("bytes {}-{}/{}", first, last, len)))
+                .insert_header((header::ACCEPT_RANGES, "bytes"))
+                .insert_header((header::AGE, age))
+                .insert_header((cache::STATUS_HEADER, "HIT"))
+                .body(SizedStream::new(last + 1 - first, cached.body(first..last + 1)))
         }
-        
-        // Generic URI/URL processing for other tags
-        if line.len() > 20 && (line.contains("URI=") || line.contains("URL=")) {
-            if let Some(colon_pos) = line.find(':') {
-                let prefix = &line[..colon_pos + 1];
-                let attrs = &line[colon_pos + 1..];
-                
-                let mut result = String::with_capacity(line.len() + 100);
-                result.push_str(prefix);
-                
-                let mut first_attr = true;
-                for attr in attrs.split(',') {
-                    if !first_attr {
-                        result.push(',');
-                    }
-                    first_attr = false;
-                    
-                    if let Some(eq_pos) = attr.find('=') {
-                        let key = attr[..eq_pos].trim();
-                        let value = attr[eq_pos + 1..].trim().trim_matches('"');
-                        
-                        if key == "URI" || key == "URL" {
-                            let resolved = get_url(value, scrape_url);
-                            
-                            let mut new_q = String::with_capacity(resolved.as_str().len() + 50);
-                            new_q.push_str("url=");
-                            new_q.push_str(&urlencoding::encode(resolved.as_str()));
-                            if let Some(o) = origin_param {
-                                new_q.push_str("&origin=");
-                                new_q.push_str(o);
-                            }
-                            
-                            result.push_str(key);
-                            result.push_str("=\"/?");
-                            result.push_str(&new_q);
-                            result.push('"');
-                        } else {
-                            result.push_str(attr);
-                        }
-                    } else {
-                        result.push_str(attr);
-                    }
-                }
-                return result;
-            }
+        cache::ByteRange::Unsatisfiable => {
+            passthrough_response(reqwest::StatusCode::RANGE_NOT_SATISFIABLE, acao, &Default::default())
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", len)))
+                .insert_header((cache::STATUS_HEADER, "HIT"))
+                .finish()
         }
-        
-        return line.to_string();
     }
-    
-    // URL line processing
-    let resolved = get_url(line, scrape_url);
-    let mut new_q = String::with_capacity(resolved.as_str().len() + 50);
-    new_q.push_str("url=");
-    new_q.push_str(&urlencoding::encode(resolved.as_str()));
-    if let Some(o) = origin_param {
-        new_q.push_str("&origin=");
-        new_q.push_str(&urlencoding::encode(o));
+}
+
+// Response for a rewritten playlist served from the playlist cache
+fn cached_playlist(cached: cache::CachedResponse, acao: Option<String>) -> HttpResponse {
+    let age = cached.age();
+    playlist_response_builder(acao)
+        .insert_header((header::AGE, age.to_string()))
+        .insert_header((cache::STATUS_HEADER, "HIT"))
+        .body(cached.body)
+}
+
+// Generated playlist for an injected subtitle track: the whole WebVTT file as one segment
+fn subtitle_playlist(acao: Option<String>, target: &UpstreamTarget, duration: f64) -> HttpResponse {
+    let vtt_url = match Url::parse(&target.url) {
+        Ok(u) => u,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid URL: {}", e)),
+    };
+    let config = config::current();
+    if let Err(reason) = config.check_upstream(&vtt_url) {
+        return HttpResponse::Forbidden().body(reason);
     }
 
-    let mut result = String::with_capacity(new_q.len() + 10);
-    result.push_str("/?");
-    result.push_str(&new_q);
-    result
+    let headers_param = target.headers.as_deref().map(encode_headers_param);
+    let ctx = RewriteContext {
+        scrape_url: &vtt_url,
+        origin_param: target.origin.as_deref(),
+        headers_param: headers_param.as_deref(),
+        session: None,
+        config: &config,
+    };
+    let playlist = subtitles::media_playlist(duration, proxy_url(&vtt_url, &ctx));
+    playlist_response(acao, playlist.to_string())
 }
 
 // Handle CORS preflight requests - more permissive
@@ -230,6 +354,23 @@ async fn handle_options(req: HttpRequest) -> impl Responder {
         .finish()
 }
 
+// What to fetch upstream and with which origin/headers context
+struct UpstreamTarget {
+    url: String,
+    origin: Option<String>,
+    // Custom upstream headers as JSON text
+    headers: Option<String>,
+    // Set when the request came in through an opaque /s/ session URL
+    session: Option<Arc<sessions::Session>>,
+    // Applied if the upstream turns out to be a master playlist
+    variant_filter: VariantFilter,
+    rendition_filter: RenditionFilter,
+    // External WebVTT tracks to add to a master playlist
+    subtitles: Vec<SubtitleTrack>,
+    // Remove ad segments from media playlists
+    strip_ads: bool,
+}
+
 #[get("/")]
 async fn m3u8_proxy(req: HttpRequest) -> impl Responder {
     // Parallel query parsing
@@ -263,42 +404,261 @@ async fn m3u8_proxy(req: HttpRequest) -> impl Responder {
         return HttpResponse::Forbidden().finish();
     }
 
+    let config = config::current();
+
+    // In signing mode only URLs we (or the operator's backend) signed are proxied
+    if let Some(url) = query.get("url") {
+        if let Err(reason) = config.signing.verify(
+            url,
+            query.get("origin").map(String::as_str),
+            query.get("headers").map(String::as_str),
+            query.get("expires").map(String::as_str),
+            query.get("sig").map(String::as_str),
+        ) {
+            return HttpResponse::Forbidden().body(reason);
+        }
+    }
+
     // Get and validate the URL
     let target_url = match query.get("url") {
         Some(u) => match validate_url(u) {
             Ok(validated) => validated,
-            Err(resp) => return resp,
+            Err(msg) => return HttpResponse::BadRequest().body(msg),
         },
         None => return HttpResponse::BadRequest().body("Missing URL"),
     };
 
-    let target_url_parsed = match Url::parse(&target_url) {
+    let variant_filter = match config.variants.with_query(&query) {
+        Ok(f) => f,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
+    let rendition_filter = config.renditions.with_query(&query);
+
+    let subtitles = match query.get("subtitles").map(|v| decode_json_param(v).ok_or("Invalid subtitles")) {
+        Some(Ok(json)) => match subtitles::parse_tracks(&json) {
+            Ok(tracks) => tracks,
+            Err(msg) => return HttpResponse::BadRequest().body(msg),
+        },
+        Some(Err(msg)) => return HttpResponse::BadRequest().body(msg),
+        None => Vec::new(),
+    };
+    // The proxy signs the subtitle URLs it writes into the playlist, and these come from the client
+    if config.signing.enabled() && !subtitles.is_empty() {
+        return HttpResponse::Forbidden().body("Subtitles can't be added to signed URLs");
+    }
+
+    let target = UpstreamTarget {
+        url: target_url,
+        origin: query.get("origin").cloned(),
+        headers: query.get("headers").and_then(|h| decode_json_param(h)),
+        session: None,
+        variant_filter,
+        rendition_filter,
+        subtitles,
+        strip_ads: query
+            .get("strip_ads")
+            .map_or(config.ad_filter.enabled(), |v| v == "true" || v == "1"),
+    };
+
+    // Injected subtitle tracks point here; nothing is fetched until the player asks for the .vtt
+    if let Some(duration) = query.get("vtt_duration") {
+        return match duration.parse::<f64>() {
+            Ok(d) if d > 0.0 && d.is_finite() => subtitle_playlist(acao, &target, d),
+            _ => HttpResponse::BadRequest().body(format!("Invalid vtt_duration: {}", duration)),
+        };
+    }
+
+    proxy_target(&req, acao, target).await
+}
+
+// Opaque child URLs handed out in session mode: /s/<session>/<n>[.<ext>]
+#[get("/s/{session}/{item}")]
+async fn session_proxy(req: HttpRequest, path: web::Path<(String, String)>) -> impl Responder {
+    let acao = get_valid_origin(&req);
+
+    if *ENABLE_CORS && acao.is_none() {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let (session_id, item) = path.into_inner();
+    let config = config::current();
+    let Some(session) = sessions::get(&session_id) else {
+        return HttpResponse::NotFound().body("Unknown or expired session");
+    };
+    let Some(url) = sessions::parse_item(&item).and_then(|n| session.url(n)) else {
+        return HttpResponse::NotFound().body("Unknown session item");
+    };
+
+    let target = UpstreamTarget {
+        url,
+        origin: session.origin.clone(),
+        headers: session.headers.clone(),
+        session: Some(session),
+        variant_filter: config.variants.clone(),
+        rendition_filter: config.renditions.clone(),
+        subtitles: Vec::new(),
+        strip_ads: config.ad_filter.enabled(),
+    };
+    proxy_target(&req, acao, target).await
+}
+
+// In session mode children of a playlist share its session (or start a new one)
+fn playlist_session(target: &UpstreamTarget, config: &config::Config) -> Option<Arc<sessions::Session>> {
+    target.session.clone().or_else(|| {
+        config.sessions.enabled.then(|| sessions::create(target.origin.clone(), target.headers.clone()))
+    })
+}
+
+// Whether something has to see the playlist as a whole before it goes out;
+// otherwise it is rewritten line by line as it streams in. URIs a host
+// policy could refuse have to be checked before the status goes out.
+fn needs_whole_playlist(target: &UpstreamTarget, expand_deltas: bool, config: &config::Config) -> bool {
+    expand_deltas
+        || !config.host_policy.is_open()
+        || target.strip_ads
+        || !target.subtitles.is_empty()
+        || !target.variant_filter.is_noop()
+        || !target.rendition_filter.is_noop()
+}
+
+// Key for the rewritten output of this fetch, if the playlist cache may serve
+// or keep it. Filters and subtitles only touch master playlists, which are
+// never kept, so they are left out.
+fn playlist_cache_key(
+    target: &UpstreamTarget,
+    url: &Url,
+    expand_deltas: bool,
+    headers: &reqwest::header::HeaderMap,
+    config: &config::Config,
+) -> Option<String> {
+    if !config.cache.playlists || !cache::cacheable_request(headers) {
+        return None;
+    }
+    // Each of these requests starts a session of its own, with its own URLs
+    if config.sessions.enabled && target.session.is_none() {
+        return None;
+    }
+    // In expand mode the proxy decides when to ask for a delta, so the skip doesn't count
+    let url = if expand_deltas { delta::without_skip(url) } else { url.clone() };
+    let rewrite = [
+        ("origin", target.origin.as_deref()),
+        ("headers", target.headers.as_deref()),
+        ("session", target.session.as_ref().map(|s| s.id.as_str())),
+        ("strip_ads", target.strip_ads.then_some("1")),
+    ];
+    Some(cache::playlist::key(&url, headers, &rewrite))
+}
+
+// Rewrite a playlist line by line while the upstream sends it, without
+// holding the whole body
+async fn stream_playlist(
+    resp: upstream::Upstream,
+    ct_is_m3u8: bool,
+    acao: Option<String>,
+    target: &UpstreamTarget,
+    config: Arc<config::Config>,
+    request_id: &str,
+    playlist_key: Option<String>,
+) -> HttpResponse {
+    let status = resp.status;
+    let upstream_headers = resp.headers;
+    // Relative URIs resolve against where the upstream redirected us
+    let scrape_url = resp.url;
+    let target_url = scrape_url.to_string();
+    let mut body = resp.body;
+
+    // A body only guessed to be a playlist from its .m3u8 path is checked first
+    let (is_m3u8, prefix) = if ct_is_m3u8 {
+        (true, Vec::new())
+    } else {
+        match sniff_m3u8(&mut body).await {
+            Ok(sniffed) => sniffed,
+            Err(e) => return ProxyError::from_reqwest(&e).for_target(&scrape_url).response(request_id, acao),
+        }
+    };
+    if !is_m3u8 {
+        let preview: String = String::from_utf8_lossy(&prefix).chars().take(200).collect();
+        eprintln!(
+            "Non-m3u8 body for URL ending with .m3u8 (status: {}): preview=\"{}\"",
+            status.as_u16(),
+            preview.replace('\n', "\\n")
+        );
+    }
+    let body = stream::iter([Ok(bytes::Bytes::from(prefix))]).chain(body);
+    if !is_m3u8 {
+        return passthrough_response(status, acao, &upstream_headers)
+            .streaming(body.map(|chunk| chunk.map_err(std::io::Error::other)));
+    }
+
+    let origin_param = target.origin.clone();
+    let headers_param = target.headers.as_deref().map(encode_headers_param);
+    let session = playlist_session(target, &config);
+    let limit = config.cache.max_entry_bytes;
+    let rewriter = LineRewriter::new(move |uri: &str| {
+        let ctx = RewriteContext {
+            scrape_url: &scrape_url,
+            origin_param: origin_param.as_deref(),
+            headers_param: headers_param.as_deref(),
+            session: session.as_deref(),
+            config: &config,
+        };
+        rewrite_uri(uri, &ctx).inspect_err(|reason| eprintln!("Refusing playlist {}: {}", target_url, reason))
+    });
+    let body = rewrite_body(body, rewriter);
+    match playlist_key {
+        Some(key) => playlist_response_builder(acao)
+            .insert_header((cache::STATUS_HEADER, "MISS"))
+            .streaming(cache::Recorder::new(Box::pin(body), limit, move |body| {
+                cache::playlist::store(key, body)
+            })),
+        None => playlist_response_builder(acao).streaming(body),
+    }
+}
+
+async fn proxy_target(req: &HttpRequest, acao: Option<String>, target: UpstreamTarget) -> HttpResponse {
+    let request_id = errors::request_id(req);
+    let mut target_url_parsed = match Url::parse(&target.url) {
         Ok(u) => u,
         Err(e) => return HttpResponse::BadRequest().body(format!("Invalid URL: {}", e)),
     };
+    apply_hls_directives(&mut target_url_parsed, req.query_string());
+
+    let config = config::current();
+
+    // In expand mode the proxy decides when to ask for a delta update, not the client
+    let delta_key = config.delta_playlists.expand().then(|| delta::cache_key(&target_url_parsed));
+    if let Some(key) = &delta_key {
+        target_url_parsed = delta::without_skip(&target_url_parsed);
+        if delta::should_request_delta(key) {
+            target_url_parsed.query_pairs_mut().append_pair("_HLS_skip", "YES");
+        }
+    }
+    let target_url = target_url_parsed.to_string();
+
+    // Refuse non-http(s) schemes and internal IP literals before any fetch
+    if let Err(e) = ssrf::check_url(&target_url_parsed, &config.ssrf) {
+        return ProxyError::blocked(e.to_string()).for_target(&target_url_parsed).response(&request_id, acao);
+    }
+
+    // Only fetch from hosts the host policy allows
+    if let Err(reason) = config.check_upstream(&target_url_parsed) {
+        return ProxyError::blocked(reason).for_target(&target_url_parsed).response(&request_id, acao);
+    }
 
     // Parallel header processing
     let headers_future = task::spawn_blocking({
         let target_url_parsed = target_url_parsed.clone();
-        let query = query.clone();
+        let origin_param = target.origin.clone();
+        let header_json = target.headers.clone();
+        let config = config.clone();
         move || {
             // Use custom origin for upstream request if provided in query (for top-level fetch only)
-            let origin_param = query.get("origin").map(|s| s.as_str());
-            let mut headers = templates::generate_headers_for_url(&target_url_parsed, origin_param);
-
-            // Custom headers support
-            if let Some(header_json) = query.get("headers") {
-                if let Ok(parsed) = serde_json::from_str::<HashMap<String, String>>(&header_json) {
-                    for (k, v) in parsed {
-                        if let (Ok(name), Ok(value)) = (
-                            HeaderName::from_str(&k),
-                            HeaderValue::from_str(&v),
-                        ) {
-                            headers.insert(name, value);
-                        }
-                    }
-                }
-            }
+            let headers = templates::upstream_headers(
+                &config.templates,
+                &target_url_parsed,
+                origin_param.as_deref(),
+                header_json.as_deref(),
+            );
 
             // Debug: show chosen origin/referer for upstream
             let dbg_origin = headers.get("origin").and_then(|v| v.to_str().ok()).unwrap_or("-");
@@ -315,30 +675,64 @@ async fn m3u8_proxy(req: HttpRequest) -> impl Responder {
     };
 
     // Copy important headers from client request
-    if let Some(range) = req.headers().get("Range") {
-        headers.insert("Range", range.clone());
+    for name in ["Range", "If-Range", "If-None-Match", "If-Modified-Since"] {
+        let value = req.headers().get(name).map(|v| reqwest::header::HeaderValue::from_bytes(v.as_bytes()));
+        if let Some(Ok(value)) = value {
+            headers.insert(name, value);
+        }
     }
-    if let Some(if_range) = req.headers().get("If-Range") {
-        headers.insert("If-Range", if_range.clone());
+
+    // Media playlists the prefetcher follows, should this turn out to be one
+    let prefetch_key = prefetch::enabled(&config).then(|| prefetch::playlist_key(&target_url_parsed, &headers));
+
+    // Rewritten media playlists may be cached as well
+    let playlist_key = playlist_cache_key(&target, &target_url_parsed, delta_key.is_some(), &headers, &config);
+    if let Some(cached) = playlist_key.as_deref().and_then(cache::playlist::get) {
+        if let Some(key) = &prefetch_key {
+            prefetch::touched(key);
+        }
+        return cached_playlist(cached, acao);
     }
-    if let Some(if_none_match) = req.headers().get("If-None-Match") {
-        headers.insert("If-None-Match", if_none_match.clone());
+
+    // Identifies requests that can share a cached response or an upstream fetch
+    let cache_key = ((config.cache.enabled || config.cache.coalesce) && cache::cacheable_request(&headers))
+        .then(|| cache::key(&target_url_parsed, &headers));
+
+    // A segment of a playlist being prefetched moves the prefetch window on
+    if let Some(key) = &cache_key {
+        prefetch::requested(key);
     }
-    if let Some(if_modified_since) = req.headers().get("If-Modified-Since") {
-        headers.insert("If-Modified-Since", if_modified_since.clone());
+
+    // Segments and other responses passed through unchanged may be cached
+    // already. A plain Range request can be answered from a complete copy.
+    if config.cache.enabled {
+        let lookup = match &cache_key {
+            Some(key) => Some((key.clone(), None)),
+            None => cache::range_key(&target_url_parsed, &headers).map(|(key, range)| (key, Some(range))),
+        };
+        if let Some((key, range)) = lookup {
+            if let Some(cached) = cache::get(&key).await {
+                return cached_response(cached, range.as_deref(), acao);
+            }
+        }
     }
 
-    // Fetch target
-    let resp = match CLIENT.get(&target_url).headers(headers).send().await {
+    // Fetch target, joining an identical fetch already under way if there is one
+    let fetch = CLIENT.get(&target_url).headers(headers.clone()).send();
+    let resp = match &cache_key {
+        Some(key) if config.cache.coalesce => upstream::shared(key.clone(), fetch).await,
+        _ => upstream::fetch(fetch).await,
+    };
+    let mut resp = match resp {
         Ok(r) => r,
-        Err(e) => {
-            eprintln!("Failed to fetch target URL {}: {:?}", target_url, e);
-            return HttpResponse::InternalServerError().body("Failed to fetch target URL");
-        }
+        Err(e) => return ProxyError::from_reqwest(&e).for_target(&target_url_parsed).response(&request_id, acao),
     };
+    let redirect_chain = std::mem::take(&mut resp.redirect_chain);
 
-    let status = resp.status();
-    let headers_copy = resp.headers().clone();
+    let status = resp.status;
+    // Relative URIs resolve against where the upstream redirected us, not the URL asked for
+    let mut scrape_url = resp.url.clone();
+    let headers_copy = resp.headers.clone();
     let content_type = headers_copy
         .get("Content-Type")
         .and_then(|v| v.to_str().ok())
@@ -348,40 +742,139 @@ async fn m3u8_proxy(req: HttpRequest) -> impl Responder {
     let ct_is_m3u8 = content_type.contains("mpegurl")
         || content_type.contains("application/vnd.apple.mpegurl")
         || content_type.contains("application/x-mpegurl");
-    let url_looks_m3u8 = target_url.to_ascii_lowercase().ends_with(".m3u8");
+    // Check the path only; blocking reloads and tokens put a query after .m3u8
+    let url_looks_m3u8 = target_url_parsed.path().to_ascii_lowercase().ends_with(".m3u8");
 
     if ct_is_m3u8 || url_looks_m3u8 {
+        // Error pages are not playlists; the client gets the upstream status instead
+        if status.is_client_error() || status.is_server_error() {
+            return ProxyError::upstream_status(status).for_target(&scrape_url).response(&request_id, acao);
+        }
+
+        if !needs_whole_playlist(&target, delta_key.is_some(), &config) {
+            // The prefetcher gets the upstream playlist once it has streamed through
+            if let Some(key) = prefetch_key {
+                let (base, origin, header_json) = (resp.url.clone(), target.origin.clone(), target.headers.clone());
+                resp.body = Box::pin(cache::Recorder::new(resp.body, config.cache.max_entry_bytes, move |body| {
+                    if let Playlist::Media(media) = Playlist::parse(&String::from_utf8_lossy(&body)) {
+                        prefetch::polled(key, &media, &base, origin.as_deref(), header_json.as_deref());
+                    }
+                }));
+            }
+            let mut response =
+                stream_playlist(resp, ct_is_m3u8, acao, &target, config, &request_id, playlist_key).await;
+            add_redirect_chain(&mut response, &redirect_chain);
+            return response;
+        }
+
         let m3u8_text = match resp.text().await {
             Ok(t) => t,
-            Err(e) => {
-                eprintln!("Failed to read potential m3u8 content ({}): {:?}", target_url, e);
-                return HttpResponse::InternalServerError().body("Failed to read m3u8");
-            }
+            Err(e) => return ProxyError::from_reqwest(&e).for_target(&scrape_url).response(&request_id, acao),
         };
 
         let looks_like_m3u8 = m3u8_text.trim_start().starts_with("#EXTM3U");
         if ct_is_m3u8 || looks_like_m3u8 {
-            let scrape_url = Url::parse(&target_url).unwrap();
-            let _headers_param = query.get("headers").cloned();
-            let origin_param = query.get("origin").cloned();
-            
-            // Process m3u8 sequentially
-            let lines = m3u8_text.lines();
-            let mut processed_lines = Vec::with_capacity(lines.size_hint().0);
-            
-            for line in lines {
-                processed_lines.push(process_m3u8_line(line, &scrape_url, &origin_param));
+            // Carry the custom headers on to segments, keys and variants, which usually need the same auth
+            let headers_param = target.headers.as_deref().map(encode_headers_param);
+            let session = playlist_session(&target, &config);
+
+            let mut playlist = Playlist::parse(&m3u8_text);
+
+            if let Some(key) = &delta_key {
+                let expanded = match &mut playlist {
+                    Playlist::Media(media) => delta::expand(key, media),
+                    Playlist::Master(_) => true,
+                };
+                if !expanded {
+                    // Our copy doesn't cover the skipped segments; get the full playlist instead
+                    eprintln!("Cannot expand delta update of {}, refetching in full", target_url);
+                    let full_url = delta::without_skip(&target_url_parsed);
+                    let refetched = match CLIENT.get(full_url.as_str()).headers(headers.clone()).send().await {
+                        Ok(r) if !r.status().is_success() => {
+                            return ProxyError::upstream_status(r.status())
+                                .for_target(&full_url)
+                                .response(&request_id, acao);
+                        }
+                        Ok(r) => {
+                            scrape_url = r.url().clone();
+                            r.text().await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match refetched {
+                        Ok(text) => playlist = Playlist::parse(&text),
+                        Err(e) => {
+                            return ProxyError::from_reqwest(&e).for_target(&full_url).response(&request_id, acao);
+                        }
+                    }
+                }
+                if let Playlist::Media(media) = &mut playlist {
+                    if media.skip().is_none() {
+                        delta::store(key, media);
+                    }
+                    delta::hide_skip_support(media);
+                }
             }
-            return HttpResponse::Ok()
-                .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, acao.clone().unwrap_or("*".to_string())))
-                .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, OPTIONS, HEAD"))
-                .insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, Authorization, Range, Origin, Accept, Accept-Encoding, Accept-Language, Cache-Control, Pragma, Sec-Fetch-Dest, Sec-Fetch-Mode, Sec-Fetch-Site, Sec-Ch-Ua, Sec-Ch-Ua-Mobile, Sec-Ch-Ua-Platform, Connection"))
-                .insert_header((header::ACCESS_CONTROL_EXPOSE_HEADERS, "Content-Length, Content-Range, Accept-Ranges, Content-Type, Cache-Control, Expires, Vary, ETag, Last-Modified"))
-                .insert_header((header::CROSS_ORIGIN_RESOURCE_POLICY, "cross-origin"))
-                .insert_header(("Vary", "Origin"))
-                .content_type("application/vnd.apple.mpegurl")
-                .insert_header((header::CACHE_CONTROL, "no-cache, no-store, must-revalidate"))
-                .body(processed_lines.join("\n"));
+            match &mut playlist {
+                Playlist::Master(master) => {
+                    target.rendition_filter.apply(master);
+                    target.variant_filter.apply(master);
+                }
+                Playlist::Media(media) if target.strip_ads => {
+                    // Same stream, same upstream headers: one renumbering state
+                    let stream_key = prefetch::playlist_key(&target_url_parsed, &headers);
+                    config.ad_filter.apply(&stream_key, &scrape_url, media);
+                }
+                Playlist::Media(_) => {}
+            }
+            if let (Playlist::Media(media), Some(key)) = (&playlist, prefetch_key) {
+                prefetch::polled(key, media, &scrape_url, target.origin.as_deref(), target.headers.as_deref());
+            }
+
+            // Injected subtitle playlists span the stream, so its length is needed up front
+            let mut subtitle_duration = None;
+            if let (Playlist::Master(master), false) = (&playlist, target.subtitles.is_empty()) {
+                for track in &target.subtitles {
+                    if let Err(reason) = config.check_upstream(&track.url) {
+                        return ProxyError::blocked(format!("Playlist references a blocked upstream: {}", reason))
+                            .for_target(&scrape_url)
+                            .response(&request_id, acao);
+                    }
+                }
+                subtitle_duration = stream_duration(master, &scrape_url, &headers, &config).await;
+                if subtitle_duration.is_none() {
+                    eprintln!("Could not determine stream duration for {}, not injecting subtitles", target_url);
+                }
+            }
+
+            let ctx = RewriteContext {
+                scrape_url: &scrape_url,
+                origin_param: target.origin.as_deref(),
+                headers_param: headers_param.as_deref(),
+                session: session.as_deref(),
+                config: &config,
+            };
+            if let Err(reason) = rewrite_playlist(&mut playlist, &ctx) {
+                return ProxyError::blocked(format!("Playlist references a blocked upstream: {}", reason))
+                    .for_target(&scrape_url)
+                    .response(&request_id, acao);
+            }
+            if let (Playlist::Master(master), Some(duration)) = (&mut playlist, subtitle_duration) {
+                subtitles::inject(master, &target.subtitles, |track| {
+                    format!("/?{}&vtt_duration={:.3}", proxy_query(&track.url, &ctx), duration)
+                });
+            }
+
+            let body = playlist.to_string();
+            let mut response = match playlist_key {
+                Some(key) => {
+                    cache::playlist::store(key, bytes::Bytes::from(body.clone()));
+                    playlist_response_builder(acao).insert_header((cache::STATUS_HEADER, "MISS")).body(body)
+                }
+                None => playlist_response(acao, body),
+            };
+            add_redirect_chain(&mut response, &redirect_chain);
+            return response;
         } else {
             let preview: String = m3u8_text.chars().take(200).collect();
             eprintln!(
@@ -391,91 +884,159 @@ async fn m3u8_proxy(req: HttpRequest) -> impl Responder {
                 preview.replace('\n', "\\n")
             );
 
-            let mut response_builder = HttpResponse::build(status);
-            
-            // Set CORS headers for all responses - more permissive
-            response_builder.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, acao.clone().unwrap_or("*".to_string())));
-            response_builder.insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, OPTIONS, HEAD"));
-            response_builder.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, Authorization, Range, Origin, Accept, Accept-Encoding, Accept-Language, Cache-Control, Pragma, Sec-Fetch-Dest, Sec-Fetch-Mode, Sec-Fetch-Site, Sec-Ch-Ua, Sec-Ch-Ua-Mobile, Sec-Ch-Ua-Platform, Connection"));
-            response_builder.insert_header((header::ACCESS_CONTROL_EXPOSE_HEADERS, "Content-Length, Content-Range, Accept-Ranges, Content-Type, Cache-Control, Expires, Vary, ETag, Last-Modified"));
-            response_builder.insert_header((header::CROSS_ORIGIN_RESOURCE_POLICY, "cross-origin"));
-            response_builder.insert_header(("Vary", "Origin"));
-            
-            // Copy important headers from the original response
-            for (name, value) in headers_copy.iter() {
-                let header_name = name.as_str().to_lowercase();
-                if header_name == "content-type" 
-                    || header_name == "content-length" 
-                    || header_name == "content-range"
-                    || header_name == "accept-ranges"
-                    || header_name == "cache-control"
-                    || header_name == "expires"
-                    || header_name == "last-modified"
-                    || header_name == "etag"
-                    || header_name == "content-encoding"
-                    || header_name == "vary" {
-                    response_builder.insert_header((name.clone(), value.clone()));
-                }
-            }
-
-            return response_builder.body(m3u8_text);
+            let mut response_builder = passthrough_response(status, acao, &headers_copy);
+            let mut response = response_builder.body(m3u8_text);
+            add_redirect_chain(&mut response, &redirect_chain);
+            return response;
         }
     }
 
-    let mut response_builder = HttpResponse::build(status);
-    
-    // Set CORS headers for all responses - more permissive
-    response_builder.insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, acao.clone().unwrap_or("*".to_string())));
-    response_builder.insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, OPTIONS, HEAD"));
-    response_builder.insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, Authorization, Range, Origin, Accept, Accept-Encoding, Accept-Language, Cache-Control, Pragma, Sec-Fetch-Dest, Sec-Fetch-Mode, Sec-Fetch-Site, Sec-Ch-Ua, Sec-Ch-Ua-Mobile, Sec-Ch-Ua-Platform, Connection"));
-    response_builder.insert_header((header::ACCESS_CONTROL_EXPOSE_HEADERS, "Content-Length, Content-Range, Accept-Ranges, Content-Type, Cache-Control, Expires, Vary, ETag, Last-Modified"));
-    response_builder.insert_header((header::CROSS_ORIGIN_RESOURCE_POLICY, "cross-origin"));
-    response_builder.insert_header(("Vary", "Origin"));
-    
-    // Copy important headers from the original response
-    for (name, value) in headers_copy.iter() {
-        let header_name = name.as_str().to_lowercase();
-        if header_name == "content-type" 
-            || header_name == "content-length" 
-            || header_name == "content-range"
-            || header_name == "accept-ranges"
-            || header_name == "cache-control"
-            || header_name == "expires"
-            || header_name == "last-modified"
-            || header_name == "etag"
-            || header_name == "content-encoding"
-            || header_name == "vary" {
-            response_builder.insert_header((name.clone(), value.clone()));
-        }
-    }
+    let mut response_builder = passthrough_response(status, acao, &headers_copy);
 
-    let stream = resp.bytes_stream().map(|chunk| {
-        chunk
-            .map(Bytes::from)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    let stream = resp.body.map(|chunk| {
+        chunk.map_err(std::io::Error::other)
     });
 
-    response_builder.body(actix_web::body::BodyStream::new(stream))
+    // Of the requests sharing a fetch, the one that started it stores the response
+    let default_ttl = Duration::from_secs(config.cache.default_ttl_secs);
+    let cache_ttl = cache_key
+        .filter(|_| config.cache.enabled && resp.leader)
+        .and_then(|key| Some((key, cache::freshness(status, &headers_copy, default_ttl)?)));
+    let mut response = match cache_ttl {
+        Some((key, ttl)) => {
+            response_builder.insert_header((cache::STATUS_HEADER, "MISS"));
+            let limit = config.cache.max_entry_bytes;
+            let recorder = cache::Recorder::new(stream, limit, move |body| {
+                cache::store(key, ttl, status, headers_copy, body)
+            });
+            response_builder.body(actix_web::body::BodyStream::new(recorder))
+        }
+        None => response_builder.body(actix_web::body::BodyStream::new(stream)),
+    };
+    add_redirect_chain(&mut response, &redirect_chain);
+    response
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenvy::dotenv().ok();
 
+    let config = match config::init() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to load config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    match &config.source {
+        Some(path) => println!("Loaded {} domain groups from {}", config.templates.len(), path.display()),
+        None => println!("Using {} built-in domain groups", config.templates.len()),
+    }
+
     println!("We alive bois: http://127.0.0.1:8080");
     if *ENABLE_CORS {
-        println!("Allowed origins: {:?}", *ALLOWED_ORIGINS);
+        println!("Allowed origins: {:?}", config.allowed_origins.entries());
+    }
+
+    config::spawn_reloader();
+
+    if config.signing.enabled() {
+        println!("Signed URLs required");
     }
 
+    if let Some(dir) = config.cache.disk_dir.as_deref().filter(|_| config.cache.enabled) {
+        match cache::disk::open(dir) {
+            Ok((entries, bytes)) => println!("Disk cache at {}: {} entries, {} bytes", dir.display(), entries, bytes),
+            Err(e) => {
+                eprintln!("Failed to open disk cache {}: {}", dir.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if config.prefetch.enabled {
+        println!("Prefetching {} segments ahead", config.prefetch.segments);
+    }
+    prefetch::spawn_sweeper();
+
+    if config.sessions.enabled {
+        println!("Rewriting playlists with opaque session URLs");
+    }
+    sessions::spawn_sweeper();
+
     HttpServer::new(|| {
         App::new()
             .wrap(Compress::default())
             .wrap(actix_web::middleware::DefaultHeaders::new().add(("Vary", "Accept-Encoding")))
             .service(m3u8_proxy)
+            .service(session_proxy)
             .route("/", actix_web::web::method(Method::OPTIONS).to(handle_options))
+            .route("/s/{session}/{item}", actix_web::web::method(Method::OPTIONS).to(handle_options))
     })
     .workers(num_cpus::get())
     .bind("0.0.0.0:8080")?
     .run()
     .await
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud\",NAME=\"en\",URI=\"audio/en.m3u8\"
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,AUDIO=\"aud\"
+low/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2560000,AUDIO=\"aud\"
+https://other.example.com/high/index.m3u8
+";
+
+    #[test]
+    fn signed_child_urls_in_rewritten_playlists_verify() {
+        let mut config = config::Config::builtin();
+        config.signing.secret = Some("s3cret".to_string());
+        let scrape_url = Url::parse("https://cdn.example.com/live/master.m3u8").unwrap();
+        let headers = encode_headers_param(r#"{"Referer":"https://example.com/"}"#);
+        let ctx = RewriteContext {
+            scrape_url: &scrape_url,
+            origin_param: Some("https://player.example.com"),
+            headers_param: Some(&headers),
+            session: None,
+            config: &config,
+        };
+
+        let mut playlist = Playlist::parse(MASTER);
+        rewrite_playlist(&mut playlist, &ctx).unwrap();
+        let text = playlist.to_string();
+
+        let mut children = 0;
+        for line in text.lines() {
+            let Some(start) = line.find("/?") else {
+                continue;
+            };
+            let end = line[start..].find('"').map_or(line.len(), |i| start + i);
+            // Decoded the way the handler's Query extractor decodes it
+            let query: HashMap<String, String> = url::form_urlencoded::parse(&line.as_bytes()[start + 2..end])
+                .into_owned()
+                .collect();
+            let get = |name: &str| query.get(name).map(String::as_str);
+            assert_eq!(
+                config.signing.verify(get("url").unwrap(), get("origin"), get("headers"), get("expires"), get("sig")),
+                Ok(()),
+                "{}",
+                line
+            );
+            assert_eq!(
+                config.signing.verify(
+                    "https://cdn.example.com/live/secret.m3u8",
+                    get("origin"),
+                    get("headers"),
+                    get("expires"),
+                    get("sig")
+                ),
+                Err("Invalid signature")
+            );
+            children += 1;
+        }
+        assert_eq!(children, 3, "{}", text);
+    }
+}
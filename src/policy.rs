@@ -0,0 +1,199 @@
+use regex::Regex;
+use serde::Deserialize;
+use url::Url;
+
+use crate::templates::Templates;
+
+// [host_policy] section of the config file. Entries are matched against the
+// upstream hostname:
+//   "cdn.example.com"       exact host
+//   "*.example.com"         any subdomain of example.com (also ".example.com")
+//   "regex:^edge[0-9]+\."   regex over the hostname
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostPolicyConfig {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    // Also allow every host covered by a domain group pattern
+    pub allow_template_hosts: bool,
+}
+
+// One host entry in the syntax above; also used by the ad filter's host list
+pub enum HostRule {
+    Exact(String),
+    Suffix(String),
+    Pattern(Regex),
+}
+
+impl HostRule {
+    pub fn parse(entry: &str) -> Result<HostRule, String> {
+        let entry = entry.trim();
+        if let Some(pattern) = entry.strip_prefix("regex:") {
+            let re = Regex::new(pattern)
+                .map_err(|e| format!("invalid host regex {:?}: {}", pattern, e))?;
+            return Ok(HostRule::Pattern(re));
+        }
+
+        let suffix = entry.strip_prefix("*.").or_else(|| entry.strip_prefix('.'));
+        match suffix {
+            Some(s) if !s.is_empty() && !s.contains('*') => Ok(HostRule::Suffix(s.to_ascii_lowercase())),
+            Some(_) => Err(format!("invalid host wildcard {:?}", entry)),
+            None if entry.is_empty() || entry.contains('*') => Err(format!("invalid host {:?}", entry)),
+            None => Ok(HostRule::Exact(entry.to_ascii_lowercase())),
+        }
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostRule::Exact(h) => h == host,
+            HostRule::Suffix(s) => {
+                host.len() > s.len()
+                    && host.ends_with(s.as_str())
+                    && host.as_bytes()[host.len() - s.len() - 1] == b'.'
+            }
+            HostRule::Pattern(re) => re.is_match(host),
+        }
+    }
+}
+
+// Which upstream hosts the proxy is willing to fetch from
+#[derive(Default)]
+pub struct HostPolicy {
+    allow: Vec<HostRule>,
+    deny: Vec<HostRule>,
+    allow_template_hosts: bool,
+}
+
+impl HostPolicy {
+    pub fn compile(config: &HostPolicyConfig) -> Result<HostPolicy, String> {
+        let parse_all = |entries: &[String]| entries.iter().map(|e| HostRule::parse(e)).collect::<Result<Vec<_>, _>>();
+        Ok(HostPolicy {
+            allow: parse_all(&config.allow).map_err(|e| format!("allow: {}", e))?,
+            deny: parse_all(&config.deny).map_err(|e| format!("deny: {}", e))?,
+            allow_template_hosts: config.allow_template_hosts,
+        })
+    }
+
+    // Whether any allow-list is in effect; without one every non-denied host passes
+    fn restricts(&self) -> bool {
+        !self.allow.is_empty() || self.allow_template_hosts
+    }
+
+    // Whether every host passes
+    pub fn is_open(&self) -> bool {
+        self.deny.is_empty() && !self.restricts()
+    }
+
+    pub fn check(&self, url: &Url, templates: &Templates) -> Result<(), String> {
+        let host = url.host_str().unwrap_or("").to_ascii_lowercase();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        if self.deny.iter().any(|rule| rule.matches(host)) {
+            return Err(format!("Upstream host is denied: {}", host));
+        }
+        if !self.restricts() {
+            return Ok(());
+        }
+
+        let allowed = self.allow.iter().any(|rule| rule.matches(host))
+            || (self.allow_template_hosts && templates.find_group(host).is_some());
+        if allowed {
+            Ok(())
+        } else {
+            Err(format!("Upstream host is not allowed: {}", host))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str], allow_template_hosts: bool) -> HostPolicy {
+        let strings = |entries: &[&str]| entries.iter().map(|e| e.to_string()).collect();
+        HostPolicy::compile(&HostPolicyConfig {
+            allow: strings(allow),
+            deny: strings(deny),
+            allow_template_hosts,
+        })
+        .unwrap()
+    }
+
+    fn check(policy: &HostPolicy, url: &str) -> Result<(), String> {
+        policy.check(&Url::parse(url).unwrap(), &Templates::builtin())
+    }
+
+    #[test]
+    fn matches_host_rules() {
+        let exact = HostRule::parse("CDN.example.com").unwrap();
+        assert!(exact.matches("cdn.example.com"));
+        assert!(!exact.matches("a.cdn.example.com"));
+
+        for entry in ["*.example.com", ".example.com"] {
+            let suffix = HostRule::parse(entry).unwrap();
+            assert!(suffix.matches("a.example.com"), "{}", entry);
+            assert!(suffix.matches("a.b.example.com"), "{}", entry);
+            assert!(!suffix.matches("example.com"), "{}", entry);
+            assert!(!suffix.matches("badexample.com"), "{}", entry);
+        }
+
+        let pattern = HostRule::parse(r"regex:^edge[0-9]+\.").unwrap();
+        assert!(pattern.matches("edge12.example.com"));
+        assert!(!pattern.matches("origin.example.com"));
+
+        for entry in ["", "*.", "a.*.com", "*", "regex:("] {
+            assert!(HostRule::parse(entry).is_err(), "{:?}", entry);
+        }
+    }
+
+    #[test]
+    fn deny_list_wins_over_allow_list() {
+        let policy = policy(&["*.example.com"], &["bad.example.com", "regex:^ads\\."], false);
+        assert_eq!(check(&policy, "https://cdn.example.com/a.ts"), Ok(()));
+        assert_eq!(
+            check(&policy, "https://bad.example.com/a.ts"),
+            Err("Upstream host is denied: bad.example.com".to_string())
+        );
+        assert!(check(&policy, "https://ads.example.com/a.ts").is_err());
+        assert_eq!(
+            check(&policy, "https://cdn.example.org/a.ts"),
+            Err("Upstream host is not allowed: cdn.example.org".to_string())
+        );
+
+        // Also when the same host is on both lists
+        let both = self::policy(&["cdn.example.com"], &["cdn.example.com"], false);
+        assert!(check(&both, "https://cdn.example.com/a.ts").unwrap_err().contains("denied"));
+    }
+
+    #[test]
+    fn deny_list_alone_lets_other_hosts_through() {
+        let policy = policy(&[], &["*.tracker.example"], false);
+        assert!(!policy.is_open());
+        assert_eq!(check(&policy, "https://cdn.example.com/a.ts"), Ok(()));
+        assert!(check(&policy, "https://x.tracker.example/a.ts").is_err());
+        assert!(HostPolicy::default().is_open());
+    }
+
+    #[test]
+    fn template_hosts_can_be_allowed() {
+        let policy = policy(&[], &["blocked.kwikie.ru"], true);
+        assert_eq!(check(&policy, "https://www.kwikie.ru/a.ts"), Ok(()));
+        assert!(check(&policy, "https://blocked.kwikie.ru/a.ts").is_err());
+        assert!(check(&policy, "https://cdn.example.com/a.ts").is_err());
+    }
+
+    #[test]
+    fn ipv6_hosts_match_without_brackets() {
+        let policy = policy(&[], &["::1"], false);
+        assert!(check(&policy, "http://[::1]:8080/a.ts").is_err());
+    }
+
+    #[test]
+    fn compile_names_the_bad_list() {
+        let config = HostPolicyConfig {
+            deny: vec!["regex:(".to_string()],
+            ..Default::default()
+        };
+        assert!(HostPolicy::compile(&config).err().unwrap().starts_with("deny: "));
+    }
+}
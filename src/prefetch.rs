@@ -0,0 +1,498 @@
+// Background fetching of the segments a player is about to ask for, so they
+// are in the cache by the time it does. Each poll of a media playlist and
+// each request for one of its segments moves the window fetched ahead.
+// Playlists nobody polls or plays from anymore are forgotten, which cancels
+// whatever was still queued or fetching for them.
+
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::sync::{watch, Semaphore};
+use url::Url;
+
+use crate::{
+    cache, config,
+    hls::playlist::MediaPlaylist,
+    ssrf, templates,
+    upstream::{self, CLIENT},
+};
+
+// [prefetch] section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrefetchConfig {
+    // Needs [cache] enabled, which is where prefetched responses go
+    pub enabled: bool,
+    // Segments fetched ahead of the player
+    pub segments: usize,
+    // Prefetches running at once across all playlists; read at startup only
+    pub max_concurrent: usize,
+    // A playlist not polled and not played from for this long is forgotten
+    pub idle_secs: u64,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        PrefetchConfig {
+            enabled: false,
+            segments: 3,
+            max_concurrent: 8,
+            idle_secs: 30,
+        }
+    }
+}
+
+impl PrefetchConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_concurrent == 0 {
+            return Err("max_concurrent must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Something a player will ask for, with what the proxy would send upstream for it
+#[derive(Clone)]
+struct Item {
+    url: Url,
+    headers: HeaderMap,
+    // cache::key of the request
+    key: String,
+}
+
+// A segment plus the key and init section it needs
+struct Segment {
+    item: Item,
+    needs: Vec<Item>,
+}
+
+struct Watched {
+    segments: Vec<Segment>,
+    // Index in `segments` by cache key
+    positions: HashMap<String, usize>,
+    // Index of the last segment a player asked for
+    played: Option<usize>,
+    ended: bool,
+    last_active: Instant,
+    // Dropped with the playlist, which cancels its prefetches
+    cancel: watch::Sender<()>,
+}
+
+#[derive(Default)]
+struct State {
+    playlists: HashMap<String, Watched>,
+    // Playlist of each watched segment, by the segment's cache key
+    segments: HashMap<String, String>,
+    // Cache keys queued or being fetched
+    pending: HashSet<String>,
+}
+
+impl State {
+    fn forget(&mut self, playlist: &str) -> Option<Watched> {
+        let watched = self.playlists.remove(playlist)?;
+        for key in watched.positions.keys() {
+            self.segments.remove(key);
+        }
+        Some(watched)
+    }
+
+    // Remember the segments of a playlist just polled, keeping track of where
+    // the player was. Returns the first segment to fetch ahead from.
+    fn watch(&mut self, playlist: &str, segments: Vec<Segment>, ended: bool, window: usize) -> usize {
+        let previous = self.forget(playlist);
+        let positions: HashMap<String, usize> = segments.iter().enumerate().map(|(i, s)| (s.item.key.clone(), i)).collect();
+        // Where the player was, found again in the new version of a live playlist
+        let played = previous.as_ref().and_then(|p| {
+            let key = &p.segments[p.played?].item.key;
+            positions.get(key).copied()
+        });
+        for key in positions.keys() {
+            self.segments.insert(key.clone(), playlist.to_string());
+        }
+        let watched = Watched {
+            segments,
+            positions,
+            played,
+            ended,
+            last_active: Instant::now(),
+            cancel: previous.map_or_else(|| watch::channel(()).0, |p| p.cancel),
+        };
+
+        // Before any segment was asked for: a live player starts near the end,
+        // a VOD player at the start
+        let start = match watched.played {
+            Some(i) => i + 1,
+            None if watched.ended => 0,
+            None => watched.segments.len().saturating_sub(window),
+        };
+        self.playlists.insert(playlist.to_string(), watched);
+        start
+    }
+
+    // A player asked for a watched segment. Returns its playlist and the
+    // segment after it.
+    fn play(&mut self, key: &str) -> Option<(String, usize)> {
+        let playlist = self.segments.get(key)?.clone();
+        let watched = self.playlists.get_mut(&playlist)?;
+        let position = *watched.positions.get(key)?;
+        watched.played = Some(position);
+        watched.last_active = Instant::now();
+        Some((playlist, position + 1))
+    }
+
+    // What `count` segments from `start` need that isn't cached or on its way,
+    // now marked as on its way, with the playlist's cancellation signal
+    fn due(&mut self, playlist: &str, start: usize, count: usize) -> Option<(Vec<Item>, watch::Receiver<()>)> {
+        let watched = self.playlists.get(playlist)?;
+        let mut items = Vec::new();
+        for segment in watched.segments.iter().skip(start).take(count) {
+            items.extend(segment.needs.iter().cloned());
+            items.push(segment.item.clone());
+        }
+        let cancel = watched.cancel.subscribe();
+        items.retain(|item| !cache::contains(&item.key) && self.pending.insert(item.key.clone()));
+        Some((items, cancel))
+    }
+
+    // Segments behind the one the player last asked for won't be asked for
+    fn still_wanted(&self, playlist: &str, key: &str) -> bool {
+        let Some(watched) = self.playlists.get(playlist) else {
+            return false;
+        };
+        match (watched.positions.get(key), watched.played) {
+            (Some(position), Some(played)) => *position > played,
+            // Keys and init sections, or nothing played yet
+            _ => true,
+        }
+    }
+
+    // Playlists neither polled nor played from within `idle`
+    fn idle(&self, idle: Duration) -> Vec<String> {
+        self.playlists
+            .iter()
+            .filter(|(_, w)| w.last_active.elapsed() > idle)
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+}
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| Mutex::new(State::default()));
+
+static PERMITS: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(config::current().prefetch.max_concurrent));
+
+pub fn enabled(config: &config::Config) -> bool {
+    config.prefetch.enabled && config.cache.enabled
+}
+
+// Key identifying a media playlist across polls, whatever LL-HLS directives they carry
+pub fn playlist_key(url: &Url, headers: &HeaderMap) -> String {
+    cache::key(&crate::hls::delta::playlist_url(url), headers)
+}
+
+fn item(uri: &str, base: &Url, origin: Option<&str>, header_json: Option<&str>, config: &config::Config) -> Option<Item> {
+    let url = base.join(uri).ok()?;
+    let headers = templates::upstream_headers(&config.templates, &url, origin, header_json);
+    let key = cache::key(&url, &headers);
+    Some(Item { url, headers, key })
+}
+
+// Segments of a media playlist, each with the key and init section it needs
+fn segments(media: &MediaPlaylist, base: &Url, origin: Option<&str>, header_json: Option<&str>, config: &config::Config) -> Vec<Segment> {
+    // Keys and init sections carry over to later segments until replaced.
+    // Byte ranges are fetched with Range requests, which the cache can't fill.
+    let mut segments = Vec::new();
+    let mut key = None;
+    let mut map = None;
+    for segment in &media.segments {
+        if let Some(attributes) = segment.key() {
+            key = attributes
+                .get("URI")
+                .filter(|_| attributes.get("METHOD") != Some("NONE"))
+                .and_then(|uri| item(uri, base, origin, header_json, config));
+        }
+        if let Some(attributes) = segment.map() {
+            map = attributes
+                .get("URI")
+                .filter(|_| attributes.get("BYTERANGE").is_none())
+                .and_then(|uri| item(uri, base, origin, header_json, config));
+        }
+        if segment.byte_range().is_some() {
+            continue;
+        }
+        if let Some(item) = item(&segment.uri, base, origin, header_json, config) {
+            let needs = key.iter().chain(map.iter()).cloned().collect();
+            segments.push(Segment { item, needs });
+        }
+    }
+    segments
+}
+
+// A media playlist was fetched for a player: remember its segments and fetch
+// ahead of where the player is. `base` resolves relative URIs; `origin` and
+// `header_json` are the params its segment URLs are rewritten with.
+pub fn polled(playlist: String, media: &MediaPlaylist, base: &Url, origin: Option<&str>, header_json: Option<&str>) {
+    let config = config::current();
+    if !enabled(&config) {
+        return;
+    }
+
+    let segments = segments(media, base, origin, header_json, &config);
+    let ended = media.has_end_list() || media.playlist_type() == Some("VOD");
+    let mut state = STATE.lock().unwrap();
+    let start = state.watch(&playlist, segments, ended, config.prefetch.segments);
+    schedule(&mut state, &playlist, start, config.prefetch.segments);
+}
+
+// A playlist was served from the playlist cache; it is still being polled
+pub fn touched(playlist: &str) {
+    if let Some(watched) = STATE.lock().unwrap().playlists.get_mut(playlist) {
+        watched.last_active = Instant::now();
+    }
+}
+
+// A player asked for something by its cache key. If it's a watched segment,
+// fetch the ones after it.
+pub fn requested(key: &str) {
+    let config = config::current();
+    if !enabled(&config) {
+        return;
+    }
+    let mut state = STATE.lock().unwrap();
+    if let Some((playlist, next)) = state.play(key) {
+        schedule(&mut state, &playlist, next, config.prefetch.segments);
+    }
+}
+
+// Start fetching `count` segments from `start` that aren't cached or on their way
+fn schedule(state: &mut State, playlist: &str, start: usize, count: usize) {
+    let Some((items, cancel)) = state.due(playlist, start, count) else {
+        return;
+    };
+    for item in items {
+        actix_web::rt::spawn(run(playlist.to_string(), item, cancel.clone()));
+    }
+}
+
+async fn run(playlist: String, item: Item, mut cancel: watch::Receiver<()>) {
+    let key = item.key.clone();
+    tokio::select! {
+        _ = wait_for_permit_and_fetch(playlist, item) => {}
+        // Resolves once the playlist is forgotten and its sender dropped
+        _ = async { while cancel.changed().await.is_ok() {} } => {}
+    }
+    STATE.lock().unwrap().pending.remove(&key);
+}
+
+async fn wait_for_permit_and_fetch(playlist: String, item: Item) {
+    let Ok(_permit) = PERMITS.acquire().await else {
+        return;
+    };
+    // While queued, the player may have moved past it or fetched it itself
+    if cache::contains(&item.key) || !STATE.lock().unwrap().still_wanted(&playlist, &item.key) {
+        return;
+    }
+    let config = config::current();
+    if ssrf::check_url(&item.url, &config.ssrf).is_err() || config.check_upstream(&item.url).is_err() {
+        return;
+    }
+
+    let fetch = CLIENT.get(item.url.as_str()).headers(item.headers).send();
+    let resp = if config.cache.coalesce {
+        upstream::shared(item.key.clone(), fetch).await
+    } else {
+        upstream::fetch(fetch).await
+    };
+    // A player's request started this fetch first and stores it itself
+    let Some(resp) = resp.ok().filter(|r| r.leader) else {
+        return;
+    };
+    let default_ttl = Duration::from_secs(config.cache.default_ttl_secs);
+    let Some(ttl) = cache::freshness(resp.status, &resp.headers, default_ttl) else {
+        return;
+    };
+    let (status, headers) = (resp.status, resp.headers);
+    let mut body = cache::Recorder::new(resp.body, config.cache.max_entry_bytes, move |body| {
+        cache::store(item.key, ttl, status, headers, body)
+    });
+    while let Some(chunk) = body.next().await {
+        if chunk.is_err() {
+            return;
+        }
+    }
+}
+
+// Periodically forget playlists nobody polls or plays from anymore
+pub fn spawn_sweeper() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            let idle = Duration::from_secs(config::current().prefetch.idle_secs);
+            let mut state = STATE.lock().unwrap();
+            for playlist in state.idle(idle) {
+                state.forget(&playlist);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hls::playlist::Playlist;
+
+    const LIVE: &str = r#"#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-MEDIA-SEQUENCE:10
+#EXT-X-MAP:URI="init.mp4"
+#EXT-X-KEY:METHOD=AES-128,URI="key1.bin"
+#EXTINF:4.0,
+seg10.mp4
+#EXTINF:4.0,
+seg11.mp4
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:4.0,
+seg12.mp4
+#EXT-X-BYTERANGE:1000@0
+#EXTINF:4.0,
+seg13.mp4
+#EXTINF:4.0,
+seg14.mp4
+#EXTINF:4.0,
+seg15.mp4"#;
+
+    // Segments of a live playlist, shifted `by` segments on from LIVE
+    fn live(base: &Url, by: usize) -> Vec<Segment> {
+        let text = LIVE.replace("#EXT-X-MEDIA-SEQUENCE:10", &format!("#EXT-X-MEDIA-SEQUENCE:{}", 10 + by));
+        let Playlist::Media(mut media) = Playlist::parse(&text) else {
+            panic!("parsed as a master playlist");
+        };
+        for segment in &mut media.segments {
+            let n: usize = segment.uri["seg".len()..segment.uri.len() - ".mp4".len()].parse().unwrap();
+            segment.uri = format!("seg{}.mp4", n + by);
+        }
+        segments(&media, base, None, None, &config::current())
+    }
+
+    fn base(name: &str) -> Url {
+        Url::parse(&format!("https://cdn.example.com/{}/index.m3u8", name)).unwrap()
+    }
+
+    // Last path segment of every item due
+    fn names(items: &[Item]) -> Vec<&str> {
+        items.iter().map(|i| i.url.path_segments().unwrap().next_back().unwrap()).collect()
+    }
+
+    fn key_of(base: &Url, uri: &str) -> String {
+        item(uri, base, None, None, &config::current()).unwrap().key
+    }
+
+    #[test]
+    fn carries_keys_and_init_sections_over() {
+        let base = base("needs");
+        let segments = live(&base, 0);
+        let listed: Vec<(&str, Vec<&str>)> = segments
+            .iter()
+            .map(|s| (names(std::slice::from_ref(&s.item))[0], names(&s.needs)))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("seg10.mp4", vec!["key1.bin", "init.mp4"]),
+                ("seg11.mp4", vec!["key1.bin", "init.mp4"]),
+                ("seg12.mp4", vec!["init.mp4"]),
+                // seg13 is a byte range
+                ("seg14.mp4", vec!["init.mp4"]),
+                ("seg15.mp4", vec!["init.mp4"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn window_follows_the_player() {
+        let base = base("window");
+        let playlist = "window".to_string();
+        let mut state = State::default();
+
+        // A live player starts near the end; the init section is fetched once
+        let start = state.watch(&playlist, live(&base, 0), false, 2);
+        assert_eq!(start, 3);
+        let (items, _) = state.due(&playlist, start, 2).unwrap();
+        assert_eq!(names(&items), ["init.mp4", "seg14.mp4", "seg15.mp4"]);
+
+        // Already on its way
+        let (items, _) = state.due(&playlist, start, 2).unwrap();
+        assert!(items.is_empty());
+
+        // Playing seg11 moves the window to the two after it
+        assert_eq!(state.play(&key_of(&base, "seg11.mp4")), Some((playlist.clone(), 2)));
+        let (items, _) = state.due(&playlist, 2, 2).unwrap();
+        assert_eq!(names(&items), ["seg12.mp4"]);
+        assert!(!state.still_wanted(&playlist, &key_of(&base, "seg10.mp4")));
+        assert!(!state.still_wanted(&playlist, &key_of(&base, "seg11.mp4")));
+        assert!(state.still_wanted(&playlist, &key_of(&base, "seg14.mp4")));
+        assert!(state.still_wanted(&playlist, &key_of(&base, "key1.bin")));
+
+        // The next version of the playlist finds the player again at seg11
+        let start = state.watch(&playlist, live(&base, 1), false, 2);
+        assert_eq!(start, 1);
+        assert_eq!(state.playlists[&playlist].played, Some(0));
+        let (items, _) = state.due(&playlist, start, 4).unwrap();
+        assert_eq!(names(&items), ["key1.bin", "seg13.mp4", "seg16.mp4"]);
+
+        // Segments that dropped out of the playlist are no longer watched
+        assert_eq!(state.play(&key_of(&base, "seg10.mp4")), None);
+        assert!(!state.segments.contains_key(&key_of(&base, "seg10.mp4")));
+        assert_eq!(state.play("unknown"), None);
+    }
+
+    #[test]
+    fn vod_starts_at_the_beginning() {
+        let base = base("vod");
+        let mut state = State::default();
+        assert_eq!(state.watch("vod", live(&base, 0), true, 2), 0);
+        let (items, _) = state.due("vod", 0, 2).unwrap();
+        assert_eq!(names(&items), ["key1.bin", "init.mp4", "seg10.mp4", "seg11.mp4"]);
+        assert!(state.due("unknown", 0, 2).is_none());
+    }
+
+    #[test]
+    fn forgetting_a_playlist_cancels_its_prefetches() {
+        let base = base("cancel");
+        let mut state = State::default();
+        state.watch("cancel", live(&base, 0), false, 2);
+        let (_, cancel) = state.due("cancel", 0, 2).unwrap();
+
+        // Polled again: the same prefetches carry on
+        state.watch("cancel", live(&base, 1), false, 2);
+        assert!(cancel.has_changed().is_ok());
+
+        assert!(state.idle(Duration::from_secs(60)).is_empty());
+        assert_eq!(state.idle(Duration::ZERO), ["cancel"]);
+        state.forget("cancel");
+        assert!(cancel.has_changed().is_err());
+        assert!(state.segments.is_empty());
+        assert!(!state.still_wanted("cancel", &key_of(&base, "seg14.mp4")));
+    }
+
+    #[tokio::test]
+    async fn cancelled_prefetches_stop_and_clear_their_pending_mark() {
+        let base = base("run");
+        let item = item("seg10.mp4", &base, None, None, &config::current()).unwrap();
+        let (sender, cancel) = watch::channel(());
+        STATE.lock().unwrap().pending.insert(item.key.clone());
+        drop(sender);
+
+        let key = item.key.clone();
+        tokio::time::timeout(Duration::from_secs(5), run("run".to_string(), item, cancel))
+            .await
+            .unwrap();
+        assert!(!STATE.lock().unwrap().pending.contains(&key));
+    }
+}
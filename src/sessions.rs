@@ -0,0 +1,273 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+use crate::config;
+
+// [sessions] section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    // Rewritten playlists point at /s/<session>/<n>.<ext> instead of
+    // /?url=<encoded upstream URL>
+    pub enabled: bool,
+    // Idle time after which a session and all its URLs are forgotten
+    pub ttl_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            enabled: false,
+            ttl_secs: 60 * 60,
+        }
+    }
+}
+
+// Upper bound on URLs remembered per session; live playlists keep adding
+// segments, so the oldest ones are dropped first
+const MAX_ITEMS_PER_SESSION: usize = 20_000;
+
+// Upper bound on live sessions; every unauthenticated playlist request starts
+// one, so the oldest ones are dropped first
+const MAX_SESSIONS: usize = 100_000;
+
+#[derive(Default)]
+struct Store {
+    sessions: HashMap<String, Arc<Session>>,
+    // Session ids in order of creation; may still hold ids the sweeper removed
+    order: VecDeque<String>,
+}
+
+static STORE: Lazy<RwLock<Store>> = Lazy::new(|| RwLock::new(Store::default()));
+
+impl Store {
+    // Add a session, dropping the oldest ones beyond `max`
+    fn insert(&mut self, session: Arc<Session>, max: usize) {
+        self.sessions.insert(session.id.clone(), session.clone());
+        self.order.push_back(session.id.clone());
+        while self.sessions.len() > max {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.sessions.remove(&oldest);
+        }
+    }
+
+    // Drop sessions nobody has used within `ttl`
+    fn sweep(&mut self, now: Instant, ttl: Duration) {
+        self.sessions.retain(|_, s| !s.expired(now, ttl));
+        let Store { sessions, order } = self;
+        order.retain(|id| sessions.contains_key(id));
+    }
+}
+
+// Upstream URLs handed out under one session, numbered in order of first use
+struct Items {
+    urls: VecDeque<String>,
+    // Number of the first entry still in `urls`
+    first: usize,
+    index: HashMap<String, usize>,
+}
+
+// Upstream context shared by every URL of one proxied playlist tree
+pub struct Session {
+    pub id: String,
+    pub origin: Option<String>,
+    // Custom upstream headers as JSON text
+    pub headers: Option<String>,
+    items: Mutex<Items>,
+    last_used: Mutex<Instant>,
+}
+
+impl Session {
+    // Number for an upstream URL, reusing the existing one if it was seen before
+    pub fn register(&self, url: &Url) -> usize {
+        let mut items = self.items.lock().unwrap();
+        if let Some(&n) = items.index.get(url.as_str()) {
+            return n;
+        }
+
+        let n = items.first + items.urls.len();
+        items.urls.push_back(url.as_str().to_string());
+        items.index.insert(url.as_str().to_string(), n);
+
+        if items.urls.len() > MAX_ITEMS_PER_SESSION {
+            if let Some(old) = items.urls.pop_front() {
+                items.index.remove(&old);
+            }
+            items.first += 1;
+        }
+        n
+    }
+
+    pub fn url(&self, n: usize) -> Option<String> {
+        let items = self.items.lock().unwrap();
+        n.checked_sub(items.first)
+            .and_then(|i| items.urls.get(i))
+            .cloned()
+    }
+
+    // Path clients use to fetch `url` through this session
+    pub fn path_for(&self, url: &Url) -> String {
+        let n = self.register(url);
+        match extension(url) {
+            Some(ext) => format!("/s/{}/{}.{}", self.id, n, ext),
+            None => format!("/s/{}/{}", self.id, n),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_used.lock().unwrap() = Instant::now();
+    }
+
+    fn expired(&self, now: Instant, ttl: Duration) -> bool {
+        now.duration_since(*self.last_used.lock().unwrap()) > ttl
+    }
+}
+
+// File extension of the URL's last path segment, kept so players that sniff
+// by extension still see .ts/.m3u8/.vtt
+fn extension(url: &Url) -> Option<&str> {
+    let last = url.path_segments()?.next_back()?;
+    let (_, ext) = last.rsplit_once('.')?;
+    let valid = !ext.is_empty() && ext.len() <= 5 && ext.bytes().all(|b| b.is_ascii_alphanumeric());
+    valid.then_some(ext)
+}
+
+fn new_id() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>())
+}
+
+pub fn create(origin: Option<String>, headers: Option<String>) -> Arc<Session> {
+    let session = Arc::new(Session {
+        id: new_id(),
+        origin,
+        headers,
+        items: Mutex::new(Items {
+            urls: VecDeque::new(),
+            first: 0,
+            index: HashMap::new(),
+        }),
+        last_used: Mutex::new(Instant::now()),
+    });
+    STORE.write().unwrap().insert(session.clone(), MAX_SESSIONS);
+    session
+}
+
+// Look up a live session, extending its lifetime
+pub fn get(id: &str) -> Option<Arc<Session>> {
+    let session = STORE.read().unwrap().sessions.get(id).cloned()?;
+    let ttl = Duration::from_secs(config::current().sessions.ttl_secs);
+    if session.expired(Instant::now(), ttl) {
+        return None;
+    }
+    session.touch();
+    Some(session)
+}
+
+// "<n>" or "<n>.<ext>" -> n
+pub fn parse_item(item: &str) -> Option<usize> {
+    item.split('.').next()?.parse().ok()
+}
+
+// Periodically drop sessions nobody has used within the TTL. Runs whether or
+// not session mode is on, since a config reload can switch it either way.
+pub fn spawn_sweeper() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let ttl = Duration::from_secs(config::current().sessions.ttl_secs);
+            STORE.write().unwrap().sweep(Instant::now(), ttl);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(path: &str) -> Url {
+        Url::parse("https://cdn.example.com").unwrap().join(path).unwrap()
+    }
+
+    #[test]
+    fn numbers_urls_in_order_of_first_use() {
+        let session = create(Some("https://player.example.com".to_string()), None);
+        assert_eq!(session.path_for(&url("/live/index.m3u8")), format!("/s/{}/0.m3u8", session.id));
+        assert_eq!(session.path_for(&url("/live/seg0.ts?token=1")), format!("/s/{}/1.ts", session.id));
+        assert_eq!(session.path_for(&url("/live/index.m3u8")), format!("/s/{}/0.m3u8", session.id));
+        assert_eq!(session.path_for(&url("/key")), format!("/s/{}/2", session.id));
+        assert_eq!(session.path_for(&url("/weird.t$s")), format!("/s/{}/3", session.id));
+
+        assert_eq!(session.url(1).as_deref(), Some("https://cdn.example.com/live/seg0.ts?token=1"));
+        assert_eq!(session.url(4), None);
+        assert_eq!(parse_item("1.ts"), Some(1));
+        assert_eq!(parse_item("2"), Some(2));
+        assert_eq!(parse_item("x.ts"), None);
+
+        let found = get(&session.id).unwrap();
+        assert_eq!(found.origin.as_deref(), Some("https://player.example.com"));
+        assert!(get("unknown").is_none());
+    }
+
+    #[test]
+    fn forgets_the_oldest_urls_beyond_the_cap() {
+        let session = create(None, None);
+        for n in 0..=MAX_ITEMS_PER_SESSION {
+            session.register(&url(&format!("/seg{}.ts", n)));
+        }
+        assert_eq!(session.url(0), None);
+        assert_eq!(session.url(1).as_deref(), Some("https://cdn.example.com/seg1.ts"));
+        assert_eq!(
+            session.url(MAX_ITEMS_PER_SESSION).as_deref(),
+            Some(format!("https://cdn.example.com/seg{}.ts", MAX_ITEMS_PER_SESSION).as_str())
+        );
+        // Seen again after being forgotten, it gets a new number
+        assert_eq!(session.register(&url("/seg0.ts")), MAX_ITEMS_PER_SESSION + 1);
+    }
+
+    #[test]
+    fn drops_the_oldest_sessions_beyond_the_cap() {
+        let mut store = Store::default();
+        let sessions: Vec<_> = (0..5).map(|_| create(None, None)).collect();
+        for session in &sessions {
+            store.insert(session.clone(), 3);
+        }
+        let mut kept: Vec<_> = store.sessions.keys().cloned().collect();
+        kept.sort();
+        let mut newest: Vec<_> = sessions[2..].iter().map(|s| s.id.clone()).collect();
+        newest.sort();
+        assert_eq!(kept, newest);
+        assert_eq!(store.order.len(), 3);
+    }
+
+    #[test]
+    fn expires_idle_sessions() {
+        let ttl = Duration::from_secs(60);
+        let mut store = Store::default();
+        let idle = create(None, None);
+        let busy = create(None, None);
+        store.insert(idle.clone(), 10);
+        store.insert(busy.clone(), 10);
+
+        let now = Instant::now();
+        assert!(!idle.expired(now, ttl));
+        let later = now + ttl + Duration::from_secs(1);
+        assert!(idle.expired(later, ttl));
+
+        // Used since: good for another ttl
+        *busy.last_used.lock().unwrap() = now + ttl;
+        store.sweep(later, ttl);
+        assert!(!store.sessions.contains_key(&idle.id));
+        assert!(store.sessions.contains_key(&busy.id));
+        assert_eq!(store.order.iter().collect::<Vec<_>>(), [&busy.id]);
+    }
+}
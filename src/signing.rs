@@ -0,0 +1,201 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// [signing] section of the config file. Setting a secret turns signing mode
+// on: every /?url=... request must then carry a valid `expires` + `sig` pair.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    pub secret: Option<String>,
+    // Lifetime of the signatures the proxy puts on rewritten playlist URLs
+    pub child_ttl_secs: u64,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        SigningConfig {
+            secret: None,
+            child_ttl_secs: 6 * 60 * 60,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Signed message: the decoded url, origin and headers params plus the expiry,
+// newline separated (missing params are empty strings)
+fn mac(secret: &[u8], url: &str, origin: Option<&str>, headers: Option<&str>, expires: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(url.as_bytes());
+    mac.update(b"\n");
+    mac.update(origin.unwrap_or("").as_bytes());
+    mac.update(b"\n");
+    mac.update(headers.unwrap_or("").as_bytes());
+    mac.update(b"\n");
+    mac.update(expires.to_string().as_bytes());
+    mac
+}
+
+impl SigningConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.secret.as_deref() == Some("") {
+            return Err("secret must not be empty; leave it out to turn signing off".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    // "&expires=...&sig=..." for a child URL, or None when signing is off
+    pub fn sign_child(&self, url: &str, origin: Option<&str>, headers: Option<&str>) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+        let expires = now() + self.child_ttl_secs;
+        let sig = URL_SAFE_NO_PAD.encode(mac(secret.as_bytes(), url, origin, headers, expires).finalize().into_bytes());
+        Some(format!("&expires={}&sig={}", expires, sig))
+    }
+
+    // Check the expires/sig params of an incoming request
+    pub fn verify(
+        &self,
+        url: &str,
+        origin: Option<&str>,
+        headers: Option<&str>,
+        expires: Option<&str>,
+        sig: Option<&str>,
+    ) -> Result<(), &'static str> {
+        let Some(secret) = self.secret.as_ref() else {
+            return Ok(());
+        };
+
+        let (Some(expires), Some(sig)) = (expires, sig) else {
+            return Err("Missing signature");
+        };
+        let expires: u64 = expires.parse().map_err(|_| "Invalid expiry")?;
+        if expires < now() {
+            return Err("Signature expired");
+        }
+
+        let sig = URL_SAFE_NO_PAD.decode(sig).map_err(|_| "Invalid signature")?;
+        mac(secret.as_bytes(), url, origin, headers, expires)
+            .verify_slice(&sig)
+            .map_err(|_| "Invalid signature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://cdn.example.com/live/index.m3u8";
+    const ORIGIN: Option<&str> = Some("https://player.example.com");
+    const HEADERS: Option<&str> = Some("eyJSZWZlcmVyIjoiaHR0cHM6Ly9leGFtcGxlLmNvbS8ifQ");
+
+    fn signing() -> SigningConfig {
+        SigningConfig {
+            secret: Some("s3cret".to_string()),
+            ..SigningConfig::default()
+        }
+    }
+
+    // expires and sig out of a "&expires=...&sig=..." suffix
+    fn params(signature: &str) -> (String, String) {
+        let mut expires = None;
+        let mut sig = None;
+        for pair in signature.trim_start_matches('&').split('&') {
+            match pair.split_once('=') {
+                Some(("expires", v)) => expires = Some(v.to_string()),
+                Some(("sig", v)) => sig = Some(v.to_string()),
+                _ => panic!("unexpected param {:?}", pair),
+            }
+        }
+        (expires.unwrap(), sig.unwrap())
+    }
+
+    fn sig_for(config: &SigningConfig, expires: u64) -> String {
+        let secret = config.secret.as_ref().unwrap();
+        URL_SAFE_NO_PAD.encode(mac(secret.as_bytes(), URL, ORIGIN, HEADERS, expires).finalize().into_bytes())
+    }
+
+    #[test]
+    fn signed_child_urls_verify() {
+        let config = signing();
+        let (expires, sig) = params(&config.sign_child(URL, ORIGIN, HEADERS).unwrap());
+        assert!(expires.parse::<u64>().unwrap() >= now() + config.child_ttl_secs - 1);
+        assert_eq!(config.verify(URL, ORIGIN, HEADERS, Some(&expires), Some(&sig)), Ok(()));
+
+        let (expires, sig) = params(&config.sign_child(URL, None, None).unwrap());
+        assert_eq!(config.verify(URL, None, None, Some(&expires), Some(&sig)), Ok(()));
+    }
+
+    #[test]
+    fn expired_signatures_are_refused() {
+        let config = signing();
+        let expires = now() - 1;
+        let sig = sig_for(&config, expires);
+        assert_eq!(
+            config.verify(URL, ORIGIN, HEADERS, Some(&expires.to_string()), Some(&sig)),
+            Err("Signature expired")
+        );
+    }
+
+    #[test]
+    fn tampered_params_are_refused() {
+        let config = signing();
+        let (expires, sig) = params(&config.sign_child(URL, ORIGIN, HEADERS).unwrap());
+        let verify = |url, origin, headers, expires: &str, sig: &str| {
+            config.verify(url, origin, headers, Some(expires), Some(sig))
+        };
+
+        assert_eq!(verify("https://cdn.example.com/live/other.m3u8", ORIGIN, HEADERS, &expires, &sig), Err("Invalid signature"));
+        assert_eq!(verify(URL, Some("https://evil.com"), HEADERS, &expires, &sig), Err("Invalid signature"));
+        assert_eq!(verify(URL, None, HEADERS, &expires, &sig), Err("Invalid signature"));
+        assert_eq!(verify(URL, ORIGIN, Some("e30"), &expires, &sig), Err("Invalid signature"));
+        assert_eq!(verify(URL, ORIGIN, None, &expires, &sig), Err("Invalid signature"));
+        let later = (expires.parse::<u64>().unwrap() + 1).to_string();
+        assert_eq!(verify(URL, ORIGIN, HEADERS, &later, &sig), Err("Invalid signature"));
+        assert_eq!(verify(URL, ORIGIN, HEADERS, &expires, "not base64!"), Err("Invalid signature"));
+        assert_eq!(verify(URL, ORIGIN, HEADERS, "soon", &sig), Err("Invalid expiry"));
+
+        let other = SigningConfig {
+            secret: Some("other".to_string()),
+            ..SigningConfig::default()
+        };
+        assert_eq!(other.verify(URL, ORIGIN, HEADERS, Some(&expires), Some(&sig)), Err("Invalid signature"));
+    }
+
+    #[test]
+    fn missing_params_are_refused() {
+        let config = signing();
+        let (expires, sig) = params(&config.sign_child(URL, ORIGIN, HEADERS).unwrap());
+        assert_eq!(config.verify(URL, ORIGIN, HEADERS, None, None), Err("Missing signature"));
+        assert_eq!(config.verify(URL, ORIGIN, HEADERS, Some(&expires), None), Err("Missing signature"));
+        assert_eq!(config.verify(URL, ORIGIN, HEADERS, None, Some(&sig)), Err("Missing signature"));
+    }
+
+    #[test]
+    fn disabled_without_a_secret() {
+        let config = SigningConfig::default();
+        assert!(!config.enabled());
+        assert_eq!(config.sign_child(URL, ORIGIN, HEADERS), None);
+        assert_eq!(config.verify(URL, ORIGIN, HEADERS, None, None), Ok(()));
+
+        let empty = SigningConfig {
+            secret: Some(String::new()),
+            ..SigningConfig::default()
+        };
+        assert!(empty.validate().is_err());
+        assert!(signing().validate().is_ok());
+    }
+}
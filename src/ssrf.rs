@@ -0,0 +1,394 @@
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect,
+};
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    error::Error,
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use url::{Host, Url};
+
+use crate::config;
+
+// Header listing the redirect hops of the upstream fetch, when enabled
+pub const REDIRECT_CHAIN_HEADER: &str = "X-Proxy-Redirect-Chain";
+
+tokio::task_local! {
+    // URLs redirected to by the fetch running in this task, see with_redirect_chain
+    static REDIRECT_CHAIN: RefCell<Vec<Url>>;
+}
+
+// [ssrf] section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SsrfConfig {
+    pub enabled: bool,
+    // Hosts (names or IP literals) that may resolve to internal addresses
+    pub allow_hosts: Vec<String>,
+}
+
+impl Default for SsrfConfig {
+    fn default() -> Self {
+        SsrfConfig {
+            enabled: true,
+            allow_hosts: Vec::new(),
+        }
+    }
+}
+
+// [redirects] section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedirectConfig {
+    // Redirects followed for a single upstream fetch; 0 refuses any redirect
+    pub max_hops: usize,
+    // Refuse redirects that switch between http and https
+    pub same_scheme: bool,
+    // Send the hops followed back to the client in X-Proxy-Redirect-Chain
+    pub expose_chain: bool,
+}
+
+impl Default for RedirectConfig {
+    fn default() -> Self {
+        RedirectConfig {
+            max_hops: 10,
+            same_scheme: false,
+            expose_chain: false,
+        }
+    }
+}
+
+impl SsrfConfig {
+    fn is_trusted(&self, host: &str) -> bool {
+        let unbracket = |h: &str| h.trim_start_matches('[').trim_end_matches(']').to_string();
+        let host = unbracket(host);
+        self.allow_hosts.iter().any(|h| unbracket(h).eq_ignore_ascii_case(&host))
+    }
+}
+
+// Returned when a destination is refused; surfaced to the client as a 403
+#[derive(Debug)]
+pub struct BlockedError(pub String);
+
+impl fmt::Display for BlockedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for BlockedError {}
+
+// Addresses the proxy must never connect to on behalf of a client
+pub fn is_forbidden_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_forbidden_v4(v4),
+        IpAddr::V6(v6) => {
+            if v6.is_loopback() || v6.is_unspecified() {
+                return true;
+            }
+            if let Some(v4) = embedded_v4(v6) {
+                return is_forbidden_v4(v4);
+            }
+            let first = v6.segments()[0];
+            v6.is_multicast()
+                || (first & 0xfe00) == 0xfc00 // unique local fc00::/7
+                || (first & 0xffc0) == 0xfe80 // link-local fe80::/10
+                || (first & 0xffc0) == 0xfec0 // site-local fec0::/10 (deprecated)
+        }
+    }
+}
+
+fn is_forbidden_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_broadcast()
+        || ip.is_unspecified()
+        || a == 0 // "this network" 0.0.0.0/8
+        || (a == 100 && (b & 0xc0) == 64) // carrier-grade NAT 100.64.0.0/10
+        || (a == 192 && b == 0 && ip.octets()[2] == 0) // IETF protocol assignments 192.0.0.0/24
+        || (a == 198 && (b & 0xfe) == 18) // benchmarking 198.18.0.0/15
+        || a >= 240 // reserved 240.0.0.0/4
+}
+
+// IPv4 address an IPv6 one stands for, which must be checked instead:
+// IPv4-mapped ::ffff:a.b.c.d, IPv4-compatible ::a.b.c.d, NAT64 64:ff9b::/96
+// and 6to4 2002:aabb:ccdd::/48
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let s = ip.segments();
+    let v4 = |hi: u16, lo: u16| Ipv4Addr::new((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8);
+    match s {
+        [0, 0, 0, 0, 0, 0 | 0xffff, hi, lo] => Some(v4(hi, lo)),
+        [0x64, 0xff9b, 0, 0, 0, 0, hi, lo] => Some(v4(hi, lo)),
+        [0x2002, hi, lo, ..] => Some(v4(hi, lo)),
+        _ => None,
+    }
+}
+
+// Checks that need no DNS: scheme and IP-literal hosts.
+// Hostnames are checked by GuardedResolver when the connection is made.
+pub fn check_url(url: &Url, ssrf: &SsrfConfig) -> Result<(), BlockedError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(BlockedError(format!("Scheme not allowed: {}", url.scheme())));
+    }
+    if !ssrf.enabled {
+        return Ok(());
+    }
+
+    let ip = match url.host() {
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        Some(Host::Domain(_)) => return Ok(()),
+        None => return Err(BlockedError("URL has no host".to_string())),
+    };
+
+    if is_forbidden_ip(ip) && !ssrf.is_trusted(&ip.to_string()) {
+        return Err(BlockedError(format!("Destination not allowed: {}", ip)));
+    }
+    Ok(())
+}
+
+// Whether a reqwest error was caused by the SSRF guard
+pub fn blocked_reason(err: &reqwest::Error) -> Option<String> {
+    let mut source: Option<&(dyn Error + 'static)> = err.source();
+    while let Some(e) = source {
+        if let Some(blocked) = e.downcast_ref::<BlockedError>() {
+            return Some(blocked.0.clone());
+        }
+        source = e.source();
+    }
+    None
+}
+
+// DNS resolver that drops internal addresses, so a hostname that resolves
+// (or rebinds) to 127.0.0.1 or 169.254.169.254 can't be reached. Runs for
+// every connection, which covers every redirect hop as well.
+pub struct GuardedResolver;
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let resolved: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .collect();
+
+            let config = config::current();
+            let ssrf = &config.ssrf;
+            if !ssrf.enabled || ssrf.is_trusted(&host) {
+                let addrs: Addrs = Box::new(resolved.into_iter());
+                return Ok(addrs);
+            }
+
+            let allowed: Vec<SocketAddr> = resolved
+                .into_iter()
+                .filter(|addr| !is_forbidden_ip(addr.ip()))
+                .collect();
+            if allowed.is_empty() {
+                return Err(Box::new(BlockedError(format!(
+                    "Destination not allowed: {} resolves to an internal address",
+                    host
+                ))) as Box<dyn Error + Send + Sync>);
+            }
+
+            let addrs: Addrs = Box::new(allowed.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+// Redirect policy that re-applies the scheme/IP-literal checks and the host
+// policy on every hop, within the limits of the [redirects] section
+pub fn redirect_policy() -> redirect::Policy {
+    redirect::Policy::custom(|attempt| {
+        let config = config::current();
+        let redirects = &config.redirects;
+        if attempt.previous().len() > redirects.max_hops {
+            return attempt.error(BlockedError("Too many redirects".to_string()));
+        }
+        let from = attempt.previous().last().map_or("", |u| u.scheme());
+        if redirects.same_scheme && attempt.url().scheme() != from {
+            let reason = format!("Redirect from {} to {} not allowed", from, attempt.url().scheme());
+            return attempt.error(BlockedError(reason));
+        }
+        if let Err(e) = check_url(attempt.url(), &config.ssrf) {
+            return attempt.error(e);
+        }
+        if let Err(reason) = config.check_upstream(attempt.url()) {
+            return attempt.error(BlockedError(reason));
+        }
+
+        let _ = REDIRECT_CHAIN.try_with(|chain| chain.borrow_mut().push(attempt.url().clone()));
+        attempt.follow()
+    })
+}
+
+// Run an upstream fetch, also returning the URLs it was redirected to in order.
+// The redirect policy runs while the fetch is polled, so a task-local works.
+pub async fn with_redirect_chain<F: Future>(fetch: F) -> (F::Output, Vec<Url>) {
+    REDIRECT_CHAIN
+        .scope(RefCell::new(Vec::new()), async {
+            let output = fetch.await;
+            (output, REDIRECT_CHAIN.with(|chain| chain.take()))
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn forbidden(ip: &str) -> bool {
+        is_forbidden_ip(ip.parse().unwrap())
+    }
+
+    fn check(url: &str, ssrf: &SsrfConfig) -> Result<(), String> {
+        check_url(&Url::parse(url).unwrap(), ssrf).map_err(|e| e.0)
+    }
+
+    #[test]
+    fn blocks_internal_ipv4_ranges() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "127.0.0.1",
+            "127.255.255.254",
+            "169.254.169.254",
+            "224.0.0.1",
+            "255.255.255.255",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "100.127.255.255",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+        ] {
+            assert!(forbidden(ip), "{} should be blocked", ip);
+        }
+    }
+
+    #[test]
+    fn allows_public_ipv4() {
+        for ip in ["8.8.8.8", "1.1.1.1", "172.32.0.1", "100.128.0.1", "192.0.1.1", "198.20.0.1", "93.184.216.34"] {
+            assert!(!forbidden(ip), "{} should be allowed", ip);
+        }
+    }
+
+    #[test]
+    fn blocks_internal_ipv6_ranges() {
+        for ip in ["::1", "::", "fc00::1", "fd12:3456::1", "fe80::1", "febf::1", "fec0::1", "ff02::1"] {
+            assert!(forbidden(ip), "{} should be blocked", ip);
+        }
+        for ip in ["2606:4700:4700::1111", "2001:4860:4860::8888"] {
+            assert!(!forbidden(ip), "{} should be allowed", ip);
+        }
+    }
+
+    #[test]
+    fn checks_ipv4_embedded_in_ipv6() {
+        for ip in [
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "::ffff:169.254.169.254",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::",
+            "2002:a9fe:a9fe::1",
+            "2002:c0a8:0101:1::1",
+        ] {
+            assert!(forbidden(ip), "{} should be blocked", ip);
+        }
+        for ip in ["::ffff:8.8.8.8", "::8.8.8.8", "64:ff9b::808:808", "2002:808:808::1"] {
+            assert!(!forbidden(ip), "{} should be allowed", ip);
+        }
+    }
+
+    #[test]
+    fn check_url_refuses_schemes_and_internal_literals() {
+        let ssrf = SsrfConfig::default();
+        assert!(check("https://example.com/a.m3u8", &ssrf).is_ok());
+        assert!(check("http://8.8.8.8/a.ts", &ssrf).is_ok());
+        assert_eq!(check("ftp://example.com/a.ts", &ssrf).unwrap_err(), "Scheme not allowed: ftp");
+        assert_eq!(check("file:///etc/passwd", &ssrf).unwrap_err(), "Scheme not allowed: file");
+        assert_eq!(check("http://127.0.0.1:8080/", &ssrf).unwrap_err(), "Destination not allowed: 127.0.0.1");
+        assert_eq!(check("http://[::ffff:7f00:1]/", &ssrf).unwrap_err(), "Destination not allowed: ::ffff:127.0.0.1");
+        assert_eq!(check("http://[2002:7f00:1::]/", &ssrf).unwrap_err(), "Destination not allowed: 2002:7f00:1::");
+        // Decimal and hex forms are normalized by the URL parser first
+        assert!(check("http://2130706433/", &ssrf).is_err());
+        assert!(check("http://0x7f.1/", &ssrf).is_err());
+        // Names are left to the resolver
+        assert!(check("http://localhost/", &ssrf).is_ok());
+    }
+
+    #[test]
+    fn allow_hosts_and_disabling_let_internal_addresses_through() {
+        let trusted = SsrfConfig {
+            allow_hosts: vec!["10.0.0.5".to_string(), "[::1]".to_string()],
+            ..SsrfConfig::default()
+        };
+        assert!(check("http://10.0.0.5/live.m3u8", &trusted).is_ok());
+        assert!(check("http://[::1]:8080/live.m3u8", &trusted).is_ok());
+        assert!(check("http://10.0.0.6/live.m3u8", &trusted).is_err());
+
+        let disabled = SsrfConfig { enabled: false, ..SsrfConfig::default() };
+        assert!(check("http://127.0.0.1/", &disabled).is_ok());
+        assert!(check("gopher://127.0.0.1/", &disabled).is_err());
+    }
+
+    #[tokio::test]
+    async fn resolver_refuses_names_of_internal_addresses() {
+        let err = GuardedResolver.resolve("localhost".parse().unwrap()).await.err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<BlockedError>().map(|e| e.0.as_str()),
+            Some("Destination not allowed: localhost resolves to an internal address")
+        );
+    }
+
+    // Local server answering every request with a redirect to `location`
+    async fn redirecting_server(location: &'static str) -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0u8; 1024];
+                let _ = socket.read(&mut request).await;
+                let response = format!(
+                    "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    location
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn redirects_are_checked_on_every_hop() {
+        let client = reqwest::Client::builder().redirect(redirect_policy()).build().unwrap();
+        for (location, reason) in [
+            ("http://169.254.169.254/latest/meta-data/", "Destination not allowed: 169.254.169.254"),
+            ("http://[::ffff:10.0.0.1]/", "Destination not allowed: ::ffff:10.0.0.1"),
+            ("http://[2002:7f00:1::]/", "Destination not allowed: 2002:7f00:1::"),
+            ("http://10.0.0.1:8080/", "Destination not allowed: 10.0.0.1"),
+        ] {
+            let start = redirecting_server(location).await;
+            let (result, chain) = with_redirect_chain(client.get(start.as_str()).send()).await;
+            let err = result.err().unwrap_or_else(|| panic!("{} was followed", location));
+            assert_eq!(blocked_reason(&err).as_deref(), Some(reason), "{}", location);
+            assert!(chain.is_empty());
+        }
+    }
+}
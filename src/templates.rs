@@ -1,23 +1,29 @@
-use regex::Regex;
+use regex::{Regex, RegexSet};
 use once_cell::sync::Lazy;
+use serde::Deserialize;
 use url::Url;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use std::collections::HashMap;
+use std::fmt;
 use std::str::FromStr;
 
 // Define default headers used by most templates
-static DEFAULT_HEADERS: Lazy<HashMap<String, String>> = Lazy::new(|| {
-    HashMap::from([
-        ("user-agent".to_string(), "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:137.0) Gecko/20100101 Firefox/137.0".to_string()),
-        ("accept".to_string(), "*/*".to_string()),
-        ("accept-language".to_string(), "en-US,en;q=0.5".to_string()),
-        ("sec-fetch-dest".to_string(), "empty".to_string()),
-        ("sec-fetch-mode".to_string(), "cors".to_string()),
-        ("sec-fetch-site".to_string(), "cross-site".to_string()),
-    ])
+static DEFAULT_HEADERS: Lazy<HeaderMap> = Lazy::new(|| {
+    let mut headers = HeaderMap::new();
+    for (key, value) in [
+        ("user-agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:137.0) Gecko/20100101 Firefox/137.0"),
+        ("accept", "*/*"),
+        ("accept-language", "en-US,en;q=0.5"),
+        ("sec-fetch-dest", "empty"),
+        ("sec-fetch-mode", "cors"),
+        ("sec-fetch-site", "cross-site"),
+    ] {
+        headers.insert(HeaderName::from_static(key), HeaderValue::from_static(value));
+    }
+    headers
 });
 
-// Define domain group configuration - simplified and focused
+// Built-in domain groups - only used when no config file provides its own list
 struct DomainGroup {
     patterns: Vec<&'static str>,
     origin: &'static str,
@@ -225,20 +231,140 @@ static DOMAIN_GROUPS: Lazy<Vec<DomainGroup>> = Lazy::new(|| {
     ]
 });
 
-// Generate headers for a URL with optional custom origin
-pub fn generate_headers_for_url(url: &Url, custom_origin: Option<&str>) -> HeaderMap {
-    let mut headers = HeaderMap::new();
-    
-    // Add base headers
-    for (key, value) in DEFAULT_HEADERS.iter() {
-        if let (Ok(name), Ok(val)) = (
-            HeaderName::from_str(key),
-            HeaderValue::from_str(value),
-        ) {
-            headers.insert(name, val);
+// Domain group as read from a config file
+#[derive(Debug, Clone, Deserialize)]
+pub struct DomainGroupConfig {
+    pub patterns: Vec<String>,
+    pub origin: String,
+    pub referer: String,
+    #[serde(default)]
+    pub custom_headers: Option<HashMap<String, String>>,
+}
+
+impl From<&DomainGroup> for DomainGroupConfig {
+    fn from(group: &DomainGroup) -> Self {
+        DomainGroupConfig {
+            patterns: group.patterns.iter().map(|p| p.to_string()).collect(),
+            origin: group.origin.to_string(),
+            referer: group.referer.to_string(),
+            custom_headers: group.custom_headers.as_ref().map(|h| {
+                h.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+            }),
+        }
+    }
+}
+
+// Validation failure for a single domain group
+#[derive(Debug)]
+pub struct TemplateError {
+    pub group: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "domain_groups[{}]: {}", self.group, self.message)
+    }
+}
+
+// Domain group with its header values validated up front
+struct CompiledGroup {
+    origin: HeaderValue,
+    referer: HeaderValue,
+    custom_headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+// The active set of domain groups used to pick upstream headers.
+// All patterns are compiled once into a single RegexSet, so matching a
+// hostname is one pass no matter how many groups are configured.
+pub struct Templates {
+    groups: Vec<CompiledGroup>,
+    patterns: RegexSet,
+    // Group index for every pattern in `patterns`, in the same order
+    pattern_groups: Vec<usize>,
+}
+
+impl Templates {
+    pub fn compile(groups: &[DomainGroupConfig]) -> Result<Templates, TemplateError> {
+        let mut compiled = Vec::with_capacity(groups.len());
+        let mut all_patterns = Vec::new();
+        let mut pattern_groups = Vec::new();
+
+        for (index, group) in groups.iter().enumerate() {
+            let err = |message: String| TemplateError { group: index, message };
+
+            if group.patterns.is_empty() {
+                return Err(err("no patterns given".to_string()));
+            }
+
+            // Compile each pattern on its own first so errors point at the right group
+            for pattern in &group.patterns {
+                Regex::new(pattern)
+                    .map_err(|e| err(format!("invalid regex {:?}: {}", pattern, e)))?;
+                all_patterns.push(pattern.as_str());
+                pattern_groups.push(index);
+            }
+
+            let origin = HeaderValue::from_str(&group.origin)
+                .map_err(|_| err(format!("invalid origin {:?}", group.origin)))?;
+            let referer = HeaderValue::from_str(&group.referer)
+                .map_err(|_| err(format!("invalid referer {:?}", group.referer)))?;
+
+            let mut custom_headers = Vec::new();
+            if let Some(extra) = &group.custom_headers {
+                for (key, value) in extra {
+                    let name = HeaderName::from_str(key)
+                        .map_err(|_| err(format!("invalid header name {:?}", key)))?;
+                    let val = HeaderValue::from_str(value)
+                        .map_err(|_| err(format!("invalid value for header {:?}", key)))?;
+                    custom_headers.push((name, val));
+                }
+            }
+
+            compiled.push(CompiledGroup { origin, referer, custom_headers });
         }
+
+        let patterns = RegexSet::new(&all_patterns).map_err(|e| TemplateError {
+            group: 0,
+            message: format!("failed to build pattern set: {}", e),
+        })?;
+
+        Ok(Templates { groups: compiled, patterns, pattern_groups })
+    }
+
+    // Compiled form of the built-in DOMAIN_GROUPS list
+    pub fn builtin() -> Templates {
+        Templates::compile(&builtin_groups()).expect("built-in domain groups must be valid")
     }
 
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    // Index of the first group with a pattern matching the hostname.
+    // Patterns are stored in group order, so the lowest matching pattern wins.
+    pub fn find_group(&self, hostname: &str) -> Option<usize> {
+        self.patterns
+            .matches(hostname)
+            .iter()
+            .next()
+            .map(|pattern| self.pattern_groups[pattern])
+    }
+
+    fn find(&self, hostname: &str) -> Option<&CompiledGroup> {
+        self.find_group(hostname).map(|index| &self.groups[index])
+    }
+}
+
+pub fn builtin_groups() -> Vec<DomainGroupConfig> {
+    DOMAIN_GROUPS.iter().map(DomainGroupConfig::from).collect()
+}
+
+// Generate headers for a URL with optional custom origin
+pub fn generate_headers_for_url(templates: &Templates, url: &Url, custom_origin: Option<&str>) -> HeaderMap {
+    // Start from the base headers
+    let mut headers = DEFAULT_HEADERS.clone();
+
     // If custom origin is provided, use it
     if let Some(origin) = custom_origin {
         if let (Ok(name), Ok(val)) = (
@@ -264,36 +390,14 @@ pub fn generate_headers_for_url(url: &Url, custom_origin: Option<&str>) -> Heade
     } else {
         // Find matching domain template and use its headers
         let hostname = url.host_str().unwrap_or("");
-        if let Some(group) = DOMAIN_GROUPS.iter().find(|g| {
-            g.patterns.iter().any(|pattern| {
-                Regex::new(pattern).map(|re| re.is_match(hostname)).unwrap_or(false)
-            })
-        }) {
+        if let Some(group) = templates.find(hostname) {
             // Add origin and referer from template
-            if let (Ok(name), Ok(val)) = (
-                HeaderName::from_str("origin"),
-                HeaderValue::from_str(group.origin),
-            ) {
-                headers.insert(name, val);
-            }
-            
-            if let (Ok(name), Ok(val)) = (
-                HeaderName::from_str("referer"),
-                HeaderValue::from_str(group.referer),
-            ) {
-                headers.insert(name, val);
-            }
+            headers.insert(reqwest::header::ORIGIN, group.origin.clone());
+            headers.insert(reqwest::header::REFERER, group.referer.clone());
 
             // Add custom headers for this domain group if they exist
-            if let Some(custom_headers) = &group.custom_headers {
-                for (header_name, header_value) in custom_headers {
-                    if let (Ok(name), Ok(val)) = (
-                        HeaderName::from_str(header_name),
-                        HeaderValue::from_str(header_value),
-                    ) {
-                        headers.insert(name, val);
-                    }
-                }
+            for (name, val) in &group.custom_headers {
+                headers.insert(name.clone(), val.clone());
             }
         } else {
             // Fallback: use the URL's own origin and referer when no template is found
@@ -327,6 +431,29 @@ pub fn generate_headers_for_url(url: &Url, custom_origin: Option<&str>) -> Heade
     headers
 }
 
+// Headers for an upstream request: the template ones plus custom headers
+// given as JSON (the `headers` param), which win over the template
+pub fn upstream_headers(
+    templates: &Templates,
+    url: &Url,
+    custom_origin: Option<&str>,
+    header_json: Option<&str>,
+) -> HeaderMap {
+    let mut headers = generate_headers_for_url(templates, url, custom_origin);
+    if let Some(header_json) = header_json {
+        if let Ok(parsed) = serde_json::from_str::<HashMap<String, String>>(header_json) {
+            for (k, v) in parsed {
+                if let (Ok(name), Ok(value)) = (
+                    HeaderName::from_str(&k),
+                    HeaderValue::from_str(&v),
+                ) {
+                    headers.insert(name, value);
+                }
+            }
+        }
+    }
+    headers
+}
 
 // DomainGroup {
 //     patterns: vec![r"(?i)\.example\.com$"],
@@ -0,0 +1,517 @@
+// Upstream responses as the proxy handlers consume them. Concurrent requests
+// for the same upstream URL and headers can share one fetch: the first starts
+// it, later ones join and get everything received so far, then the rest as it
+// arrives. Nothing waits for the whole body.
+
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use once_cell::sync::Lazy;
+use reqwest::{header::HeaderMap, Client, StatusCode};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{watch, Notify};
+use url::Url;
+
+use crate::{errors, ssrf};
+
+// Reqwest client pool
+pub static CLIENT: Lazy<Client> = Lazy::new(|| {
+    Client::builder()
+        .pool_idle_timeout(Duration::from_secs(90))
+        .http2_adaptive_window(true)
+        .pool_max_idle_per_host(10)
+        .danger_accept_invalid_certs(true)  // Accept invalid SSL certificates
+        .dns_resolver(Arc::new(ssrf::GuardedResolver))  // Refuse internal addresses after DNS
+        .redirect(ssrf::redirect_policy())
+        .connect_timeout(errors::connect_timeout())
+        .build()
+        .expect("Failed to build reqwest client")
+});
+
+// Past this many bytes a shared fetch takes no new joiners, so the chunks
+// every current reader has seen can be let go
+const MAX_SHARED_BUFFER: usize = 32 * 1024 * 1024;
+
+// How far a shared fetch reads ahead of its slowest reader before it waits,
+// so slow clients still hold the upstream back as they did unshared
+const MAX_READ_AHEAD: usize = 8 * 1024 * 1024;
+
+// Fetch errors are handed to every request sharing the fetch
+pub type FetchError = Arc<reqwest::Error>;
+
+pub type Body = Pin<Box<dyn Stream<Item = Result<Bytes, FetchError>>>>;
+
+pub struct Upstream {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    // Final URL, after redirects
+    pub url: Url,
+    pub redirect_chain: Vec<Url>,
+    pub body: Body,
+    // Whether this request started the fetch rather than joining another's
+    pub leader: bool,
+}
+
+impl Upstream {
+    // Whole body as text, for playlists that have to be processed at once
+    pub async fn text(mut self) -> Result<String, FetchError> {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = self.body.next().await {
+            bytes.extend_from_slice(&chunk?);
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+// Status line, headers and where the fetch ended up
+#[derive(Clone)]
+struct Head {
+    status: StatusCode,
+    headers: HeaderMap,
+    url: Url,
+    redirect_chain: Vec<Url>,
+}
+
+impl Head {
+    fn of(resp: &reqwest::Response, redirect_chain: Vec<Url>) -> Head {
+        Head {
+            status: resp.status(),
+            headers: resp.headers().clone(),
+            url: resp.url().clone(),
+            redirect_chain,
+        }
+    }
+}
+
+// Fetch for this request alone
+pub async fn fetch<F>(request: F) -> Result<Upstream, FetchError>
+where
+    F: Future<Output = reqwest::Result<reqwest::Response>>,
+{
+    let (resp, redirect_chain) = ssrf::with_redirect_chain(request).await;
+    let resp = resp.map_err(Arc::new)?;
+    let head = Head::of(&resp, redirect_chain);
+    Ok(Upstream {
+        status: head.status,
+        headers: head.headers,
+        url: head.url,
+        redirect_chain: head.redirect_chain,
+        body: Box::pin(resp.bytes_stream().map(|chunk| chunk.map_err(Arc::new))),
+        leader: true,
+    })
+}
+
+#[derive(Default)]
+struct FlightState {
+    head: Option<Result<Head, FetchError>>,
+    chunks: VecDeque<Bytes>,
+    // Index of chunks[0] in the whole body
+    first: usize,
+    buffered: usize,
+    // Bytes of the whole body received so far
+    received: usize,
+    // Set once the body has ended or failed
+    end: Option<Result<(), FetchError>>,
+    joinable: bool,
+    // Index of the next chunk each reader will get, and the bytes it has had
+    readers: HashMap<u64, (usize, usize)>,
+    next_reader: u64,
+}
+
+impl FlightState {
+    // Bytes received that the slowest reader hasn't had yet
+    fn unread(&self) -> usize {
+        let read = self.readers.values().map(|&(_, bytes)| bytes).min();
+        read.map_or(0, |read| self.received - read)
+    }
+
+    // Chunks behind every reader are only needed by future joiners
+    fn trim(&mut self) {
+        if self.joinable {
+            return;
+        }
+        let needed = self.readers.values().map(|&(chunk, _)| chunk).min().unwrap_or(usize::MAX);
+        while self.first < needed {
+            let Some(chunk) = self.chunks.pop_front() else {
+                break;
+            };
+            self.first += 1;
+            self.buffered -= chunk.len();
+        }
+    }
+}
+
+struct Flight {
+    state: Mutex<FlightState>,
+    // Bumped on every state change readers may be waiting for
+    changed: watch::Sender<u64>,
+    // Signalled when a reader moves on or leaves, for a fetch waiting on the slowest one
+    advanced: Notify,
+}
+
+impl Flight {
+    fn update<T>(&self, f: impl FnOnce(&mut FlightState) -> T) -> T {
+        let result = f(&mut self.state.lock().unwrap());
+        self.changed.send_modify(|version| *version += 1);
+        result
+    }
+
+    fn add_reader(self: &Arc<Self>, state: &mut FlightState) -> Reader {
+        let id = state.next_reader;
+        state.next_reader += 1;
+        state.readers.insert(id, (0, 0));
+        Reader {
+            flight: self.clone(),
+            id,
+            changes: self.changed.subscribe(),
+            done: false,
+        }
+    }
+}
+
+// Fetches that can still be joined, by cache::key
+static FLIGHTS: Lazy<Mutex<HashMap<String, Arc<Flight>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn detach(key: &str, flight: &Arc<Flight>) {
+    let mut flights = FLIGHTS.lock().unwrap();
+    if flights.get(key).is_some_and(|f| Arc::ptr_eq(f, flight)) {
+        flights.remove(key);
+    }
+}
+
+// Fetch shared with every concurrent request for the same key. `request` is
+// only sent if no fetch for the key is under way.
+pub async fn shared<F>(key: String, request: F) -> Result<Upstream, FetchError>
+where
+    F: Future<Output = reqwest::Result<reqwest::Response>> + Send + 'static,
+{
+    let (mut reader, leader) = {
+        let mut flights = FLIGHTS.lock().unwrap();
+        let joined = flights.get(&key).and_then(|flight| {
+            let mut state = flight.state.lock().unwrap();
+            state.joinable.then(|| flight.add_reader(&mut state))
+        });
+        match joined {
+            Some(reader) => (reader, false),
+            None => {
+                let flight = Arc::new(Flight {
+                    state: Mutex::new(FlightState {
+                        joinable: true,
+                        ..FlightState::default()
+                    }),
+                    changed: watch::channel(0).0,
+                    advanced: Notify::new(),
+                });
+                let reader = flight.add_reader(&mut flight.state.lock().unwrap());
+                flights.insert(key.clone(), flight.clone());
+                tokio::spawn(drive(key, flight, request));
+                (reader, true)
+            }
+        }
+    };
+
+    let head = reader.head().await?;
+    Ok(Upstream {
+        status: head.status,
+        headers: head.headers,
+        url: head.url,
+        redirect_chain: head.redirect_chain,
+        body: Box::pin(stream::unfold(reader, |mut reader| async move {
+            let item = reader.next_chunk().await?;
+            Some((item, reader))
+        })),
+        leader,
+    })
+}
+
+// Runs the shared fetch, independent of which request started it. Waits
+// while the slowest reader is too far behind, and stops early once nobody is
+// reading anymore.
+async fn drive<F>(key: String, flight: Arc<Flight>, request: F)
+where
+    F: Future<Output = reqwest::Result<reqwest::Response>>,
+{
+    let (resp, redirect_chain) = ssrf::with_redirect_chain(request).await;
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(e) => {
+            flight.update(|state| {
+                state.head = Some(Err(Arc::new(e)));
+                state.joinable = false;
+            });
+            detach(&key, &flight);
+            return;
+        }
+    };
+    flight.update(|state| state.head = Some(Ok(Head::of(&resp, redirect_chain))));
+
+    let mut body = resp.bytes_stream();
+    loop {
+        loop {
+            let (idle, lagging) = {
+                let state = flight.state.lock().unwrap();
+                (state.readers.is_empty(), state.unread() > MAX_READ_AHEAD)
+            };
+            if idle {
+                flight.update(|state| state.joinable = false);
+                detach(&key, &flight);
+                return;
+            }
+            if !lagging {
+                break;
+            }
+            flight.advanced.notified().await;
+        }
+
+        let item = body.next().await;
+        let keep_going = flight.update(|state| {
+            match item {
+                Some(Ok(chunk)) => {
+                    state.buffered += chunk.len();
+                    state.received += chunk.len();
+                    state.chunks.push_back(chunk);
+                    if state.buffered > MAX_SHARED_BUFFER {
+                        state.joinable = false;
+                    }
+                }
+                Some(Err(e)) => state.end = Some(Err(Arc::new(e))),
+                None => state.end = Some(Ok(())),
+            }
+            if state.end.is_some() || state.readers.is_empty() {
+                state.joinable = false;
+            }
+            state.trim();
+            state.end.is_none() && !state.readers.is_empty()
+        });
+        if !flight.state.lock().unwrap().joinable {
+            detach(&key, &flight);
+        }
+        if !keep_going {
+            return;
+        }
+    }
+}
+
+struct Reader {
+    flight: Arc<Flight>,
+    id: u64,
+    changes: watch::Receiver<u64>,
+    done: bool,
+}
+
+impl Reader {
+    async fn head(&mut self) -> Result<Head, FetchError> {
+        loop {
+            self.changes.borrow_and_update();
+            if let Some(head) = &self.flight.state.lock().unwrap().head {
+                return head.clone();
+            }
+            // The flight owns the sender, so this only fails if it's gone
+            let _ = self.changes.changed().await;
+        }
+    }
+
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, FetchError>> {
+        if self.done {
+            return None;
+        }
+        loop {
+            self.changes.borrow_and_update();
+            {
+                let mut state = self.flight.state.lock().unwrap();
+                let (position, read) = state.readers[&self.id];
+                if let Some(chunk) = state.chunks.get(position - state.first).cloned() {
+                    state.readers.insert(self.id, (position + 1, read + chunk.len()));
+                    state.trim();
+                    drop(state);
+                    self.flight.advanced.notify_one();
+                    return Some(Ok(chunk));
+                }
+                if let Some(end) = &state.end {
+                    self.done = true;
+                    return end.clone().err().map(Err);
+                }
+            }
+            let _ = self.changes.changed().await;
+        }
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let mut state = self.flight.state.lock().unwrap();
+        state.readers.remove(&self.id);
+        state.trim();
+        drop(state);
+        self.flight.advanced.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::mpsc;
+
+    // Upstream response whose body is whatever gets sent on the returned channel
+    fn response(capacity: usize) -> (mpsc::Sender<Result<Bytes, std::io::Error>>, reqwest::Response) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let body = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|item| (item, rx)) });
+        let resp = http::Response::builder()
+            .status(200)
+            .body(reqwest::Body::wrap_stream(body))
+            .unwrap();
+        (tx, reqwest::Response::from(resp))
+    }
+
+    // Endless body of `size`-byte chunks, counting how many were pulled
+    fn endless(size: usize, pulled: Arc<AtomicUsize>) -> reqwest::Response {
+        let body = stream::repeat_with(move || {
+            pulled.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, std::io::Error>(Bytes::from(vec![0u8; size]))
+        });
+        let resp = http::Response::builder()
+            .status(200)
+            .body(reqwest::Body::wrap_stream(body))
+            .unwrap();
+        reqwest::Response::from(resp)
+    }
+
+    async fn read_all(upstream: Upstream) -> Result<Vec<u8>, FetchError> {
+        let mut body = upstream.body;
+        let mut out = Vec::new();
+        while let Some(chunk) = body.next().await {
+            out.extend_from_slice(&chunk?);
+        }
+        Ok(out)
+    }
+
+    fn in_flight(key: &str) -> bool {
+        FLIGHTS.lock().unwrap().contains_key(key)
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn joiners_get_the_whole_body_from_one_fetch() {
+        let key = "test://upstream/join".to_string();
+        let (tx, resp) = response(4);
+        let leader = shared(key.clone(), async move { Ok(resp) }).await.unwrap();
+        assert!(leader.leader);
+
+        tx.send(Ok(Bytes::from_static(b"abc"))).await.unwrap();
+        settle().await;
+        // Would panic if the second request were sent
+        let follower = shared(key.clone(), async { panic!("joined fetch sent again") }).await.unwrap();
+        assert!(!follower.leader);
+        assert_eq!(follower.status, StatusCode::OK);
+
+        tx.send(Ok(Bytes::from_static(b"def"))).await.unwrap();
+        drop(tx);
+        let (a, b) = tokio::join!(read_all(leader), read_all(follower));
+        assert_eq!(a.unwrap(), b"abcdef");
+        assert_eq!(b.unwrap(), b"abcdef");
+        settle().await;
+        assert!(!in_flight(&key));
+    }
+
+    #[tokio::test]
+    async fn errors_reach_every_reader() {
+        let key = "test://upstream/error".to_string();
+        let (tx, resp) = response(4);
+        let leader = shared(key.clone(), async move { Ok(resp) }).await.unwrap();
+        let follower = shared(key.clone(), async { panic!("joined fetch sent again") }).await.unwrap();
+
+        tx.send(Ok(Bytes::from_static(b"abc"))).await.unwrap();
+        tx.send(Err(std::io::Error::other("reset"))).await.unwrap();
+        let (a, b) = tokio::join!(read_all(leader), read_all(follower));
+        assert!(a.is_err() && b.is_err());
+        assert!(!in_flight(&key));
+
+        // The next request starts a fresh fetch
+        let (tx, resp) = response(1);
+        drop(tx);
+        let retry = shared(key.clone(), async move { Ok(resp) }).await.unwrap();
+        assert!(retry.leader);
+        assert_eq!(read_all(retry).await.unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn stops_once_every_reader_has_gone() {
+        let key = "test://upstream/idle".to_string();
+        let (tx, resp) = response(1);
+        let leader = shared(key.clone(), async move { Ok(resp) }).await.unwrap();
+        drop(leader);
+        settle().await;
+        // The fetch notices on the next chunk and drops the body
+        let _ = tx.send(Ok(Bytes::from_static(b"abc"))).await;
+        settle().await;
+        assert!(!in_flight(&key));
+        assert!(tx.is_closed());
+    }
+
+    #[tokio::test]
+    async fn stalled_readers_hold_the_fetch_back() {
+        let key = "test://upstream/stalled".to_string();
+        let chunk = 1024 * 1024;
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let resp = endless(chunk, pulled.clone());
+        let mut stalled = shared(key.clone(), async move { Ok(resp) }).await.unwrap();
+
+        settle().await;
+        let ahead = pulled.load(Ordering::SeqCst);
+        assert!(ahead <= MAX_READ_AHEAD / chunk + 2, "read {} chunks ahead", ahead);
+        settle().await;
+        assert_eq!(pulled.load(Ordering::SeqCst), ahead);
+
+        // Reading lets the fetch go on, still only so far ahead
+        for _ in 0..20 {
+            stalled.body.next().await.unwrap().unwrap();
+        }
+        settle().await;
+        let pulled_now = pulled.load(Ordering::SeqCst);
+        assert!(pulled_now > ahead && pulled_now <= 20 + MAX_READ_AHEAD / chunk + 2);
+        {
+            let flight = FLIGHTS.lock().unwrap().get(&key).cloned();
+            if let Some(flight) = flight {
+                assert!(flight.state.lock().unwrap().buffered <= MAX_SHARED_BUFFER + chunk);
+            }
+        }
+
+        drop(stalled);
+        settle().await;
+        assert!(!in_flight(&key));
+        let stopped = pulled.load(Ordering::SeqCst);
+        settle().await;
+        assert_eq!(pulled.load(Ordering::SeqCst), stopped);
+    }
+
+    #[tokio::test]
+    async fn trims_chunks_once_nobody_can_join() {
+        let key = "test://upstream/trim".to_string();
+        let chunk = 4 * 1024 * 1024;
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let resp = endless(chunk, pulled.clone());
+        let mut reader = shared(key.clone(), async move { Ok(resp) }).await.unwrap();
+
+        // Past MAX_SHARED_BUFFER the fetch can't be joined, and what was read is let go
+        let mut read = 0;
+        while read <= MAX_SHARED_BUFFER + chunk {
+            read += reader.body.next().await.unwrap().unwrap().len();
+        }
+        settle().await;
+        assert!(!in_flight(&key));
+        let (_tx, resp) = response(1);
+        let joiner = shared(key.clone(), async move { Ok(resp) }).await.unwrap();
+        assert!(joiner.leader);
+        drop(joiner);
+        assert!(pulled.load(Ordering::SeqCst) * chunk <= read + MAX_READ_AHEAD + 2 * chunk);
+    }
+}